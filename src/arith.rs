@@ -0,0 +1,597 @@
+//! Evaluation of arithmetic expressions, as used by `$((expression))`.
+//!
+//! Expressions follow the POSIX shell arithmetic: signed 64-bit integers, decimal, octal (with a
+//! leading `0`) and hexadecimal (with a leading `0x`) constants, variables referenced by their
+//! names, and the operators of the C language except for `++`, `--`, `,` and the `sizeof` and
+//! cast operators, with the same precedence and associativity. Unlike in C, overflows and
+//! divisions by zero are errors.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use Position;
+
+/// Describes what went wrong during evaluation of an arithmetic expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArithmeticErrorKind {
+    /// The expression is malformed; the message describes the problem.
+    Syntax(String),
+    /// A number in the expression or in the value of a variable is not a valid integer.
+    InvalidNumber(String),
+    /// The result of an operation does not fit into a 64-bit signed integer, or a shift amount
+    /// is out of range.
+    Overflow,
+    /// The right operand of `/` or `%` is zero.
+    DivisionByZero,
+}
+
+impl fmt::Display for ArithmeticErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ArithmeticErrorKind::Syntax(ref message) => write!(f, "syntax error: {}", message),
+            ArithmeticErrorKind::InvalidNumber(ref number) => {
+                write!(f, "invalid number '{}'", number)
+            }
+            ArithmeticErrorKind::Overflow => f.write_str("integer overflow"),
+            ArithmeticErrorKind::DivisionByZero => f.write_str("division by zero"),
+        }
+    }
+}
+
+/// Represents an arithmetic expansion error.
+///
+/// This error is returned, wrapped into `ExpandError::Arithmetic`, when the expression of a
+/// `$((expression))` expansion cannot be evaluated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArithmeticError {
+    /// The expression, after expansion of variables referenced with `$` inside it.
+    pub expression: String,
+    /// What went wrong.
+    pub kind: ArithmeticErrorKind,
+    /// Where the `$((expression))` expansion is in the input.
+    pub position: Position,
+}
+
+impl fmt::Display for ArithmeticError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error evaluating '{}': {}", self.expression, self.kind)
+    }
+}
+
+impl Error for ArithmeticError {}
+
+/// An error of `evaluate()`: either an arithmetic one, or one returned by the lookup function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EvalError<E> {
+    Arithmetic(ArithmeticErrorKind),
+    Lookup(E),
+}
+
+impl<E> From<ArithmeticErrorKind> for EvalError<E> {
+    fn from(kind: ArithmeticErrorKind) -> EvalError<E> {
+        EvalError::Arithmetic(kind)
+    }
+}
+
+/// Evaluates an arithmetic expression.
+///
+/// `lookup` returns the value of a variable, or `None` if it is not set, in which case its value
+/// is zero. Assignments, like in `A += 1`, are passed to `assign`; later references to the
+/// assigned variable in the same expression see the new value.
+pub fn evaluate<E, L, A>(expression: &str, lookup: L, assign: A) -> Result<i64, EvalError<E>>
+where
+    L: FnMut(&str) -> Result<Option<String>, E>,
+    A: FnMut(&str, &str),
+{
+    let tokens = tokenize(expression)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        depth: 0,
+    };
+    let expr = parser.assignment()?;
+    if let Some(token) = parser.tokens.get(parser.pos) {
+        return Err(syntax_error(format!("unexpected '{}'", token)));
+    }
+
+    let mut evaluator = Evaluator {
+        lookup,
+        assign,
+        assigned: HashMap::new(),
+    };
+    evaluator.eval(&expr)
+}
+
+fn syntax_error<E>(message: String) -> EvalError<E> {
+    EvalError::Arithmetic(ArithmeticErrorKind::Syntax(message))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Number(n) => n.fmt(f),
+            Token::Name(ref name) => f.write_str(name),
+            Token::Op(op) => f.write_str(op),
+        }
+    }
+}
+
+/// Operators, ordered so that longer ones come before their prefixes.
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=", "*=", "/=", "%=",
+    "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "|", "^", "!", "~", "?", ":", "=",
+    "(", ")",
+];
+
+fn tokenize<E>(expression: &str) -> Result<Vec<Token>, EvalError<E>> {
+    let mut tokens = Vec::new();
+    let mut rest = expression.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let token_len = if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            tokens.push(Token::Number(parse_number(&rest[..len])?));
+            len
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..len].into()));
+            len
+        } else {
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    op.len()
+                }
+                None => return Err(syntax_error(format!("unexpected '{}'", c))),
+            }
+        };
+        rest = rest[token_len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Parses an integer constant, possibly preceded by a sign and surrounded by whitespace.
+fn parse_number(s: &str) -> Result<i64, ArithmeticErrorKind> {
+    let invalid = || ArithmeticErrorKind::InvalidNumber(s.into());
+    let trimmed = s.trim();
+    let (negative, digits) = match trimmed.chars().next() {
+        Some('-') => (true, &trimmed[1..]),
+        Some('+') => (false, &trimmed[1..]),
+        _ => (false, trimmed),
+    };
+    let (radix, digits) = if digits.starts_with("0x") || digits.starts_with("0X") {
+        (16, &digits[2..])
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        (8, &digits[1..])
+    } else {
+        (10, digits)
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return Err(invalid());
+    }
+    // parsing the negated digits allows `i64::MIN` to be written
+    let digits = if negative {
+        format!("-{}", digits)
+    } else {
+        digits.into()
+    };
+    i64::from_str_radix(&digits, radix).map_err(|_| ArithmeticErrorKind::Overflow)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Number(i64),
+    Variable(String),
+    Unary(&'static str, Box<Expr>),
+    /// A chain of left-associative binary operators of the same precedence, like `1 - 2 + 3`,
+    /// which is evaluated from left to right without recursion.
+    Binary(Box<Expr>, Vec<(&'static str, Expr)>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+    /// An assignment, with the binary operator of compound assignments like `+=`.
+    Assign(String, Option<&'static str>, Box<Expr>),
+}
+
+/// Binary operators from the lowest to the highest precedence.
+const BINARY_OPERATORS: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+/// How deeply parentheses, unary operators, assignments and conditional operators may be nested,
+/// so that parsing and evaluation don't overflow the stack.
+const MAX_NESTING: usize = 100;
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// The number of nested subexpressions being parsed.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(&Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect<E>(&mut self, op: &str) -> Result<(), EvalError<E>> {
+        match self.tokens.get(self.pos) {
+            Some(&Token::Op(found)) if found == op => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(token) => Err(syntax_error(format!(
+                "expected '{}', found '{}'",
+                op, token
+            ))),
+            None => Err(syntax_error(format!("expected '{}'", op))),
+        }
+    }
+
+    /// Parses a nested subexpression with `parse`, failing if it is nested too deeply.
+    fn nested<E, F>(&mut self, parse: F) -> Result<Expr, EvalError<E>>
+    where
+        F: FnOnce(&mut Parser) -> Result<Expr, EvalError<E>>,
+    {
+        if self.depth >= MAX_NESTING {
+            return Err(syntax_error("expression nested too deeply".into()));
+        }
+        self.depth += 1;
+        let expr = parse(self)?;
+        self.depth -= 1;
+        Ok(expr)
+    }
+
+    fn assignment<E>(&mut self) -> Result<Expr, EvalError<E>> {
+        if let Some(Token::Name(name)) = self.tokens.get(self.pos) {
+            if let Some(&Token::Op(op)) = self.tokens.get(self.pos + 1) {
+                if op.ends_with('=') && !["==", "!=", "<=", ">="].contains(&op) {
+                    let name = name.clone();
+                    self.pos += 2;
+                    let value = self.nested(Parser::assignment)?;
+                    let operator = OPERATORS
+                        .iter()
+                        .find(|o| o.len() + 1 == op.len() && op.starts_with(**o))
+                        .cloned();
+                    return Ok(Expr::Assign(name, operator, Box::new(value)));
+                }
+            }
+        }
+        self.conditional()
+    }
+
+    fn conditional<E>(&mut self) -> Result<Expr, EvalError<E>> {
+        let condition = self.binary(0)?;
+        if self.peek_op() != Some("?") {
+            return Ok(condition);
+        }
+        self.pos += 1;
+        let then = self.nested(Parser::assignment)?;
+        self.expect(":")?;
+        let otherwise = self.nested(Parser::assignment)?;
+        Ok(Expr::Conditional(
+            Box::new(condition),
+            Box::new(then),
+            Box::new(otherwise),
+        ))
+    }
+
+    fn binary<E>(&mut self, level: usize) -> Result<Expr, EvalError<E>> {
+        if level == BINARY_OPERATORS.len() {
+            return self.unary();
+        }
+        let first = self.binary(level + 1)?;
+        let mut rest = Vec::new();
+        while let Some(op) = self.peek_op() {
+            if !BINARY_OPERATORS[level].contains(&op) {
+                break;
+            }
+            self.pos += 1;
+            rest.push((op, self.binary(level + 1)?));
+        }
+        if rest.is_empty() {
+            Ok(first)
+        } else {
+            Ok(Expr::Binary(Box::new(first), rest))
+        }
+    }
+
+    fn unary<E>(&mut self) -> Result<Expr, EvalError<E>> {
+        match self.peek_op() {
+            Some(op @ "+") | Some(op @ "-") | Some(op @ "!") | Some(op @ "~") => {
+                self.pos += 1;
+                Ok(Expr::Unary(op, Box::new(self.nested(Parser::unary)?)))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary<E>(&mut self) -> Result<Expr, EvalError<E>> {
+        let token = match self.tokens.get(self.pos) {
+            Some(token) => token.clone(),
+            None => return Err(syntax_error("unexpected end of expression".into())),
+        };
+        self.pos += 1;
+        match token {
+            Token::Number(n) => Ok(Expr::Number(n)),
+            Token::Name(name) => Ok(Expr::Variable(name)),
+            Token::Op("(") => {
+                let expr = self.nested(Parser::assignment)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            token => Err(syntax_error(format!("unexpected '{}'", token))),
+        }
+    }
+}
+
+struct Evaluator<L, A> {
+    lookup: L,
+    assign: A,
+    assigned: HashMap<String, i64>,
+}
+
+impl<E, L, A> Evaluator<L, A>
+where
+    L: FnMut(&str) -> Result<Option<String>, E>,
+    A: FnMut(&str, &str),
+{
+    fn eval(&mut self, expr: &Expr) -> Result<i64, EvalError<E>> {
+        match *expr {
+            Expr::Number(n) => Ok(n),
+            Expr::Variable(ref name) => self.variable(name),
+            Expr::Unary(op, ref operand) => {
+                let value = self.eval(operand)?;
+                match op {
+                    "+" => Ok(value),
+                    "-" => value
+                        .checked_neg()
+                        .ok_or(EvalError::Arithmetic(ArithmeticErrorKind::Overflow)),
+                    "!" => Ok((value == 0) as i64),
+                    _ => Ok(!value),
+                }
+            }
+            Expr::Binary(ref first, ref rest) => {
+                let mut value = self.eval(first)?;
+                for &(op, ref right) in rest {
+                    value = match op {
+                        "&&" => (value != 0 && self.eval(right)? != 0) as i64,
+                        "||" => (value != 0 || self.eval(right)? != 0) as i64,
+                        _ => apply_binary(op, value, self.eval(right)?)?,
+                    };
+                }
+                Ok(value)
+            }
+            Expr::Conditional(ref condition, ref then, ref otherwise) => {
+                if self.eval(condition)? != 0 {
+                    self.eval(then)
+                } else {
+                    self.eval(otherwise)
+                }
+            }
+            Expr::Assign(ref name, operator, ref value) => {
+                let mut value = self.eval(value)?;
+                if let Some(op) = operator {
+                    value = apply_binary(op, self.variable(name)?, value)?;
+                }
+                (self.assign)(name, &value.to_string());
+                self.assigned.insert(name.clone(), value);
+                Ok(value)
+            }
+        }
+    }
+
+    fn variable(&mut self, name: &str) -> Result<i64, EvalError<E>> {
+        if let Some(&value) = self.assigned.get(name) {
+            return Ok(value);
+        }
+        match (self.lookup)(name).map_err(EvalError::Lookup)? {
+            Some(ref value) if !value.trim().is_empty() => Ok(parse_number(value)?),
+            _ => Ok(0),
+        }
+    }
+}
+
+fn apply_binary(op: &str, left: i64, right: i64) -> Result<i64, ArithmeticErrorKind> {
+    let shift = |right: i64| {
+        if (0..64).contains(&right) {
+            Ok(right as u32)
+        } else {
+            Err(ArithmeticErrorKind::Overflow)
+        }
+    };
+    let result = match op {
+        "+" => left.checked_add(right),
+        "-" => left.checked_sub(right),
+        "*" => left.checked_mul(right),
+        "/" | "%" if right == 0 => return Err(ArithmeticErrorKind::DivisionByZero),
+        "/" => left.checked_div(right),
+        "%" => left.checked_rem(right),
+        "<<" => left.checked_shl(shift(right)?),
+        ">>" => left.checked_shr(shift(right)?),
+        "<" => Some((left < right) as i64),
+        "<=" => Some((left <= right) as i64),
+        ">" => Some((left > right) as i64),
+        ">=" => Some((left >= right) as i64),
+        "==" => Some((left == right) as i64),
+        "!=" => Some((left != right) as i64),
+        "&" => Some(left & right),
+        "^" => Some(left ^ right),
+        "|" => Some(left | right),
+        _ => unreachable!("unknown operator {}", op),
+    };
+    result.ok_or(ArithmeticErrorKind::Overflow)
+}
+
+#[cfg(test)]
+mod arith_tests {
+    use std::collections::HashMap;
+
+    use super::{evaluate, ArithmeticErrorKind, EvalError, MAX_NESTING};
+
+    fn eval(expression: &str) -> Result<i64, EvalError<()>> {
+        evaluate(
+            expression,
+            |name| match name {
+                "A" => Ok(Some("5".into())),
+                "HEX" => Ok(Some(" 0x1f ".into())),
+                "EMPTY" => Ok(Some("".into())),
+                "TEXT" => Ok(Some("abc".into())),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            },
+            |_, _| {},
+        )
+    }
+
+    #[test]
+    fn test_operators() {
+        let cases = [
+            ("1 + 2 * 3", 7),
+            ("(1 + 2) * 3", 9),
+            ("10 - 4 - 3", 3),
+            ("7 / 2 + 7 % 2", 4),
+            ("-7 / 2", -3),
+            ("1 << 4 | 1", 17),
+            ("0xff & ~0xf ^ 1", 0xf1),
+            ("010 + 0X10", 24),
+            ("3 > 2 && 2 >= 2 && 1 < 2 && 2 <= 1 == 0 && 1 != 2", 1),
+            ("0 || 0", 0),
+            ("!0 + !5 + -(-3) + +2", 6),
+            ("1 ? 2 : 3", 2),
+            ("0 ? 2 : 1 ? 3 : 4", 3),
+            ("A * 2 + HEX + EMPTY + UNSET", 41),
+            ("0 && 1 / 0", 0),
+            ("1 || 1 / 0", 1),
+            ("1 ? 1 : 1 / 0", 1),
+            ("-9223372036854775807 - 1", i64::MIN),
+        ];
+        for &(expression, expected) in cases.iter() {
+            assert_eq!(eval(expression), Ok(expected), "{:?}", expression);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let cases = [
+            ("1 / 0", ArithmeticErrorKind::DivisionByZero),
+            ("1 % (A - 5)", ArithmeticErrorKind::DivisionByZero),
+            ("9223372036854775807 + 1", ArithmeticErrorKind::Overflow),
+            ("99999999999999999999", ArithmeticErrorKind::Overflow),
+            ("-(-9223372036854775807 - 1)", ArithmeticErrorKind::Overflow),
+            ("1 << 64", ArithmeticErrorKind::Overflow),
+            ("08", ArithmeticErrorKind::InvalidNumber("08".into())),
+            ("12ab", ArithmeticErrorKind::InvalidNumber("12ab".into())),
+            ("TEXT + 1", ArithmeticErrorKind::InvalidNumber("abc".into())),
+            (
+                "",
+                ArithmeticErrorKind::Syntax("unexpected end of expression".into()),
+            ),
+            (
+                "1 +",
+                ArithmeticErrorKind::Syntax("unexpected end of expression".into()),
+            ),
+            ("(1 + 2", ArithmeticErrorKind::Syntax("expected ')'".into())),
+            ("1 2", ArithmeticErrorKind::Syntax("unexpected '2'".into())),
+            ("1 ? 2", ArithmeticErrorKind::Syntax("expected ':'".into())),
+            (
+                "1 $ 2",
+                ArithmeticErrorKind::Syntax("unexpected '$'".into()),
+            ),
+            (
+                "1 = 2",
+                ArithmeticErrorKind::Syntax("unexpected '='".into()),
+            ),
+        ];
+        for (expression, kind) in cases.iter().cloned() {
+            assert_eq!(
+                eval(expression),
+                Err(EvalError::Arithmetic(kind)),
+                "{:?}",
+                expression
+            );
+        }
+        assert_eq!(eval("ERR + 1"), Err(EvalError::Lookup(())));
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let too_deep = Err(EvalError::Arithmetic(ArithmeticErrorKind::Syntax(
+            "expression nested too deeply".into(),
+        )));
+        let nested = |open: &str, inner: &str, close: &str, depth: usize| {
+            format!("{}{}{}", open.repeat(depth), inner, close.repeat(depth))
+        };
+
+        assert_eq!(eval(&nested("(", "1", ")", MAX_NESTING)), Ok(1));
+        assert_eq!(eval(&nested("- ", "1", "", MAX_NESTING)), Ok(1));
+        assert_eq!(eval(&nested("A = ", "1", "", MAX_NESTING)), Ok(1));
+        assert_eq!(eval(&nested("0 ? 0 : ", "1", "", MAX_NESTING)), Ok(1));
+        for &depth in &[MAX_NESTING + 1, 100_000] {
+            assert_eq!(eval(&nested("(", "1", ")", depth)), too_deep);
+            assert_eq!(eval(&nested("- ", "1", "", depth)), too_deep);
+            assert_eq!(eval(&nested("!", "1", "", depth)), too_deep);
+            assert_eq!(eval(&nested("A = ", "1", "", depth)), too_deep);
+            assert_eq!(eval(&nested("0 ? 0 : ", "1", "", depth)), too_deep);
+        }
+
+        // chains of binary operators are not nested, however long they are
+        assert_eq!(eval(&nested("1 + ", "1", "", 100_000)), Ok(100_001));
+        assert_eq!(eval(&nested("1 && ", "1", "", 100_000)), Ok(1));
+    }
+
+    #[test]
+    fn test_assignments() {
+        let mut vars = HashMap::new();
+        vars.insert("A".to_owned(), "1".to_owned());
+        let mut assigned = Vec::new();
+
+        let result = evaluate::<(), _, _>(
+            "B = A += 2, C = (B *= 3) + A",
+            |name| Ok(vars.get(name).cloned()),
+            |name, value| assigned.push((name.to_owned(), value.to_owned())),
+        );
+        assert_eq!(
+            result,
+            Err(EvalError::Arithmetic(ArithmeticErrorKind::Syntax(
+                "unexpected ','".into()
+            )))
+        );
+
+        let result = evaluate::<(), _, _>(
+            "C = (B = A += 2) * 3 + A",
+            |name| Ok(vars.get(name).cloned()),
+            |name, value| assigned.push((name.to_owned(), value.to_owned())),
+        );
+        assert_eq!(result, Ok(12));
+        assert_eq!(
+            assigned,
+            vec![
+                ("A".to_owned(), "3".to_owned()),
+                ("B".to_owned(), "3".to_owned()),
+                ("C".to_owned(), "12".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,408 @@
+//! Brace expansion, which turns `a{b,c}d` into `abd` and `acd`.
+
+use std::error::Error;
+use std::fmt;
+use std::iter;
+
+use Position;
+
+/// Performs brace expansion in a string, producing a list of words.
+///
+/// A brace expression is either a comma-separated list of alternatives, like `{api,worker}`, or a
+/// sequence, like `{1..10}`, `{a..e}` or `{1..10..2}`. Every word of the result consists of the
+/// text before the expression, one of its alternatives or one of the elements of the sequence,
+/// and the text after the expression, so `x{a,b}y` expands to `xay` and `xby`. Several
+/// expressions in the same string produce all combinations of their elements, and expressions
+/// may be nested, like in `{a,b{1,2}}`, which expands to `a`, `b1` and `b2`.
+///
+/// Sequences count up or down from their first to their last element, with the optional
+/// increment as the step. Numbers are padded with zeros to the same width if either of the
+/// first two numbers has a leading zero, so `{01..12}` expands to `01`, `02` and so on up to
+/// `12`. Sequences of letters, like `{a..e}`, are supported too.
+///
+/// Braces which do not form a valid expression, like in `{a}` or `{}`, are left as they are,
+/// and so are braces preceded by a backslash and braces of variable references like `${A}`, which
+/// are never treated as brace expressions. Backslashes are kept in the result, so that they can
+/// be handled by environment expansion later. The input itself is returned as the single element
+/// of the result if it has no brace expressions.
+///
+/// To keep the result reasonably small, expressions nested more than 100 levels deep are left as
+/// they are, and if the result would have more than 100,000 words, a `BracesError` is returned.
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(
+///     shellexpand::braces("~/logs/{api,worker}/{01..03}.log").unwrap(),
+///     vec![
+///         "~/logs/api/01.log", "~/logs/api/02.log", "~/logs/api/03.log",
+///         "~/logs/worker/01.log", "~/logs/worker/02.log", "~/logs/worker/03.log",
+///     ]
+/// );
+///
+/// assert_eq!(shellexpand::braces("{a,b{1,2}}").unwrap(), vec!["a", "b1", "b2"]);
+/// assert_eq!(
+///     shellexpand::braces("{10..0..5}{y..x}").unwrap(),
+///     vec!["10y", "10x", "5y", "5x", "0y", "0x"]
+/// );
+/// assert_eq!(shellexpand::braces("${A:-{a,b}}{a}").unwrap(), vec!["${A:-{a,b}}{a}"]);
+///
+/// let error = shellexpand::braces(&"{a,b}".repeat(17)).unwrap_err();
+/// assert_eq!(error.limit, 100_000);
+/// ```
+pub fn braces(input: &str) -> Result<Vec<String>, BracesError> {
+    expand(input, 0).ok_or_else(|| BracesError {
+        limit: MAX_WORDS,
+        position: Position::new(input, 0..input.len()),
+    })
+}
+
+/// Represents a brace expansion which would produce too many words.
+///
+/// This error is returned by `braces()`, and wrapped into `ExpandError::Braces` by the functions
+/// which perform brace expansion before the other expansions, like `full_braces()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BracesError {
+    /// The maximum number of words, which the result would exceed.
+    pub limit: usize,
+    /// Spans the whole input, since its brace expressions produce the words together.
+    pub position: Position,
+}
+
+impl fmt::Display for BracesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "brace expansion produces more than {} words", self.limit)
+    }
+}
+
+impl Error for BracesError {}
+
+/// The maximum number of brace expressions which may be nested in each other.
+const MAX_NESTING: usize = 100;
+
+/// The maximum number of words which brace expansion may produce.
+const MAX_WORDS: usize = 100_000;
+
+/// Expands the brace expressions of `input`, which is nested `depth` levels deep.
+///
+/// Returns `None` if the result would have more than `MAX_WORDS` words.
+fn expand(input: &str, depth: usize) -> Option<Vec<String>> {
+    let mut words = vec![String::new()];
+    let mut literal_start = 0;
+    let mut search_start = 0;
+    for group in find_groups(input) {
+        let (open_idx, close_idx) = (group.open_idx, group.close_idx);
+        if open_idx < search_start {
+            continue;
+        }
+        let elements = match split_alternatives(input, &group) {
+            Some(alternatives) if depth < MAX_NESTING => {
+                let mut elements = Vec::new();
+                for alternative in alternatives {
+                    elements.extend(expand(alternative, depth + 1)?);
+                }
+                elements
+            }
+            Some(_) => {
+                // nested too deeply, so left as it is
+                search_start = close_idx + 1;
+                continue;
+            }
+            // inner braces may still form an expression, and the next groups are these
+            None if group.nested => continue,
+            None => match sequence(&input[open_idx + 1..close_idx]) {
+                Some(elements) => elements,
+                None => continue,
+            },
+        };
+        if words.len().saturating_mul(elements.len()) > MAX_WORDS {
+            return None;
+        }
+
+        let prefix = &input[literal_start..open_idx];
+        words = words
+            .iter()
+            .flat_map(|word| {
+                elements
+                    .iter()
+                    .map(move |element| format!("{}{}{}", word, prefix, element))
+            })
+            .collect();
+        literal_start = close_idx + 1;
+        search_start = close_idx + 1;
+    }
+    for word in &mut words {
+        word.push_str(&input[literal_start..]);
+    }
+    Some(words)
+}
+
+/// A pair of matching braces in the input of brace expansion.
+struct Group {
+    open_idx: usize,
+    close_idx: usize,
+    /// The positions of the commas between the braces, outside of the nested groups.
+    commas: Vec<usize>,
+    /// Whether there are other braces between the braces.
+    nested: bool,
+}
+
+/// Finds the groups of matching braces in `input`, ordered by their opening braces.
+///
+/// Braces of variable references and braces after a backslash are skipped. The input is scanned
+/// only once, so that many nested or unmatched braces do not take quadratic time.
+fn find_groups(input: &str) -> Vec<Group> {
+    let mut groups = Vec::new();
+    // the groups whose closing brace has not been found yet, innermost last
+    let mut open_groups: Vec<Group> = Vec::new();
+    let mut pos = 0;
+    while pos < input.len() {
+        match input[pos..].chars().next().unwrap() {
+            '{' => {
+                if let Some(parent) = open_groups.last_mut() {
+                    parent.nested = true;
+                }
+                open_groups.push(Group {
+                    open_idx: pos,
+                    close_idx: pos,
+                    commas: Vec::new(),
+                    nested: false,
+                });
+            }
+            '}' => {
+                if let Some(mut group) = open_groups.pop() {
+                    group.close_idx = pos;
+                    groups.push(group);
+                }
+            }
+            ',' => {
+                if let Some(group) = open_groups.last_mut() {
+                    group.commas.push(pos);
+                }
+            }
+            c => {
+                pos += skip_special(input, pos).unwrap_or_else(|| c.len_utf8());
+                continue;
+            }
+        }
+        pos += 1;
+    }
+    groups.sort_by_key(|group| group.open_idx);
+    groups
+}
+
+/// Returns the length of an escaped character or a variable reference in braces at `pos`.
+fn skip_special(input: &str, pos: usize) -> Option<usize> {
+    let rest = &input[pos..];
+    let mut chars = rest.chars();
+    match (chars.next(), chars.next()) {
+        (Some('\\'), Some(c)) => Some(1 + c.len_utf8()),
+        (Some('$'), Some('{')) => {
+            // nested references are skipped by counting their braces too
+            let mut depth = 0;
+            let mut inner_pos = 2;
+            while inner_pos < rest.len() {
+                let mut chars = rest[inner_pos..].chars();
+                match (chars.next().unwrap(), chars.next()) {
+                    ('\\', Some(c)) => inner_pos += c.len_utf8(),
+                    ('{', _) => depth += 1,
+                    ('}', _) if depth == 0 => return Some(inner_pos + 1),
+                    ('}', _) => depth -= 1,
+                    _ => {}
+                }
+                inner_pos += rest[inner_pos..].chars().next().unwrap().len_utf8();
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Splits the body of a group at its top-level commas.
+///
+/// Returns `None` if there are no such commas.
+fn split_alternatives<'a>(input: &'a str, group: &Group) -> Option<Vec<&'a str>> {
+    if group.commas.is_empty() {
+        return None;
+    }
+    let mut alternatives = Vec::new();
+    let mut alternative_start = group.open_idx + 1;
+    for &end in group.commas.iter().chain(iter::once(&group.close_idx)) {
+        alternatives.push(&input[alternative_start..end]);
+        alternative_start = end + 1;
+    }
+    Some(alternatives)
+}
+
+/// Expands the body of a sequence expression, like `1..10..2`.
+///
+/// Returns `None` if the body is not a valid sequence.
+fn sequence(body: &str) -> Option<Vec<String>> {
+    let parts: Vec<&str> = body.split("..").collect();
+    let (first, last, increment) = match parts[..] {
+        [first, last] => (first, last, 1),
+        [first, last, increment] => (first, last, increment.parse::<i64>().ok()?),
+        _ => return None,
+    };
+    // the sign of the increment does not matter, the direction is defined by the bounds
+    let step = match increment.checked_abs()? {
+        0 => 1,
+        step => step,
+    };
+
+    if let (Ok(first_number), Ok(last_number)) = (first.parse::<i64>(), last.parse::<i64>()) {
+        let has_leading_zero = |s: &str| {
+            s.trim_start_matches('-').starts_with('0') && s.trim_start_matches('-').len() > 1
+        };
+        let width = if has_leading_zero(first) || has_leading_zero(last) {
+            first.len().max(last.len())
+        } else {
+            0
+        };
+        let numbers = count(first_number, last_number, step);
+        return Some(
+            numbers
+                .into_iter()
+                .map(|n| format!("{:01$}", n, width))
+                .collect(),
+        );
+    }
+
+    let letter = |s: &str| match s.as_bytes() {
+        [c] if c.is_ascii_alphabetic() => Some(*c as i64),
+        _ => None,
+    };
+    let letters = count(letter(first)?, letter(last)?, step);
+    Some(
+        letters
+            .into_iter()
+            .map(|c| (c as u8 as char).to_string())
+            .collect(),
+    )
+}
+
+/// Returns the numbers from `first` to `last` with the given positive step.
+///
+/// Stops after `MAX_WORDS + 1` numbers, which is already too many.
+fn count(first: i64, last: i64, step: i64) -> Vec<i64> {
+    let mut numbers = Vec::new();
+    let mut n = first;
+    loop {
+        numbers.push(n);
+        if numbers.len() > MAX_WORDS {
+            return numbers;
+        }
+        let next = if first <= last {
+            n.checked_add(step).filter(|&next| next <= last)
+        } else {
+            n.checked_sub(step).filter(|&next| next >= last)
+        };
+        match next {
+            Some(next) => n = next,
+            None => return numbers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod braces_tests {
+    use super::{braces, BracesError, MAX_NESTING, MAX_WORDS};
+    use Position;
+
+    #[test]
+    fn test_alternatives() {
+        let cases: &[(&str, &[&str])] = &[
+            ("", &[""]),
+            ("abc", &["abc"]),
+            ("{a,b,c}", &["a", "b", "c"]),
+            ("x{a,b}y", &["xay", "xby"]),
+            ("{a,b}{1,2}", &["a1", "a2", "b1", "b2"]),
+            ("{a,b{1,2},c}", &["a", "b1", "b2", "c"]),
+            ("{,a}|{a,}", &["|a", "|", "a|a", "a|"]),
+            ("{a,{b}}", &["a", "{b}"]),
+            ("{a{b,c}}", &["{ab}", "{ac}"]),
+            ("{a}|{}|{a,b", &["{a}|{}|{a,b"]),
+            ("}{a,b}{", &["}a{", "}b{"]),
+            ("\\{a,b}|{a\\,b}", &["\\{a,b}|{a\\,b}"]),
+            ("{a\\}b,c}", &["a\\}b", "c"]),
+            ("${A}{a,b}", &["${A}a", "${A}b"]),
+            ("${A:-{a,b}}|${A:-${B}}", &["${A:-{a,b}}|${A:-${B}}"]),
+            ("{$A,${B:-x,y}}", &["$A", "${B:-x,y}"]),
+            ("é{ü,ö}é", &["éüé", "éöé"]),
+        ];
+        for &(input, expected) in cases {
+            assert_eq!(braces(input).unwrap(), expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_sequences() {
+        let cases: &[(&str, &[&str])] = &[
+            ("{1..3}", &["1", "2", "3"]),
+            ("{3..1}", &["3", "2", "1"]),
+            ("{-1..1}", &["-1", "0", "1"]),
+            ("{1..10..4}", &["1", "5", "9"]),
+            ("{10..1..-4}", &["10", "6", "2"]),
+            ("{1..2..0}", &["1", "2"]),
+            ("{5..5}", &["5"]),
+            ("{08..11}", &["08", "09", "10", "11"]),
+            ("{1..003}", &["001", "002", "003"]),
+            ("{-02..1}", &["-02", "-01", "000", "001"]),
+            ("{a..c}", &["a", "b", "c"]),
+            ("{c..a..2}", &["c", "a"]),
+            ("x{1..2}{a..b}", &["x1a", "x1b", "x2a", "x2b"]),
+            ("{a,{1..2}}", &["a", "1", "2"]),
+            (
+                "{1..b}|{a..}|{1..2..x}|{1...2}|{ab..c}",
+                &["{1..b}|{a..}|{1..2..x}|{1...2}|{ab..c}"],
+            ),
+        ];
+        for &(input, expected) in cases {
+            assert_eq!(braces(input).unwrap(), expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let nested = |depth: usize| format!("{}a{}", "{a,".repeat(depth), "}".repeat(depth));
+
+        let result = braces(&nested(MAX_NESTING)).unwrap();
+        assert_eq!(result.len(), MAX_NESTING + 1);
+        assert!(result.iter().all(|word| word == "a"));
+        let result = braces(&nested(10_000)).unwrap();
+        assert_eq!(result.len(), MAX_NESTING + 1);
+        assert!(result[..MAX_NESTING].iter().all(|word| word == "a"));
+        assert_eq!(result[MAX_NESTING], nested(10_000 - MAX_NESTING));
+
+        let input = "{a,b}".repeat(16);
+        assert_eq!(braces(&input).unwrap().len(), 1 << 16);
+        let input = "{a,b}".repeat(17);
+        assert_eq!(
+            braces(&input),
+            Err(BracesError {
+                limit: MAX_WORDS,
+                position: Position::new(&input, 0..input.len()),
+            })
+        );
+        let input = format!("{{1..{}}}", MAX_WORDS);
+        assert_eq!(braces(&input).unwrap().len(), MAX_WORDS);
+        for &input in &["{0..100000}", "{1..9223372036854775807}", "{a,b}{1..60000}"] {
+            assert!(braces(input).is_err(), "{:?}", input);
+        }
+
+        assert_eq!(
+            braces(&"{1..1}".repeat(10_000)).unwrap(),
+            ["1".repeat(10_000)]
+        );
+        // nested and unmatched braces are scanned once
+        let input = format!("{}{}", "{a".repeat(20_000), "}".repeat(20_000));
+        assert_eq!(braces(&input).unwrap(), [input]);
+        let input = format!("{}{{a,b}}", "{".repeat(20_000));
+        assert_eq!(braces(&input).unwrap().len(), 2);
+        let reference = format!("{}{}", "${A:-{".repeat(10_000), "}}".repeat(10_000));
+        assert_eq!(
+            braces(&format!("{}{{a,b}}", reference)).unwrap(),
+            [format!("{}a", reference), format!("{}b", reference)]
+        );
+    }
+}
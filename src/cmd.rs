@@ -0,0 +1,270 @@
+//! Environment expansion with the syntax of `cmd.exe`, like `%VAR%` and `%VAR:~0,4%`.
+
+use std::borrow::Cow;
+
+use {ExpandError, LookupError, Position, Recovery};
+
+/// Performs the environment expansion of `input` with the `cmd.exe` syntax.
+///
+/// `%%` stands for a single `%`, and `%NAME%` is replaced with the value of `NAME`, optionally
+/// modified with `:~start,length` or `:old=new` after the name. References with invalid modifiers
+/// and a `%` without a closing one are left as they are, and so are references to unknown
+/// variables, unless `strict` is true, in which case they cause `ExpandError::Unset` errors.
+/// With a `recovery`, errors are collected in it instead of being returned.
+pub fn expand<'a, CO, C, E>(
+    input: &'a str,
+    mut context: C,
+    strict: bool,
+    mut recovery: Option<Recovery<'_, E>>,
+) -> Result<Cow<'a, str>, ExpandError<E>>
+where
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+{
+    if !input.contains('%') {
+        return Ok(input.into());
+    }
+
+    let mut result = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(percent_idx) = rest.find('%') {
+        result.push_str(&rest[..percent_idx]);
+        let after_percent = &rest[percent_idx + 1..];
+        if let Some(after_escape) = after_percent.strip_prefix('%') {
+            result.push('%');
+            rest = after_escape;
+            continue;
+        }
+        let closing_idx = match after_percent.find('%') {
+            Some(closing_idx) => closing_idx,
+            None => {
+                rest = &rest[percent_idx..];
+                break;
+            }
+        };
+
+        let reference = &rest[percent_idx..percent_idx + closing_idx + 2];
+        let reference_start = input.len() - rest.len() + percent_idx;
+        let position = || Position::new(input, reference_start..reference_start + reference.len());
+        let body = &after_percent[..closing_idx];
+        let (var_name, modifier) = match body.find(':') {
+            Some(colon_idx) => (&body[..colon_idx], Some(&body[colon_idx + 1..])),
+            None => (body, None),
+        };
+        let expanded = match context(var_name) {
+            Ok(Some(var_value)) => {
+                match modifier {
+                    Some(modifier) => match apply_modifier(var_value.as_ref(), modifier) {
+                        Some(modified) => result.push_str(&modified),
+                        None => result.push_str(reference),
+                    },
+                    None => result.push_str(var_value.as_ref()),
+                }
+                Ok(())
+            }
+            Ok(None) if strict => Err(ExpandError::Unset {
+                var_name: var_name.into(),
+                position: position(),
+            }),
+            Ok(None) => {
+                result.push_str(reference);
+                Ok(())
+            }
+            Err(cause) => Err(ExpandError::Lookup(LookupError {
+                var_name: var_name.into(),
+                cause,
+                position: position(),
+            })),
+        };
+        if let Err(error) = expanded {
+            match recovery {
+                Some(ref mut recovery) => recovery.recover(error, reference, &mut result),
+                None => return Err(error),
+            }
+        }
+        rest = &after_percent[closing_idx + 1..];
+    }
+    result.push_str(rest);
+
+    Ok(result.into())
+}
+
+/// Applies the modifier after the colon in `%VAR:modifier%` to the value of the variable.
+///
+/// Returns `None` if the modifier is invalid.
+fn apply_modifier<'v>(value: &'v str, modifier: &str) -> Option<Cow<'v, str>> {
+    if let Some(range) = modifier.strip_prefix('~') {
+        let (start, length) = match range.find(',') {
+            Some(comma_idx) => (
+                &range[..comma_idx],
+                Some(range[comma_idx + 1..].trim().parse().ok()?),
+            ),
+            None => (range, None),
+        };
+        return Some(substring(value, start.trim().parse().ok()?, length).into());
+    }
+
+    let equals_idx = modifier.find('=')?;
+    let (old, new) = (&modifier[..equals_idx], &modifier[equals_idx + 1..]);
+    if old.is_empty() || old == "*" {
+        return None;
+    }
+    match old.strip_prefix('*') {
+        // `*old` replaces everything up to the end of the first match
+        Some(old) => Some(match find_ignoring_case(value, old) {
+            Some((_, match_end)) => format!("{}{}", new, &value[match_end..]).into(),
+            None => value.into(),
+        }),
+        None => {
+            let mut result = String::with_capacity(value.len());
+            let mut rest = value;
+            while let Some((match_start, match_end)) = find_ignoring_case(rest, old) {
+                result.push_str(&rest[..match_start]);
+                result.push_str(new);
+                rest = &rest[match_end..];
+            }
+            result.push_str(rest);
+            Some(result.into())
+        }
+    }
+}
+
+/// Returns the part of `value` selected by `%VAR:~start,length%`.
+///
+/// Unlike in `${VAR:offset:length}`, a negative start which is out of bounds selects the
+/// beginning of the value.
+fn substring(value: &str, start: isize, length: Option<isize>) -> &str {
+    let char_count = value.chars().count() as isize;
+    let start = if start < 0 {
+        (char_count + start).max(0)
+    } else {
+        start.min(char_count)
+    };
+    let end = match length {
+        Some(length) if length < 0 => char_count + length,
+        Some(length) => start.saturating_add(length).min(char_count),
+        None => char_count,
+    };
+    if start >= end {
+        return "";
+    }
+
+    let byte_idx = |n: isize| {
+        value
+            .char_indices()
+            .nth(n as usize)
+            .map_or(value.len(), |(i, _)| i)
+    };
+    &value[byte_idx(start)..byte_idx(end)]
+}
+
+/// Finds the first occurrence of `needle` in `haystack`, comparing characters case-insensitively
+/// like `cmd.exe` does.
+///
+/// Returns the byte range of the occurrence.
+fn find_ignoring_case(haystack: &str, needle: &str) -> Option<(usize, usize)> {
+    let same = |a: char, b: char| a == b || a.to_lowercase().eq(b.to_lowercase());
+    haystack.char_indices().find_map(|(start, _)| {
+        let mut end = start;
+        let mut haystack_chars = haystack[start..].chars();
+        for needle_char in needle.chars() {
+            match haystack_chars.next() {
+                Some(c) if same(c, needle_char) => end += c.len_utf8(),
+                _ => return None,
+            }
+        }
+        Some((start, end))
+    })
+}
+
+#[cfg(test)]
+mod cmd_tests {
+    use {ExpandError, LookupError, Position};
+
+    use super::expand;
+
+    fn context(s: &str) -> Result<Option<&'static str>, ()> {
+        match s {
+            "A" => Ok(Some("a value")),
+            "PATH" => Ok(Some(r"C:\Windows;C:\Tools")),
+            "EMPTY" => Ok(Some("")),
+            "Ü" => Ok(Some("äöü")),
+            "ERR" => Err(()),
+            _ => Ok(None),
+        }
+    }
+
+    #[test]
+    fn test_variables() {
+        let cases = &[
+            ("", ""),
+            ("plain", "plain"),
+            ("%A%", "a value"),
+            ("[%A%|%EMPTY%|%Ü%]", "[a value||äöü]"),
+            ("100%% %%A%% %%%A%", "100% %A% %a value"),
+            ("%UNSET% %A", "%UNSET% %A"),
+            ("%UNSET%A%", "%UNSET%A%"),
+            ("%A%%A%", "a valuea value"),
+            ("%", "%"),
+        ];
+        for &(input, expected) in cases {
+            assert_eq!(
+                expand(input, context, false, None),
+                Ok(expected.into()),
+                "{:?}",
+                input
+            );
+        }
+        assert_eq!(
+            expand("%A% %ERR%", context, false, None),
+            Err(ExpandError::Lookup(LookupError {
+                var_name: "ERR".into(),
+                cause: (),
+                position: Position::new("%A% %ERR%", 4..9),
+            }))
+        );
+        assert_eq!(
+            expand("%A% %%UNSET%% %UNSET:~1%", context, true, None),
+            Err(ExpandError::Unset {
+                var_name: "UNSET".into(),
+                position: Position {
+                    span: 14..24,
+                    line: 1,
+                    column: 15,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_modifiers() {
+        let cases = &[
+            ("%A:~2%", "value"),
+            ("%A:~2,3%", "val"),
+            ("%A:~-5%", "value"),
+            ("%A:~-5,2%", "va"),
+            ("%A:~0,-6%", "a"),
+            ("%A:~-100,1%", "a"),
+            ("%A:~100%|%A:~3,-100%", "|"),
+            ("%Ü:~1,1%", "ö"),
+            ("%A:~x%|%A:~1,%|%A:x%", "%A:~x%|%A:~1,%|%A:x%"),
+            ("%A:a=A%", "A vAlue"),
+            ("%A:VALUE=key%", "a key"),
+            ("%A: =%", "avalue"),
+            ("%PATH:;=\" \"%", r#"C:\Windows" "C:\Tools"#),
+            ("%A:*l=L%", "Lue"),
+            ("%A:*x=y%", "a value"),
+            ("%Ü:Ö=o%", "äoü"),
+            ("%A:=x%|%A:*=x%", "%A:=x%|%A:*=x%"),
+            ("%UNSET:~1%", "%UNSET:~1%"),
+        ];
+        for &(input, expected) in cases {
+            assert_eq!(
+                expand(input, context, false, None),
+                Ok(expected.into()),
+                "{:?}",
+                input
+            );
+        }
+    }
+}
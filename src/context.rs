@@ -0,0 +1,268 @@
+//! Variable contexts: the sources of values of variables.
+
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::env::{self, VarError};
+use std::hash::{BuildHasher, Hash};
+use std::iter::FromIterator;
+
+/// A source of values of variables, which environment expansion functions look variables up in.
+///
+/// Every closure of type `FnMut(&str) -> Result<Option<CO>, E>` is a context, which is called with
+/// the name of a variable and returns its value, `Ok(None)` if the variable is unknown, or an
+/// error. Besides closures, this trait is implemented for shared references to `HashMap` and
+/// `BTreeMap` with string keys and values, for slices of name and value pairs, for `Env`, which
+/// looks variables up in the environment of the process, and for `EnvSnapshot`, which keeps a copy
+/// of it. Types which implement it can be stored in structs and passed around like any other value.
+///
+/// Contexts are accepted by `Expander` and by the `*_with_context()` functions, like
+/// `env_with_context()` and `full_with_context()`; the `*_os_with_context()` and `*_no_errors()`
+/// functions take closures.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// let mut vars = HashMap::new();
+/// vars.insert("NAME", "world");
+///
+/// assert_eq!(shellexpand::env_with_context("hello, $NAME", &vars).unwrap(), "hello, world");
+///
+/// let pairs = [("A", "1"), ("B", "2"), ("A", "3")];
+/// assert_eq!(shellexpand::env_with_context("$A$B$C", &pairs[..]).unwrap(), "32$C");
+/// ```
+pub trait Context {
+    /// The type of values of variables.
+    type Value: AsRef<str>;
+    /// The type of errors of lookups.
+    type Error;
+
+    /// Looks up the variable `name`, returning `Ok(None)` if it is unknown.
+    fn lookup(&mut self, name: &str) -> Result<Option<Self::Value>, Self::Error>;
+
+    /// Assigns `value` to the variable `name`, when `${VAR:=word}` or `${VAR=word}` expands to
+    /// `word`, or when an arithmetic expansion assigns a variable.
+    ///
+    /// Whether later lookups see the assigned value is up to the context. By default assignments
+    /// are dropped, like with all the contexts provided by this crate, which are read-only.
+    fn assign(&mut self, name: &str, value: &str) {
+        let _ = (name, value);
+    }
+
+    /// Runs the command of a command substitution, like `$(command)`, and returns its output,
+    /// when command substitutions are enabled with `Expander::commands()`.
+    ///
+    /// By default no commands are run, and `Ok(None)` leaves the substitution as it is, like with
+    /// all the contexts provided by this crate. An error is reported as `ExpandError::Command`.
+    fn execute(&mut self, command: &str) -> Result<Option<String>, Self::Error> {
+        let _ = command;
+        Ok(None)
+    }
+}
+
+impl<F, CO, E> Context for F
+where
+    F: FnMut(&str) -> Result<Option<CO>, E>,
+    CO: AsRef<str>,
+{
+    type Value = CO;
+    type Error = E;
+
+    #[inline]
+    fn lookup(&mut self, name: &str) -> Result<Option<CO>, E> {
+        self(name)
+    }
+}
+
+impl<'a, K, V, S> Context for &'a HashMap<K, V, S>
+where
+    K: Borrow<str> + Hash + Eq,
+    V: AsRef<str>,
+    S: BuildHasher,
+{
+    type Value = &'a V;
+    type Error = Infallible;
+
+    #[inline]
+    fn lookup(&mut self, name: &str) -> Result<Option<&'a V>, Infallible> {
+        Ok(self.get(name))
+    }
+}
+
+impl<'a, K, V> Context for &'a BTreeMap<K, V>
+where
+    K: Borrow<str> + Ord,
+    V: AsRef<str>,
+{
+    type Value = &'a V;
+    type Error = Infallible;
+
+    #[inline]
+    fn lookup(&mut self, name: &str) -> Result<Option<&'a V>, Infallible> {
+        Ok(self.get(name))
+    }
+}
+
+/// Pairs of names and values; if a name occurs several times, the last value wins, like with
+/// repeated assignments in a shell.
+impl<'a, N, V> Context for &'a [(N, V)]
+where
+    N: AsRef<str>,
+    V: AsRef<str>,
+{
+    type Value = &'a V;
+    type Error = Infallible;
+
+    fn lookup(&mut self, name: &str) -> Result<Option<&'a V>, Infallible> {
+        Ok(self
+            .iter()
+            .rev()
+            .find(|(n, _)| n.as_ref() == name)
+            .map(|(_, value)| value))
+    }
+}
+
+/// The environment of the process, as a context which looks variables up with
+/// `std::env::var()` on every lookup.
+///
+/// Unlike in `env()`, variables which are not present are unknown rather than errors, so they
+/// are left as they are, or reported as `ExpandError::Unset` by strict expansions; variables
+/// whose values are not valid Unicode are still errors.
+///
+/// # Examples
+///
+/// ```
+/// use shellexpand::Env;
+///
+/// std::env::set_var("SHELLEXPAND_ENV_EXAMPLE", "value");
+/// assert_eq!(
+///     shellexpand::env_with_context("$SHELLEXPAND_ENV_EXAMPLE $SHELLEXPAND_UNSET_EXAMPLE", Env)
+///         .unwrap(),
+///     "value $SHELLEXPAND_UNSET_EXAMPLE"
+/// );
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Env;
+
+impl Context for Env {
+    type Value = String;
+    type Error = VarError;
+
+    fn lookup(&mut self, name: &str) -> Result<Option<String>, VarError> {
+        match env::var(name) {
+            Ok(value) => Ok(Some(value)),
+            Err(VarError::NotPresent) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A copy of the environment of the process, taken once, for example with `std::env::vars()`.
+///
+/// Later changes of the environment are not visible in a snapshot, so it gives the same results
+/// no matter when or on which thread it is used. `EnvSnapshot::new()` skips variables whose names
+/// or values are not valid Unicode, so they are unknown, and a snapshot can also be collected
+/// from any iterator of names and values, like `std::env::vars()` itself.
+///
+/// # Examples
+///
+/// ```
+/// use shellexpand::EnvSnapshot;
+///
+/// std::env::set_var("SHELLEXPAND_SNAPSHOT_EXAMPLE", "before");
+/// let snapshot = EnvSnapshot::new();
+/// std::env::set_var("SHELLEXPAND_SNAPSHOT_EXAMPLE", "after");
+///
+/// assert_eq!(
+///     shellexpand::env_with_context("$SHELLEXPAND_SNAPSHOT_EXAMPLE", &snapshot).unwrap(),
+///     "before"
+/// );
+///
+/// let snapshot: EnvSnapshot = std::env::vars().collect();
+/// assert_eq!(snapshot.get("SHELLEXPAND_SNAPSHOT_EXAMPLE"), Some("after"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvSnapshot {
+    vars: HashMap<String, String>,
+}
+
+impl EnvSnapshot {
+    /// Takes a snapshot of the current environment of the process.
+    pub fn new() -> EnvSnapshot {
+        env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect()
+    }
+
+    /// Returns the value of the variable `name`, or `None` if it is not in the snapshot.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(String::as_str)
+    }
+}
+
+impl FromIterator<(String, String)> for EnvSnapshot {
+    fn from_iter<I>(iter: I) -> EnvSnapshot
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        EnvSnapshot {
+            vars: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl<'a> Context for &'a EnvSnapshot {
+    type Value = &'a str;
+    type Error = Infallible;
+
+    #[inline]
+    fn lookup(&mut self, name: &str) -> Result<Option<&'a str>, Infallible> {
+        Ok(self.get(name))
+    }
+}
+
+#[cfg(test)]
+mod context_tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use super::{Context, EnvSnapshot};
+    use {env_with_context, full_with_context, Expander};
+
+    #[test]
+    fn test_contexts() {
+        let mut hash_map = HashMap::new();
+        hash_map.insert("A".to_owned(), "a".to_owned());
+        assert_eq!(env_with_context("$A$B", &hash_map).unwrap(), "a$B");
+
+        let mut btree_map = BTreeMap::new();
+        btree_map.insert("A", "a");
+        assert_eq!(env_with_context("${A}x${B:-b}", &btree_map).unwrap(), "axb");
+
+        let pairs = [("A", "1".to_owned()), ("A", "2".to_owned())];
+        assert_eq!((&pairs[..]).lookup("A"), Ok(Some(&"2".to_owned())));
+        assert_eq!((&pairs[..]).lookup("B"), Ok(None));
+
+        let snapshot: EnvSnapshot = vec![("A".to_owned(), "a".to_owned())].into_iter().collect();
+        assert_eq!(
+            full_with_context("~/$A", || Some("/home"), &snapshot).unwrap(),
+            "/home/a"
+        );
+
+        let mut expander = Expander::new().context(&btree_map).strict(true);
+        assert_eq!(expander.expand("$A").unwrap(), "a");
+        assert_eq!(expander.expand_all("$B", None).1.len(), 1);
+        assert_eq!(expander.expand_mut("$A").unwrap(), "a");
+        assert!(expander.expand_mut("$B").is_err());
+
+        let mut calls = 0;
+        let mut closure = |name: &str| {
+            calls += 1;
+            Ok::<_, ()>(Some(name.to_lowercase()))
+        };
+        assert_eq!(closure.lookup("A"), Ok(Some("a".to_owned())));
+        assert_eq!(env_with_context("$B", &mut closure).unwrap(), "b");
+        assert_eq!(calls, 2);
+    }
+}
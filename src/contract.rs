@@ -0,0 +1,288 @@
+//! Contraction of strings, the inverse of tilde and environment expansions.
+
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::path::{is_separator, Path};
+
+use parse::is_valid_var_name_char;
+
+/// Replaces the home directory in the beginning of the input with a tilde, using the provided
+/// context.
+///
+/// This function is the inverse of `tilde_with_context()`: if the input starts with the path
+/// returned by `home_dir`, and the path is followed by a separator or by the end of the string, it
+/// is replaced with `~`. Otherwise, including when `home_dir` returns `None` or the root directory,
+/// the input is returned as it is. A trailing separator of the home directory is ignored.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{Path, PathBuf};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+///
+/// assert_eq!(
+///     shellexpand::contract_tilde_with_context("/home/user/projects/x", home_dir),
+///     "~/projects/x"
+/// );
+/// assert_eq!(shellexpand::contract_tilde_with_context("/home/user", home_dir), "~");
+/// assert_eq!(
+///     shellexpand::contract_tilde_with_context("/home/username", home_dir),
+///     "/home/username"
+/// );
+/// ```
+pub fn contract_tilde_with_context<SI: ?Sized, P, HD>(input: &SI, home_dir: HD) -> Cow<'_, str>
+where
+    SI: AsRef<str>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    let input = input.as_ref();
+    match home_dir().and_then(|hd| home_dir_len(input, hd.as_ref())) {
+        Some(home_dir_len) => format!("~{}", &input[home_dir_len..]).into(),
+        None => input.into(),
+    }
+}
+
+/// Replaces the home directory of the current user in the beginning of the input with a tilde.
+///
+/// This function is the inverse of `tilde()` for the current user; it calls
+/// `contract_tilde_with_context()` with `dirs::home_dir()` as the context.
+///
+/// # Examples
+///
+/// ```
+/// extern crate dirs_next as dirs;
+///
+/// let hds = dirs::home_dir()
+///     .map(|p| p.display().to_string())
+///     .unwrap_or_else(|| "~".to_owned());
+///
+/// assert_eq!(shellexpand::contract_tilde(&format!("{}/projects", hds)), "~/projects");
+/// ```
+#[inline]
+pub fn contract_tilde<SI: ?Sized>(input: &SI) -> Cow<'_, str>
+where
+    SI: AsRef<str>,
+{
+    contract_tilde_with_context(input, dirs::home_dir)
+}
+
+/// Replaces the home directory and values of variables in the input with `~` and references to
+/// the variables, using the provided contexts.
+///
+/// This function is the inverse of `full_with_context()`. The home directory is contracted like
+/// in `contract_tilde_with_context()`, and every occurrence of a non-empty value from `vars`,
+/// which yields pairs of names and values, is replaced with `${NAME}`. Where several values, or a
+/// value and the home directory, match at the same position, the longest one wins, and the home
+/// directory wins over a variable with the same value. Pairs with names which are not valid
+/// variable names are ignored. Every other `$` is escaped as `$$`, so that `full_with_context()`
+/// with the same home directory and variables turns the result back into the input, unless the
+/// input itself starts with a tilde prefix.
+///
+/// Values are matched anywhere in the input, even in the middle of words, so `vars` should only
+/// contain variables whose values are distinctive, like paths, and not, for example, all of
+/// `std::env::vars()`.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{Path, PathBuf};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+/// let vars = vec![
+///     ("DATA", "/home/user/data"),
+///     ("CACHE", "/var/cache"),
+///     ("TMP", "/var/cache/tmp"),
+/// ];
+///
+/// assert_eq!(
+///     shellexpand::contract_with_context(
+///         "/home/user/b /home/user/data/a /var/cache/tmp/c $5",
+///         home_dir,
+///         vars.iter().cloned()
+///     ),
+///     "~/b ${DATA}/a ${TMP}/c $$5"
+/// );
+/// ```
+pub fn contract_with_context<SI: ?Sized, P, HD, I, N, V>(
+    input: &SI,
+    home_dir: HD,
+    vars: I,
+) -> Cow<'_, str>
+where
+    SI: AsRef<str>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+    I: IntoIterator<Item = (N, V)>,
+    N: AsRef<str>,
+    V: AsRef<str>,
+{
+    let input = input.as_ref();
+    let mut vars: Vec<(N, V)> = vars
+        .into_iter()
+        .filter(|(name, value)| {
+            let name = name.as_ref();
+            !name.is_empty()
+                && name.chars().all(is_valid_var_name_char)
+                && !value.as_ref().is_empty()
+        })
+        .collect();
+    // the longest values come first, so that the first match is the longest one
+    vars.sort_by_key(|var| Reverse(var.1.as_ref().len()));
+    let find_var = |rest: &str| {
+        vars.iter()
+            .find(|&(_, value)| rest.starts_with(value.as_ref()))
+            .map(|(name, value)| (name.as_ref(), value.as_ref().len()))
+    };
+
+    let mut result = String::with_capacity(input.len());
+    let mut pos = 0;
+    if let Some(home_dir_len) = home_dir().and_then(|hd| home_dir_len(input, hd.as_ref())) {
+        if find_var(input).map_or(0, |(_, len)| len) <= home_dir_len {
+            result.push('~');
+            pos = home_dir_len;
+        }
+    }
+    while pos < input.len() {
+        let rest = &input[pos..];
+        if let Some((name, len)) = find_var(rest) {
+            result.push_str("${");
+            result.push_str(name);
+            result.push('}');
+            pos += len;
+            continue;
+        }
+        let c = rest.chars().next().unwrap();
+        if c == '$' {
+            result.push('$');
+        }
+        result.push(c);
+        pos += c.len_utf8();
+    }
+
+    if result == input {
+        input.into()
+    } else {
+        result.into()
+    }
+}
+
+/// Replaces the home directory of the current user and values of variables in the input with
+/// `~` and references to the variables.
+///
+/// This function calls `contract_with_context()` with `dirs::home_dir()` as the home directory
+/// context.
+///
+/// # Examples
+///
+/// ```
+/// extern crate dirs_next as dirs;
+///
+/// let hds = dirs::home_dir()
+///     .map(|p| p.display().to_string())
+///     .unwrap_or_else(|| "~".to_owned());
+///
+/// assert_eq!(
+///     shellexpand::contract(&format!("{}/.cache/app", hds), vec![("APP", "app")]),
+///     "~/.cache/${APP}"
+/// );
+/// ```
+#[inline]
+pub fn contract<SI: ?Sized, I, N, V>(input: &SI, vars: I) -> Cow<'_, str>
+where
+    SI: AsRef<str>,
+    I: IntoIterator<Item = (N, V)>,
+    N: AsRef<str>,
+    V: AsRef<str>,
+{
+    contract_with_context(input, dirs::home_dir, vars)
+}
+
+/// Returns the length of `home_dir` if the input starts with it, followed by a separator or by
+/// the end of the input.
+fn home_dir_len(input: &str, home_dir: &Path) -> Option<usize> {
+    let home_dir = home_dir.to_str()?.trim_end_matches(is_separator);
+    // the root directory is a prefix of every absolute path, and is never contracted
+    if home_dir.is_empty() {
+        return None;
+    }
+    let rest = input.strip_prefix(home_dir)?;
+    if rest.is_empty() || rest.starts_with(is_separator) {
+        Some(home_dir.len())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod contract_tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{contract_tilde_with_context, contract_with_context};
+    use full_with_context;
+
+    fn home_dir() -> Option<PathBuf> {
+        Some(Path::new("/home/user").into())
+    }
+
+    #[test]
+    fn test_contract_tilde() {
+        let cases = &[
+            ("/home/user", "~"),
+            ("/home/user/", "~/"),
+            ("/home/user/a/b", "~/a/b"),
+            ("/home/users", "/home/users"),
+            ("/srv/home/user", "/srv/home/user"),
+            ("$HOME/x", "$HOME/x"),
+            ("", ""),
+        ];
+        for &(input, expected) in cases {
+            assert_eq!(contract_tilde_with_context(input, home_dir), expected);
+        }
+        assert_eq!(
+            contract_tilde_with_context("/home/user", || None::<PathBuf>),
+            "/home/user"
+        );
+        assert_eq!(
+            contract_tilde_with_context("/home/user/a", || Some("/home/user/")),
+            "~/a"
+        );
+        assert_eq!(contract_tilde_with_context("/etc", || Some("/")), "/etc");
+    }
+
+    #[test]
+    fn test_contract() {
+        let vars = [
+            ("HOME_ALIAS", "/home/user"),
+            ("PROJECT", "/home/user/projects/x"),
+            ("BIN", "/usr/bin"),
+            ("LOCAL_BIN", "/usr/bin/local"),
+            ("EMPTY", ""),
+            ("not a name", "/usr"),
+        ];
+        let cases = &[
+            ("/home/user/projects/x/src", "${PROJECT}/src"),
+            ("/home/user/projects/y", "~/projects/y"),
+            (
+                "/usr/bin/local:/usr/bin:/usr/lib",
+                "${LOCAL_BIN}:${BIN}:/usr/lib",
+            ),
+            ("cost: $5, ~/x", "cost: $$5, ~/x"),
+            ("plain", "plain"),
+        ];
+        let context =
+            |s: &str| Ok::<_, ()>(vars.iter().find(|&&(name, _)| name == s).map(|&(_, v)| v));
+        for &(input, expected) in cases {
+            let contracted = contract_with_context(input, home_dir, vars.iter().cloned());
+            assert_eq!(contracted, expected, "{:?}", input);
+            // expanding the result gives the input back
+            assert_eq!(
+                full_with_context(&contracted, home_dir, context).unwrap(),
+                input,
+                "{:?}",
+                input
+            );
+        }
+    }
+}
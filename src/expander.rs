@@ -0,0 +1,945 @@
+//! A reusable expander which keeps its contexts and settings together.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::env::VarError;
+use std::path::{Path, PathBuf};
+
+use fields::{self, Fields};
+use parse::{self, Syntax};
+use {
+    apply_tilde, cmd, split_tilde_prefix, user_home_dir, Context, ExpandError, Expansion,
+    LookupError, Options, Position, Recovery,
+};
+
+/// The syntax of variable references recognized by an `Expander`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    /// The syntax of POSIX shells, like `$VAR` and `${VAR:-word}`; see `env_with_context()`.
+    Shell,
+    /// The syntax of `cmd.exe`, like `%VAR%` and `%VAR:~0,4%`; see `env_cmd_with_context()`.
+    ///
+    /// Tilde prefixes, escape styles, quotes, operators and recursive expansion are specific to
+    /// the shell syntax, so the respective settings of the `Expander` do not apply to this
+    /// dialect.
+    Cmd,
+}
+
+/// The escape sequences recognized by an `Expander` with the shell dialect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EscapeStyle {
+    /// Only `$$` stands for a `$`, like in `env_with_context()`.
+    Dollar,
+    /// A backslash escapes `$`, `{`, `}`, `~` and another backslash as well, like in
+    /// `env_with_context_and_escapes()`.
+    Backslash,
+}
+
+/// The groups of operators inside braces which an `Expander` expands.
+///
+/// References with operators which are not allowed are left as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Operators {
+    /// `${VAR:-word}`, `${VAR:=word}`, `${VAR:?message}` and `${VAR:+word}`, with or without the
+    /// colon.
+    pub defaults: bool,
+    /// `${VAR#pattern}`, `${VAR%pattern}` and `${VAR/pattern/replacement}`, with all their
+    /// variations.
+    pub patterns: bool,
+    /// `${VAR:offset:length}` and `${#VAR}`.
+    pub substrings: bool,
+    /// `${VAR^pattern}` and `${VAR,pattern}`, with all their variations.
+    pub case: bool,
+}
+
+impl Operators {
+    /// All operators.
+    pub const ALL: Operators = Operators {
+        defaults: true,
+        patterns: true,
+        substrings: true,
+        case: true,
+    };
+
+    /// No operators, so that only plain references, like `$VAR` and `${VAR}`, are expanded.
+    pub const NONE: Operators = Operators {
+        defaults: false,
+        patterns: false,
+        substrings: false,
+        case: false,
+    };
+}
+
+impl Default for Operators {
+    fn default() -> Operators {
+        Operators::ALL
+    }
+}
+
+/// The type of the default variable context of `Expander`, which uses `std::env::var()` and
+/// reports variables which are not present as unknown ones.
+pub type SystemContext = fn(&str) -> Result<Option<String>, VarError>;
+
+/// The type of the default home directory context of `Expander`, which uses `dirs::home_dir()`
+/// and `user_home_dir()`.
+pub type SystemHomeDir = fn(&str) -> Option<PathBuf>;
+
+/// Performs expansions with contexts and settings which are configured once.
+///
+/// The free functions of this crate have their contexts passed in every call and their behavior
+/// fixed by their names. An `Expander` keeps both instead, so that an application can configure
+/// it in one place and then use its `expand()` method everywhere. The contexts are the same as
+/// the ones of the free functions, except that the home directory context takes the user name
+/// after the tilde, which is empty for the current user, so it handles both `~` and `~user`, and
+/// that the values of `${VAR:=word}` and the like are assigned and the commands of command
+/// substitutions are run with `Context::assign()` and `Context::execute()` of the variable context. Its `expand_all()` method goes on after errors instead, reporting all of them at once.
+///
+/// `Expander::new()` creates an expander which works like `full()`, except that variables which
+/// are not present in the environment are not errors: it uses the system contexts and the shell
+/// syntax, expands tilde prefixes and leaves references to unknown variables as they are. Its
+/// builder methods change the contexts and the following settings:
+///
+/// * `dialect()` chooses between the shell syntax and the syntax of `cmd.exe`;
+/// * `escape_style()` enables backslash escapes, like in `env_with_context_and_escapes()`;
+/// * `quotes()` enables quotes, like in `env_with_context_and_quotes()`;
+/// * `arithmetic()` enables arithmetic expansion, like in `env_with_context_and_arithmetic()`;
+/// * `commands()` enables command substitution, like in `env_with_context_and_commands()`;
+/// * `operators()` selects the operators which are allowed inside braces;
+/// * `strict()` turns references to unknown variables into errors;
+/// * `syntax_errors()` turns malformed references into errors;
+/// * `recursion_limit()` makes values of variables expanded themselves;
+/// * `tilde()` disables tilde expansion, like in `env_with_context()`.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{Path, PathBuf};
+/// use shellexpand::{EscapeStyle, ExpandError, Expander, Operators, Position};
+///
+/// fn home_dir(user: &str) -> Option<PathBuf> {
+///     match user {
+///         "" => Some(Path::new("/home/user").into()),
+///         "deploy" => Some(Path::new("/srv/deploy").into()),
+///         _ => None,
+///     }
+/// }
+///
+/// fn get_env(name: &str) -> Result<Option<&'static str>, ()> {
+///     match name {
+///         "A" => Ok(Some("a value")),
+///         _ => Ok(None)
+///     }
+/// }
+///
+/// let expander = Expander::new()
+///     .context(get_env)
+///     .home_dir(home_dir)
+///     .escape_style(EscapeStyle::Backslash)
+///     .operators(Operators { defaults: true, ..Operators::NONE });
+///
+/// assert_eq!(expander.expand("~/$A \\$A").unwrap(), "/home/user/a value $A");
+/// assert_eq!(expander.expand("~deploy/${B:-b}").unwrap(), "/srv/deploy/b");
+/// assert_eq!(expander.expand("$B ${A#a}").unwrap(), "$B ${A#a}");
+///
+/// let expander = expander.strict(true).tilde(false);
+/// assert_eq!(expander.expand("~/$A").unwrap(), "~/a value");
+/// assert_eq!(
+///     expander.expand("$A/$B"),
+///     Err(ExpandError::Unset {
+///         var_name: "B".into(),
+///         position: Position::new("$A/$B", 3..5),
+///     })
+/// );
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Expander<C, HD> {
+    context: C,
+    home_dir: HD,
+    settings: Settings,
+}
+
+/// The settings of an `Expander` which are not contexts.
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    dialect: Dialect,
+    escape_style: EscapeStyle,
+    quotes: bool,
+    arithmetic: bool,
+    commands: bool,
+    operators: Operators,
+    strict: bool,
+    syntax_errors: bool,
+    recursion_limit: usize,
+    tilde: bool,
+}
+
+impl Expander<SystemContext, SystemHomeDir> {
+    /// Creates an expander which uses the system contexts and the default settings.
+    ///
+    /// The type of the expander may be spelled out, like in fields of structs:
+    ///
+    /// ```
+    /// use shellexpand::{Expander, SystemContext, SystemHomeDir};
+    ///
+    /// struct Config {
+    ///     expander: Expander<SystemContext, SystemHomeDir>,
+    /// }
+    ///
+    /// let config = Config { expander: Expander::new() };
+    /// assert_eq!(config.expander.expand("$$").unwrap(), "$");
+    /// ```
+    pub fn new() -> Self {
+        Expander {
+            context: system_context,
+            home_dir: system_home_dir,
+            settings: Settings {
+                dialect: Dialect::Shell,
+                escape_style: EscapeStyle::Dollar,
+                quotes: false,
+                arithmetic: false,
+                commands: false,
+                operators: Operators::ALL,
+                strict: false,
+                syntax_errors: false,
+                recursion_limit: 0,
+                tilde: true,
+            },
+        }
+    }
+}
+
+impl Default for Expander<SystemContext, SystemHomeDir> {
+    fn default() -> Self {
+        Expander::new()
+    }
+}
+
+impl<C, HD> Expander<C, HD> {
+    /// Sets the variable context, which has the same meaning as in `env_with_context()`.
+    pub fn context<C2>(self, context: C2) -> Expander<C2, HD> {
+        Expander {
+            context,
+            home_dir: self.home_dir,
+            settings: self.settings,
+        }
+    }
+
+    /// Sets the home directory context.
+    ///
+    /// The context is called with the user name after the tilde, which is empty for `~` and
+    /// `~/...`, and returns the home directory of that user, or `None` if the tilde prefix should
+    /// be left as it is.
+    pub fn home_dir<HD2>(self, home_dir: HD2) -> Expander<C, HD2> {
+        Expander {
+            context: self.context,
+            home_dir,
+            settings: self.settings,
+        }
+    }
+
+    /// Sets the syntax of variable references, `Dialect::Shell` by default.
+    pub fn dialect(mut self, dialect: Dialect) -> Self {
+        self.settings.dialect = dialect;
+        self
+    }
+
+    /// Sets the escape sequences, `EscapeStyle::Dollar` by default.
+    pub fn escape_style(mut self, escape_style: EscapeStyle) -> Self {
+        self.settings.escape_style = escape_style;
+        self
+    }
+
+    /// Sets whether quotes are recognized, which is disabled by default.
+    pub fn quotes(mut self, quotes: bool) -> Self {
+        self.settings.quotes = quotes;
+        self
+    }
+
+    /// Sets whether arithmetic expansions, like `$((A + 1))`, are evaluated, which is disabled by
+    /// default.
+    pub fn arithmetic(mut self, arithmetic: bool) -> Self {
+        self.settings.arithmetic = arithmetic;
+        self
+    }
+
+    /// Sets whether command substitutions, like `$(command)`, are performed, which is disabled by
+    /// default.
+    ///
+    /// The commands are passed to `Context::execute()` of the variable context, which is what
+    /// runs them, like the executor function of `env_with_context_and_commands()` does.
+    pub fn commands(mut self, commands: bool) -> Self {
+        self.settings.commands = commands;
+        self
+    }
+
+    /// Sets the operators which are allowed inside braces, all of them by default.
+    pub fn operators(mut self, operators: Operators) -> Self {
+        self.settings.operators = operators;
+        self
+    }
+
+    /// Sets whether references to unknown variables cause `ExpandError::Unset` errors, instead of
+    /// being left as they are, which is disabled by default.
+    ///
+    /// This works like `set -u` in shells: references with a default value, like `${VAR:-word}`,
+    /// and the other operators which check whether the variable is set are still allowed, and so
+    /// is `$$`, but `$VAR`, `${VAR}`, `${VAR#pattern}` and the like, as well as names of unknown
+    /// variables in arithmetic expressions, are errors. Malformed references, like `${VAR` or
+    /// `${}`, are reported as `ExpandError::Syntax`, like with `syntax_errors()`.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.settings.strict = strict;
+        self
+    }
+
+    /// Sets whether malformed references, like `${VAR` or `${}`, cause `ExpandError::Syntax`
+    /// errors, instead of being left as they are, which is disabled by default.
+    ///
+    /// Unlike `strict()`, this does not make references to unknown variables errors, so it checks
+    /// the syntax of the input while still leaving such references as they are. Strict expansions
+    /// report syntax errors regardless of this setting.
+    pub fn syntax_errors(mut self, syntax_errors: bool) -> Self {
+        self.settings.syntax_errors = syntax_errors;
+        self
+    }
+
+    /// Sets how deep values of variables are expanded themselves, zero by default.
+    ///
+    /// Normally the value of a variable is inserted into the result as it is, so if `DATA` is
+    /// `$HOME/data`, `$DATA` expands to `$HOME/data`. With a non-zero limit, values are expanded
+    /// with the same contexts and settings as the input, and so are the values of variables in
+    /// them, until nothing is left to expand, so `$DATA` expands to `/home/user/data`. Values
+    /// nested deeper than `limit` cause `ExpandError::RecursionLimit` errors, and values which
+    /// refer to each other, like `A=$B` and `B=$A`, cause `ExpandError::Cycle` errors listing the
+    /// variables of the cycle.
+    pub fn recursion_limit(mut self, limit: usize) -> Self {
+        self.settings.recursion_limit = limit;
+        self
+    }
+
+    /// Sets whether tilde prefixes are expanded, which is enabled by default.
+    pub fn tilde(mut self, tilde: bool) -> Self {
+        self.settings.tilde = tilde;
+        self
+    }
+
+    /// Expands the input with the contexts and settings of this expander.
+    ///
+    /// The variable context may be any `Context`. Looking variables up may change it, so it is
+    /// cloned for every expansion; closures which only borrow what they capture, references to
+    /// maps, `Env` and `&EnvSnapshot` are cheap to clone.
+    pub fn expand<'i, SI: ?Sized, CO, E, P>(
+        &self,
+        input: &'i SI,
+    ) -> Result<Cow<'i, str>, ExpandError<E>>
+    where
+        SI: AsRef<str>,
+        CO: AsRef<str>,
+        C: Context<Value = CO, Error = E> + Clone,
+        P: AsRef<Path>,
+        HD: Fn(&str) -> Option<P>,
+    {
+        self.settings.expand(
+            input.as_ref(),
+            &mut self.context.clone(),
+            &self.home_dir,
+            None,
+        )
+    }
+
+    /// Same as `expand()`, but uses the contexts of the expander without cloning them, so it works
+    /// with contexts which cannot be cloned, like `FnMut` closures which keep their own state.
+    pub fn expand_mut<'i, SI: ?Sized, CO, E, P>(
+        &mut self,
+        input: &'i SI,
+    ) -> Result<Cow<'i, str>, ExpandError<E>>
+    where
+        SI: AsRef<str>,
+        CO: AsRef<str>,
+        C: Context<Value = CO, Error = E>,
+        P: AsRef<Path>,
+        HD: FnMut(&str) -> Option<P>,
+    {
+        self.settings
+            .expand(input.as_ref(), &mut self.context, &mut self.home_dir, None)
+    }
+
+    /// Expands the input like `expand()`, but goes on after errors and returns all of them along
+    /// with the result.
+    ///
+    /// Every reference which fails is replaced with `placeholder`, or left as it is if it is
+    /// `None`, so the result is as complete as possible, and its error is added to the returned
+    /// list, in the order of the input. A failed reference inside a word after an operator is
+    /// replaced within the word, so `${A:-$ERR}` may cause an error both for `$ERR` and for the
+    /// outer reference, while an error in the value of a variable which is expanded recursively
+    /// fails the whole reference to the variable. The list is empty if the expansion succeeded.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::Expander;
+    ///
+    /// fn context(s: &str) -> Result<Option<&'static str>, std::env::VarError> {
+    ///     match s {
+    ///         "HOST" => Ok(Some("localhost")),
+    ///         _ => Ok(None),
+    ///     }
+    /// }
+    ///
+    /// let expander = Expander::new().context(context).strict(true);
+    /// let (result, errors) = expander.expand_all("$USER@$HOST:$PORT", Some("?"));
+    /// assert_eq!(result, "?@localhost:?");
+    /// assert_eq!(
+    ///     errors.iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+    ///     ["USER: unbound variable", "PORT: unbound variable"]
+    /// );
+    /// assert_eq!(errors[1].position().span, 12..17);
+    ///
+    /// let (result, _) = expander.expand_all("$USER@$HOST:$PORT", None);
+    /// assert_eq!(result, "$USER@localhost:$PORT");
+    /// ```
+    pub fn expand_all<'i, SI: ?Sized, CO, E, P>(
+        &self,
+        input: &'i SI,
+        placeholder: Option<&str>,
+    ) -> (Cow<'i, str>, Vec<ExpandError<E>>)
+    where
+        SI: AsRef<str>,
+        CO: AsRef<str>,
+        C: Context<Value = CO, Error = E> + Clone,
+        P: AsRef<Path>,
+        HD: Fn(&str) -> Option<P>,
+    {
+        self.settings.expand_all(
+            input.as_ref(),
+            &mut self.context.clone(),
+            &self.home_dir,
+            placeholder,
+        )
+    }
+
+    /// Same as `expand_all()`, but uses the contexts of the expander without cloning them, like
+    /// `expand_mut()`.
+    pub fn expand_all_mut<'i, SI: ?Sized, CO, E, P>(
+        &mut self,
+        input: &'i SI,
+        placeholder: Option<&str>,
+    ) -> (Cow<'i, str>, Vec<ExpandError<E>>)
+    where
+        SI: AsRef<str>,
+        CO: AsRef<str>,
+        C: Context<Value = CO, Error = E>,
+        P: AsRef<Path>,
+        HD: FnMut(&str) -> Option<P>,
+    {
+        self.settings.expand_all(
+            input.as_ref(),
+            &mut self.context,
+            &mut self.home_dir,
+            placeholder,
+        )
+    }
+}
+
+impl Settings {
+    /// Expands `input`; with a `recovery`, errors are collected in it instead of being returned.
+    fn expand<'i, CO, C, E, P, HD>(
+        self,
+        input: &'i str,
+        context: &mut C,
+        mut home_dir: HD,
+        recovery: Option<Recovery<'_, E>>,
+    ) -> Result<Cow<'i, str>, ExpandError<E>>
+    where
+        CO: AsRef<str>,
+        C: Context<Value = CO, Error = E>,
+        P: AsRef<Path>,
+        HD: FnMut(&str) -> Option<P>,
+    {
+        if self.dialect == Dialect::Cmd {
+            return cmd::expand(
+                input,
+                |name: &str| context.lookup(name),
+                self.strict,
+                recovery,
+            );
+        }
+
+        // `~$VAR/...` may only be expanded as `~/...`, never as a home directory of some user
+        let literal_user = match split_tilde_prefix(input) {
+            Some((user, _)) => !user.contains('$'),
+            None => false,
+        };
+        let mut tilde = |user: &str| {
+            if self.tilde {
+                home_dir(user).map(|hd| hd.as_ref().display().to_string())
+            } else {
+                None
+            }
+        };
+        // the closures use the context, one at a time
+        let context = RefCell::new(context);
+        let result = Expansion {
+            input,
+            syntax: self.syntax(),
+            context: &mut |name: &str| context.borrow_mut().lookup(name),
+            assign: &mut |name: &str, value: &str| context.borrow_mut().assign(name, value),
+            tilde: &mut tilde,
+            execute: &mut |command: &str| context.borrow_mut().execute(command),
+            options: self.options(),
+            path: Vec::new(),
+            recovery,
+        }
+        .expand()?;
+        if !self.tilde {
+            return Ok(result);
+        }
+        Ok(apply_tilde(input, result, |user| {
+            if user.is_empty() || literal_user {
+                tilde(user)
+            } else {
+                None
+            }
+        }))
+    }
+
+    /// Expands `input`, collecting the errors along with the result instead of returning them.
+    fn expand_all<'i, CO, C, E, P, HD>(
+        self,
+        input: &'i str,
+        context: &mut C,
+        home_dir: HD,
+        placeholder: Option<&str>,
+    ) -> (Cow<'i, str>, Vec<ExpandError<E>>)
+    where
+        CO: AsRef<str>,
+        C: Context<Value = CO, Error = E>,
+        P: AsRef<Path>,
+        HD: FnMut(&str) -> Option<P>,
+    {
+        let mut errors = Vec::new();
+        let recovery = Recovery {
+            errors: &mut errors,
+            placeholder,
+        };
+        // the recovery collects the errors of all references, so an error which is returned
+        // anyway concerns the input as a whole, which is then left as it is
+        let result = match self.expand(input, context, home_dir, Some(recovery)) {
+            Ok(result) => result,
+            Err(error) => {
+                errors.push(error);
+                input.into()
+            }
+        };
+        (result, errors)
+    }
+
+    /// Expands the command line `input` and splits it into arguments; see `args_with_context()`.
+    fn split_args<CO, C, E, P, HD>(
+        self,
+        input: &str,
+        context: &mut C,
+        mut home_dir: HD,
+    ) -> Result<Vec<String>, ExpandError<E>>
+    where
+        CO: AsRef<str>,
+        C: Context<Value = CO, Error = E>,
+        P: AsRef<Path>,
+        HD: FnMut(&str) -> Option<P>,
+    {
+        // `IFS` is not referenced in the input, so its errors concern the whole input
+        let ifs = match context.lookup("IFS") {
+            Ok(Some(ifs)) => ifs.as_ref().to_owned(),
+            Ok(None) => fields::DEFAULT_IFS.to_owned(),
+            Err(cause) => {
+                return Err(ExpandError::Lookup(LookupError {
+                    var_name: "IFS".into(),
+                    cause,
+                    position: Position::new(input, 0..input.len()),
+                }))
+            }
+        };
+        let syntax = self.syntax();
+        let mut tilde = |user: &str| {
+            if self.tilde {
+                home_dir(user).map(|hd| hd.as_ref().display().to_string())
+            } else {
+                None
+            }
+        };
+        let context = RefCell::new(context);
+        let mut expansion = Expansion {
+            input,
+            syntax,
+            context: &mut |name: &str| context.borrow_mut().lookup(name),
+            assign: &mut |name: &str, value: &str| context.borrow_mut().assign(name, value),
+            tilde: &mut tilde,
+            execute: &mut |command: &str| context.borrow_mut().execute(command),
+            options: self.options(),
+            path: Vec::new(),
+            recovery: None,
+        };
+        let mut fields = Fields::new(&ifs);
+        expansion.split_segments(
+            &parse::parse_without_tilde(input, syntax),
+            &mut fields,
+            false,
+        )?;
+        Ok(fields.finish())
+    }
+
+    /// The syntax of the shell dialect with these settings.
+    fn syntax(self) -> Syntax {
+        Syntax {
+            backslash_escapes: self.escape_style == EscapeStyle::Backslash,
+            quotes: self.quotes,
+            commands: self.commands,
+            arithmetic: self.arithmetic,
+        }
+    }
+
+    /// The options of environment expansion with these settings.
+    fn options(self) -> Options {
+        Options {
+            strict: self.strict,
+            syntax_errors: self.strict || self.syntax_errors,
+            operators: self.operators,
+            recursion_limit: self.recursion_limit,
+        }
+    }
+}
+
+/// Expands a command line with the contexts and settings of `expander` and splits it into
+/// arguments; see `args_with_context()`.
+pub fn split_args<CO, C, E, P, HD>(
+    expander: &mut Expander<C, HD>,
+    input: &str,
+) -> Result<Vec<String>, ExpandError<E>>
+where
+    CO: AsRef<str>,
+    C: Context<Value = CO, Error = E>,
+    P: AsRef<Path>,
+    HD: FnMut(&str) -> Option<P>,
+{
+    expander
+        .settings
+        .split_args(input, &mut expander.context, &mut expander.home_dir)
+}
+
+fn system_context(var_name: &str) -> Result<Option<String>, VarError> {
+    match std::env::var(var_name) {
+        Ok(value) => Ok(Some(value)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the home directory of `user` with `user_home_dir()`, or of the current user with
+/// `dirs::home_dir()` if `user` is empty; the default home directory context of `Expander`.
+pub fn system_home_dir(user: &str) -> Option<PathBuf> {
+    if user.is_empty() {
+        dirs::home_dir()
+    } else {
+        user_home_dir(user)
+    }
+}
+
+#[cfg(test)]
+mod expander_tests {
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+
+    use super::{Dialect, EscapeStyle, Expander, Operators};
+    use {CommandError, Context, ExpandError, Position};
+
+    fn context(s: &str) -> Result<Option<&'static str>, ()> {
+        match s {
+            "A" => Ok(Some("a value")),
+            "T" => Ok(Some("~")),
+            "EMPTY" => Ok(Some("")),
+            _ => Ok(None),
+        }
+    }
+
+    fn home_dir(user: &str) -> Option<PathBuf> {
+        match user {
+            "" => Some(Path::new("/home/user").into()),
+            "deploy" => Some(Path::new("/srv/deploy").into()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_settings() {
+        let expander = Expander::new().context(context).home_dir(home_dir);
+        assert_eq!(expander.expand("~/$A").unwrap(), "/home/user/a value");
+        assert_eq!(
+            expander.expand("~deploy/$A").unwrap(),
+            "/srv/deploy/a value"
+        );
+        assert_eq!(expander.expand("~nobody/$T").unwrap(), "~nobody/~");
+        assert_eq!(
+            expander.expand("$T/${X:-~deploy}").unwrap(),
+            "~//srv/deploy"
+        );
+        assert_eq!(expander.expand("\\$A '$A'").unwrap(), "\\a value 'a value'");
+
+        let expander = expander
+            .tilde(false)
+            .escape_style(EscapeStyle::Backslash)
+            .quotes(true);
+        assert_eq!(expander.expand("~/${X:-~}").unwrap(), "~/~");
+        assert_eq!(expander.expand("\\$A '$A'").unwrap(), "$A '$A'");
+
+        let expander = expander.dialect(Dialect::Cmd);
+        assert_eq!(expander.expand("~/%A% $A %X%").unwrap(), "~/a value $A %X%");
+    }
+
+    #[test]
+    fn test_operators() {
+        let input = "${A:-x} ${A#a} ${A:1:2} ${#A} ${A^}";
+        let expander = Expander::new().context(context);
+        assert_eq!(
+            expander.expand(input).unwrap(),
+            "a value  value  v 7 A value"
+        );
+
+        let expander = expander.operators(Operators::NONE);
+        assert_eq!(expander.expand(input).unwrap(), input);
+        assert_eq!(expander.expand("$A ${A}").unwrap(), "a value a value");
+
+        let expander = expander.operators(Operators {
+            patterns: true,
+            case: true,
+            ..Operators::NONE
+        });
+        assert_eq!(
+            expander.expand(input).unwrap(),
+            "${A:-x}  value ${A:1:2} ${#A} A value"
+        );
+    }
+
+    #[test]
+    fn test_recursion() {
+        fn context(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "HOME" => Ok(Some("/home/user")),
+                "DATA" => Ok(Some("$HOME/data")),
+                "CACHE" => Ok(Some("${DATA}/cache")),
+                "LOGS" => Ok(Some("${XDG_LOGS:-$DATA/logs}")),
+                "PRICE" => Ok(Some("$$5")),
+                "N" => Ok(Some("$((1 + 1))")),
+                "A" => Ok(Some("a$B")),
+                "B" => Ok(Some("${C#x}")),
+                "C" => Ok(Some("$A")),
+                "SELF" => Ok(Some("${SELF:-x}")),
+                _ => Ok(None),
+            }
+        }
+
+        let expander = Expander::new().context(context).tilde(false);
+        assert_eq!(expander.expand("$CACHE").unwrap(), "${DATA}/cache");
+
+        let expander = expander.recursion_limit(8).arithmetic(true);
+        assert_eq!(expander.expand("$CACHE").unwrap(), "/home/user/data/cache");
+        assert_eq!(expander.expand("$LOGS").unwrap(), "/home/user/data/logs");
+        assert_eq!(
+            expander.expand("${CACHE%/*} ${#DATA}").unwrap(),
+            "/home/user/data 15"
+        );
+        assert_eq!(
+            expander.expand("$PRICE $((N * 2)) $UNSET").unwrap(),
+            "$5 4 $UNSET"
+        );
+        assert_eq!(
+            expander.expand("$HOME $B"),
+            Err(ExpandError::Cycle {
+                path: vec!["B".into(), "C".into(), "A".into(), "B".into()],
+                position: Position::new("$HOME $B", 6..8),
+            })
+        );
+        assert_eq!(
+            expander.expand("$SELF"),
+            Err(ExpandError::Cycle {
+                path: vec!["SELF".into(), "SELF".into()],
+                position: Position::new("$SELF", 0..5),
+            })
+        );
+
+        let expander = expander.recursion_limit(1);
+        assert_eq!(expander.expand("$DATA").unwrap(), "/home/user/data");
+        assert_eq!(
+            expander.expand("$CACHE"),
+            Err(ExpandError::RecursionLimit {
+                var_name: "DATA".into(),
+                limit: 1,
+                position: Position::new("$CACHE", 0..6),
+            })
+        );
+    }
+
+    #[test]
+    fn test_expand_all() {
+        let expander = Expander::new()
+            .context(context)
+            .home_dir(home_dir)
+            .strict(true);
+        let (result, errors) = expander.expand_all("~/$A/$X/${Y}", None);
+        assert_eq!(result, "/home/user/a value/$X/${Y}");
+        assert_eq!(
+            errors,
+            [
+                ExpandError::Unset {
+                    var_name: "X".into(),
+                    position: Position::new("~/$A/$X/${Y}", 5..7),
+                },
+                ExpandError::Unset {
+                    var_name: "Y".into(),
+                    position: Position::new("~/$A/$X/${Y}", 8..12),
+                },
+            ]
+        );
+        let (result, errors) = expander.expand_all::<_, _, (), _>("$A ${X:-${Y}} ${", Some("_"));
+        assert_eq!(result, "a value _ _");
+        assert_eq!(
+            errors
+                .iter()
+                .map(|e| e.position().span.clone())
+                .collect::<Vec<_>>(),
+            [8..12, 14..16]
+        );
+        assert!(expander.expand_all::<_, _, (), _>("$A", None).1.is_empty());
+
+        let expander = expander.dialect(Dialect::Cmd);
+        let (result, errors) = expander.expand_all("%X% %A% %Y:~1%", Some(""));
+        assert_eq!(result, " a value ");
+        assert_eq!(
+            errors,
+            [
+                ExpandError::Unset {
+                    var_name: "X".into(),
+                    position: Position::new("%X% %A% %Y:~1%", 0..3),
+                },
+                ExpandError::Unset {
+                    var_name: "Y".into(),
+                    position: Position::new("%X% %A% %Y:~1%", 8..14),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assign() {
+        #[derive(Default)]
+        struct Vars(HashMap<String, String>);
+
+        impl Context for Vars {
+            type Value = String;
+            type Error = ();
+
+            fn lookup(&mut self, name: &str) -> Result<Option<String>, ()> {
+                Ok(self.0.get(name).cloned())
+            }
+
+            fn assign(&mut self, name: &str, value: &str) {
+                self.0.insert(name.into(), value.into());
+            }
+        }
+
+        let mut expander = Expander::new()
+            .context(Vars::default())
+            .home_dir(home_dir)
+            .arithmetic(true);
+        assert_eq!(
+            expander
+                .expand_mut("${A:=a} $A ${B=~/b} $((N += 2)) $((N * 2))")
+                .unwrap(),
+            "a a /home/user/b 2 4"
+        );
+        // failed arithmetic expansions assign nothing
+        assert!(expander.expand_mut("$(((N = 5) / 0))").is_err());
+        let mut assigned = expander.context.0.into_iter().collect::<Vec<_>>();
+        assigned.sort();
+        assert_eq!(
+            assigned,
+            [
+                ("A".into(), "a".into()),
+                ("B".into(), "/home/user/b".into()),
+                ("N".into(), "2".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_commands() {
+        #[derive(Clone)]
+        struct Commands;
+
+        impl Context for Commands {
+            type Value = &'static str;
+            type Error = &'static str;
+
+            fn lookup(&mut self, name: &str) -> Result<Option<&'static str>, &'static str> {
+                Ok(if name == "A" { Some("a") } else { None })
+            }
+
+            fn execute(&mut self, command: &str) -> Result<Option<String>, &'static str> {
+                match command {
+                    "date +%Y" => Ok(Some("2021\n".into())),
+                    "false" => Err("failed"),
+                    _ => Ok(None),
+                }
+            }
+        }
+
+        let expander = Expander::new().context(Commands);
+        assert_eq!(expander.expand("$A/$(date +%Y)").unwrap(), "a/$(date +%Y)");
+        let expander = expander.commands(true);
+        assert_eq!(
+            expander
+                .expand("$A/$(date +%Y)/`date +%Y`/$(other)")
+                .unwrap(),
+            "a/2021/2021/$(other)"
+        );
+        assert_eq!(
+            expander.expand("$(false)"),
+            Err(ExpandError::Command(CommandError {
+                command: "false".into(),
+                cause: "failed",
+                position: Position::new("$(false)", 0..8),
+            }))
+        );
+
+        // contexts which do not run commands leave them as they are
+        let expander = Expander::new().context(context).commands(true);
+        assert_eq!(expander.expand("$A/$(date)").unwrap(), "a value/$(date)");
+    }
+
+    #[test]
+    fn test_strict() {
+        let mut looked_up = Vec::new();
+        let mut expander = Expander::new()
+            .context(|s: &str| {
+                looked_up.push(s.to_owned());
+                context(s)
+            })
+            .strict(true);
+        assert_eq!(
+            expander.expand_mut("$A ${EMPTY} ${X:-x} ${X+y}").unwrap(),
+            "a value  x "
+        );
+        let unset = |input, span| {
+            Err(ExpandError::Unset {
+                var_name: "X".into(),
+                position: Position::new(input, span),
+            })
+        };
+        assert_eq!(expander.expand_mut("$A/$X"), unset("$A/$X", 3..5));
+        assert_eq!(expander.expand_mut("${X}"), unset("${X}", 0..4));
+        assert_eq!(expander.expand_mut("${X#x}"), unset("${X#x}", 0..6));
+        assert_eq!(expander.expand_mut("$$X ${A:-$X}").unwrap(), "$X a value");
+
+        let mut expander = expander.dialect(Dialect::Cmd);
+        assert_eq!(expander.expand_mut("%%X%% %X%"), unset("%%X%% %X%", 6..9));
+        assert_eq!(looked_up.last().unwrap(), "X");
+    }
+}
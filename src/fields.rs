@@ -0,0 +1,125 @@
+//! Field splitting, which turns an expanded command line into a list of arguments.
+
+/// The value of `IFS` which is used if the context does not define it.
+pub const DEFAULT_IFS: &str = " \t\n";
+
+/// A list of fields which is being built from the parts of an expanded command line.
+///
+/// Quoted parts are appended to the current field as they are, while unquoted parts are split
+/// into fields at the characters of `IFS`, as described in POSIX: sequences of `IFS` whitespace
+/// separate fields and are otherwise ignored, and every other `IFS` character separates fields
+/// by itself, along with the whitespace around it, so that two of them in a row delimit an empty
+/// field.
+pub struct Fields<'a> {
+    ifs: &'a str,
+    fields: Vec<String>,
+    current: String,
+    /// Whether the current field exists even if it is empty, like after `""`.
+    started: bool,
+    /// Whether the last character was `IFS` whitespace which ended a field, so that a following
+    /// non-whitespace `IFS` character does not delimit an empty field.
+    after_whitespace: bool,
+}
+
+impl<'a> Fields<'a> {
+    pub fn new(ifs: &'a str) -> Fields<'a> {
+        Fields {
+            ifs,
+            fields: Vec::new(),
+            current: String::new(),
+            started: false,
+            after_whitespace: false,
+        }
+    }
+
+    /// Appends text which is not split, like a quoted string.
+    pub fn push_quoted(&mut self, text: &str) {
+        self.current.push_str(text);
+        self.started = true;
+        self.after_whitespace = false;
+    }
+
+    /// Appends text which is split at the characters of `IFS`, like a value of an unquoted variable.
+    pub fn push_split(&mut self, text: &str) {
+        for c in text.chars() {
+            if !self.ifs.contains(c) {
+                self.current.push(c);
+                self.started = true;
+                self.after_whitespace = false;
+            } else if is_blank(c) {
+                if self.started {
+                    self.end_field();
+                    self.after_whitespace = true;
+                }
+            } else {
+                if self.started || !self.after_whitespace {
+                    self.end_field();
+                }
+                self.after_whitespace = false;
+            }
+        }
+    }
+
+    /// Ends the current field, if there is one, like a blank between words of a command line does.
+    pub fn delimit(&mut self) {
+        if self.started {
+            self.end_field();
+        }
+        self.after_whitespace = false;
+    }
+
+    pub fn finish(mut self) -> Vec<String> {
+        self.delimit();
+        self.fields
+    }
+
+    fn end_field(&mut self) {
+        self.fields.push(std::mem::take(&mut self.current));
+        self.started = false;
+    }
+}
+
+/// Returns `true` if `c` separates words of a command line.
+pub fn is_blank(c: char) -> bool {
+    c == ' ' || c == '\t' || c == '\n'
+}
+
+#[cfg(test)]
+mod fields_tests {
+    use super::{Fields, DEFAULT_IFS};
+
+    fn split(ifs: &str, parts: &[(&str, bool)]) -> Vec<String> {
+        let mut fields = Fields::new(ifs);
+        for &(text, quoted) in parts {
+            if quoted {
+                fields.push_quoted(text);
+            } else {
+                fields.push_split(text);
+            }
+        }
+        fields.finish()
+    }
+
+    #[test]
+    fn test_splitting() {
+        let empty: &[&str] = &[];
+        assert_eq!(split(DEFAULT_IFS, &[("", false)]), empty);
+        assert_eq!(split(DEFAULT_IFS, &[("  a \t b\n", false)]), ["a", "b"]);
+        assert_eq!(split(DEFAULT_IFS, &[("a b", true)]), ["a b"]);
+        assert_eq!(split(DEFAULT_IFS, &[("", true)]), [""]);
+        assert_eq!(
+            split(DEFAULT_IFS, &[("x", true), ("a b", false), ("y", true)]),
+            ["xa", "by"]
+        );
+        assert_eq!(
+            split(DEFAULT_IFS, &[("x", true), (" ", false), ("", true)]),
+            ["x", ""]
+        );
+        assert_eq!(split(DEFAULT_IFS, &[("a:b", false)]), ["a:b"]);
+        assert_eq!(split(":", &[("a::b:", false)]), ["a", "", "b"]);
+        assert_eq!(split(":", &[(":a", false)]), ["", "a"]);
+        assert_eq!(split(":", &[("a b", false)]), ["a b"]);
+        assert_eq!(split(" :", &[(" a : b  ::c ", false)]), ["a", "b", "", "c"]);
+        assert_eq!(split("", &[("a b", false)]), ["a b"]);
+    }
+}
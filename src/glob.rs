@@ -0,0 +1,143 @@
+//! Pathname expansion, which matches patterns like `*.toml` against the file system.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use os;
+use pattern::{self, Pattern};
+
+/// Defines what pathname expansion does with a pattern which does not match any path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoMatch {
+    /// The pattern is kept as it is, like shells do by default.
+    Keep,
+    /// An `ExpandError::NoMatch` error is returned, like with the `failglob` option of bash.
+    Fail,
+}
+
+/// Matches a pattern against the file system.
+///
+/// The pattern is split into components at slashes, and every component with wildcards is
+/// matched against the entries of the directories matched so far. Returns the sorted list of
+/// existing matching paths, or `None` if the pattern has no wildcards at all. Like in the
+/// `*_os()` functions, the pattern is converted with `os::decode()`, and names of entries with
+/// `os::encode()`, so that paths which are not valid Unicode are matched and returned as they are.
+pub fn expand(pattern: &str) -> Option<Vec<PathBuf>> {
+    let components: Vec<&str> = pattern.split('/').collect();
+    if !components.iter().any(|c| Pattern::new(c).has_wildcards()) {
+        return None;
+    }
+
+    // an absolute pattern starts with an empty component
+    let (mut paths, components) = match components.split_first() {
+        Some((&"", rest)) => (vec![PathBuf::from("/")], rest),
+        _ => (vec![PathBuf::new()], &components[..]),
+    };
+
+    for component in components {
+        let component_pattern = Pattern::new(component);
+        if !component_pattern.has_wildcards() {
+            let name = pattern::unescape(component);
+            let name = os::decode(&name);
+            for path in &mut paths {
+                // an empty component after a slash makes the path match only directories
+                *path = path.join(&name);
+            }
+            continue;
+        }
+
+        // hidden files only match patterns which start with a literal dot
+        let match_hidden = component.starts_with('.') || component.starts_with("\\.");
+        let mut matched_paths = Vec::new();
+        for path in &paths {
+            let dir = if path.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                path.as_path()
+            };
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                // unreadable directories are skipped, like shells do
+                Err(_) => continue,
+            };
+            for entry in entries.filter_map(Result::ok) {
+                let file_name = entry.file_name();
+                let name = os::encode(&file_name);
+                if (match_hidden || !name.starts_with('.')) && component_pattern.matches(&name) {
+                    matched_paths.push(path.join(&file_name));
+                }
+            }
+        }
+        paths = matched_paths;
+    }
+
+    paths.retain(|path| fs::symlink_metadata(path).is_ok());
+    paths.sort();
+    Some(paths)
+}
+
+#[cfg(test)]
+mod glob_tests {
+    use std::env;
+    use std::fs;
+
+    use super::expand;
+
+    #[test]
+    fn test_expand() {
+        let root = env::temp_dir().join(format!("shellexpand-glob-{}", std::process::id()));
+        for dir in ["a/x", "b/x", "c", "[d]"].iter() {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in ["a/x/1.toml", "b/x/2.toml", "b/x/3.txt", "c/.hidden", "c/x"].iter() {
+            fs::write(root.join(file), "").unwrap();
+        }
+        let root_pattern = root.display().to_string();
+        let expand = |pattern: &str| expand(&format!("{}/{}", root_pattern, pattern));
+        let paths = |paths: &[&str]| Some(paths.iter().map(|p| root.join(p)).collect::<Vec<_>>());
+
+        assert_eq!(expand("*/x/*.toml"), paths(&["a/x/1.toml", "b/x/2.toml"]));
+        assert_eq!(expand("?/x"), paths(&["a/x", "b/x", "c/x"]));
+        assert_eq!(expand("?/x/"), paths(&["a/x/", "b/x/"]));
+        assert_eq!(expand("[!a]/x/?.t*"), paths(&["b/x/2.toml", "b/x/3.txt"]));
+        assert_eq!(expand("c/*"), paths(&["c/x"]));
+        assert_eq!(expand("c/.*"), paths(&["c/.hidden"]));
+        assert_eq!(expand("\\[d]/*"), paths(&[]));
+        assert_eq!(expand("[[]d]"), paths(&["[d]"]));
+        assert_eq!(expand("*/nothing"), paths(&[]));
+        assert_eq!(expand("nothing/*"), paths(&[]));
+        assert_eq!(expand("a/x/1.toml"), None);
+        assert_eq!(expand("\\*"), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+    #[cfg(unix)]
+    #[test]
+    fn test_expand_non_unicode() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        use os;
+        use pattern;
+
+        let mut dir_name = OsStr::from_bytes(b"shellexpand-glob-\xff-").to_os_string();
+        dir_name.push(std::process::id().to_string());
+        let root = env::temp_dir().join(dir_name);
+        fs::create_dir_all(&root).unwrap();
+        let names = [OsStr::new("a.toml"), OsStr::from_bytes(b"\xfe.toml")];
+        for name in names.iter() {
+            fs::write(root.join(name), "").unwrap();
+        }
+        let root_pattern = pattern::escape(&os::encode(root.as_os_str()));
+
+        let paths = names.iter().map(|name| root.join(name)).collect::<Vec<_>>();
+        assert_eq!(expand(&format!("{}/?.toml", root_pattern)), Some(paths));
+        let encoded_name = os::encode(names[1]);
+        assert_eq!(
+            expand(&format!("{}/{}*", root_pattern, encoded_name)),
+            Some(vec![root.join(names[1])])
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
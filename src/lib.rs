@@ -3,21 +3,44 @@
 //! In particular, the following expansions are supported:
 //!
 //! * tilde expansion, when `~` in the beginning of a string, like in `"~/some/path"`,
-//!   is expanded into the home directory of the current user;
+//!   is expanded into the home directory of the current user, and `~user`, like in
+//!   `"~deploy/releases"`, is expanded into the home directory of the named user;
 //! * environment expansion, when `$A` or `${B}`, like in `"~/$A/${B}something"`,
-//!   are expanded into their values in some environment.
+//!   are expanded into their values in some environment;
+//! * brace expansion, when `{a,b}` or `{1..3}`, like in `"logs/{api,worker}.log"`, are expanded
+//!   into several strings; see `braces()`;
+//! * pathname expansion, when `*`, `?` or `[...]`, like in `"~/.config/app/*.toml"`, are matched
+//!   against the file system; see `glob()`;
+//! * field splitting, when a command line like `"ls -l $FLAGS '~/My Files'"` is split into a list
+//!   of arguments after the expansions, with quotes removed; see `args()`.
 //!
 //! Environment expansion also supports default values with the familiar shell syntax,
 //! so for example `${UNSET_ENV:-42}` will use the specified default value, i.e. `42`, if
-//! the `UNSET_ENV` variable is not set in the environment.
+//! the `UNSET_ENV` variable is not set in the environment. The rest of the POSIX parameter
+//! expansion operators, `${VAR=word}`, `${VAR?message}` and `${VAR+word}`, with or without a colon,
+//! are supported as well; see `env_with_context()` for details. Arithmetic expansion, like
+//! `$((BASE_PORT + 1))`, is opt-in; see `env_with_context_and_arithmetic()`.
 //!
 //! The source of external information for these expansions (home directory and environment
 //! variables) is called their *context*. The context is provided to these functions as a closure
-//! of the respective type.
+//! of the respective type. Variable contexts may also be anything which implements the `Context`
+//! trait, like a reference to a `HashMap` of values, `Env` for the environment of the process or
+//! an `EnvSnapshot` of it.
 //!
 //! This crate provides both customizable functions, which require their context to be provided
 //! explicitly, and wrapper functions which use `dirs::home_dir()` and `std::env::var()`
 //! for obtaining home directory and environment variables, respectively.
+//! Alternatively, an `Expander` can be configured once with the contexts and the syntax to use, and
+//! then reused for all expansions of an application.
+//!
+//! The grammar of environment expansion is available on its own as well: `parse()` turns a string
+//! into a list of `Segment`s with their byte ranges in the input, which a `Visitor` can walk, for
+//! example to highlight or lint references to variables. For the common case of checking which
+//! variables a string needs, `references()` lists them with their operators and positions.
+//!
+//! The opposite direction is covered too: `contract_tilde()` and `contract()` replace the home
+//! directory with `~`, and the latter also values of given variables with references to them, for
+//! example to show paths in a shorter and more portable form.
 //!
 //! Also there is a "full" function which performs both tilde and environment
 //! expansion, but does it correctly, rather than just doing one after another: for example,
@@ -25,7 +48,9 @@
 //! won't be expanded.
 //!
 //! All functions return `Cow<str>` because it is possible for their input not to contain anything
-//! which triggers the expansion. In that case performing allocations can be avoided.
+//! which triggers the expansion. In that case performing allocations can be avoided. Strings which
+//! are not valid Unicode, like some paths, are supported by the `*_os` and `*_path` functions,
+//! which return `Cow<OsStr>` and `Cow<Path>` instead.
 //!
 //! Please note that by default unknown variables in environment expansion are left as they are
 //! and are not, for example, substituted with an empty string:
@@ -53,10 +78,11 @@
 //!
 //! assert_eq!(
 //!     shellexpand::env("$MOST_LIKELY_NONEXISTING_VAR"),
-//!     Err(shellexpand::LookupError {
+//!     Err(shellexpand::ExpandError::Lookup(shellexpand::LookupError {
 //!         var_name: "MOST_LIKELY_NONEXISTING_VAR".into(),
-//!         cause: env::VarError::NotPresent
-//!     })
+//!         cause: env::VarError::NotPresent,
+//!         position: shellexpand::Position::new("$MOST_LIKELY_NONEXISTING_VAR", 0..28),
+//!     }))
 //! );
 //! ```
 //!
@@ -87,14 +113,95 @@
 //!
 //! The above example also demonstrates the flexibility of context function signatures: the context
 //! function may return anything which can be `AsRef`ed into a string slice.
+//!
+//! On the other hand, if a reference to an unknown variable is always a mistake, a strict
+//! `Expander` reports it as `ExpandError::Unset` with the name of the variable, like shells do
+//! with `set -u`, while still allowing default values:
+//!
+//! ```
+//! use std::env;
+//! use shellexpand::Expander;
+//!
+//! env::remove_var("MOST_LIKELY_NONEXISTING_VAR");
+//!
+//! let expander = Expander::new().tilde(false).strict(true);
+//! assert_eq!(
+//!     expander.expand("${MOST_LIKELY_NONEXISTING_VAR:-42}").unwrap(),
+//!     "42"
+//! );
+//! assert_eq!(
+//!     expander.expand("$MOST_LIKELY_NONEXISTING_VAR"),
+//!     Err(shellexpand::ExpandError::Unset {
+//!         var_name: "MOST_LIKELY_NONEXISTING_VAR".into(),
+//!         position: shellexpand::Position::new("$MOST_LIKELY_NONEXISTING_VAR", 0..28),
+//!     })
+//! );
+//! ```
+//!
+//! Strict expansions also report malformed references, like `${` without a closing brace, as
+//! `ExpandError::Syntax` instead of leaving them as they are, and `Expander::syntax_errors()`
+//! reports them without making unknown variables errors. Every error carries the `Position` of
+//! the part of the input which caused it, that is, its byte range along with its line and column:
+//!
+//! ```
+//! use shellexpand::{ExpandError, Expander, SyntaxErrorKind};
+//!
+//! let expander = Expander::new().syntax_errors(true);
+//! let error = expander.expand("PATH=/bin\nHOME=${HOME").unwrap_err();
+//! match error {
+//!     ExpandError::Syntax(ref e) => assert_eq!(e.kind, SyntaxErrorKind::UnterminatedBrace),
+//!     _ => panic!("unexpected error: {}", error),
+//! }
+//! assert_eq!(error.to_string(), "syntax error: unterminated '${'");
+//! assert_eq!(error.position().span, 15..17);
+//! assert_eq!((error.position().line, error.position().column), (2, 6));
+//! ```
+
+// `SI: ?Sized` is kept next to the type parameter, with the rest of the bounds in `where` clauses
+#![allow(clippy::multiple_bound_locations)]
 
 extern crate dirs_next as dirs;
+#[cfg(unix)]
+extern crate libc;
 
 use std::borrow::Cow;
 use std::env::VarError;
 use std::error::Error;
+use std::ffi::OsStr;
 use std::fmt;
-use std::path::Path;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::slice;
+
+use fields::Fields;
+use pattern::Pattern;
+
+pub use arith::{ArithmeticError, ArithmeticErrorKind};
+pub use braces::{braces, BracesError};
+pub use context::{Context, Env, EnvSnapshot};
+pub use contract::{contract, contract_tilde, contract_tilde_with_context, contract_with_context};
+pub use expander::{Dialect, EscapeStyle, Expander, Operators, SystemContext, SystemHomeDir};
+pub use glob::NoMatch;
+pub use parse::{
+    parse, walk, walk_modifier, walk_segment, Modifier, Operator, Segment, Syntax, SyntaxError,
+    SyntaxErrorKind, Visitor, Word,
+};
+pub use references::{references, Reference, References};
+pub use users::user_home_dir;
+
+mod arith;
+mod braces;
+mod cmd;
+mod context;
+mod contract;
+mod expander;
+mod fields;
+mod glob;
+mod os;
+mod parse;
+mod pattern;
+mod references;
+mod users;
 
 /// Performs both tilde and environment expansion using the provided contexts.
 ///
@@ -139,10 +246,11 @@ use std::path::Path;
 /// // Errors from environment expansion are propagated to the result
 /// assert_eq!(
 ///     shellexpand::full_with_context("~/$E/something", home_dir, get_env),
-///     Err(shellexpand::LookupError {
+///     Err(shellexpand::ExpandError::Lookup(shellexpand::LookupError {
 ///         var_name: "E".into(),
-///         cause: "some error"
-///     })
+///         cause: "some error",
+///         position: shellexpand::Position::new("~/$E/something", 2..4),
+///     }))
 /// );
 ///
 /// // Input without starting tilde and without variables does not cause allocations
@@ -163,33 +271,262 @@ pub fn full_with_context<SI: ?Sized, CO, C, E, P, HD>(
     input: &SI,
     home_dir: HD,
     context: C,
-) -> Result<Cow<str>, LookupError<E>>
+) -> Result<Cow<'_, str>, ExpandError<E>>
 where
     SI: AsRef<str>,
     CO: AsRef<str>,
-    C: FnMut(&str) -> Result<Option<CO>, E>,
+    C: Context<Value = CO, Error = E>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    Expander::new()
+        .context(context)
+        .home_dir(home_dir_expander(home_dir))
+        .expand_mut(input)
+}
+
+/// Same as `full_with_context()`, but also supports backslash escapes.
+///
+/// See `env_with_context_and_escapes()` for the description of the escapes. In addition, `\~` in
+/// the beginning of the input or of a word inside braces prevents tilde expansion.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{PathBuf, Path};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+///
+/// fn get_env(name: &str) -> Result<Option<&'static str>, &'static str> {
+///     match name {
+///         "A" => Ok(Some("a value")),
+///         _ => Ok(None)
+///     }
+/// }
+///
+/// assert_eq!(
+///     shellexpand::full_with_context_and_escapes("~/$A/\\$A", home_dir, get_env).unwrap(),
+///     "/home/user/a value/$A"
+/// );
+/// assert_eq!(
+///     shellexpand::full_with_context_and_escapes("\\~/$A", home_dir, get_env).unwrap(),
+///     "~/a value"
+/// );
+/// ```
+pub fn full_with_context_and_escapes<SI: ?Sized, CO, C, E, P, HD>(
+    input: &SI,
+    home_dir: HD,
+    context: C,
+) -> Result<Cow<'_, str>, ExpandError<E>>
+where
+    SI: AsRef<str>,
+    CO: AsRef<str>,
+    C: Context<Value = CO, Error = E>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    Expander::new()
+        .context(context)
+        .home_dir(home_dir_expander(home_dir))
+        .escape_style(EscapeStyle::Backslash)
+        .expand_mut(input)
+}
+
+/// Same as `full_with_context()`, but also recognizes quotes.
+///
+/// See `env_with_context_and_quotes()` for the description of quoting. Tilde prefixes are not
+/// expanded inside quotes, and a quoted tilde does not start a tilde prefix.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{PathBuf, Path};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+///
+/// fn get_env(name: &str) -> Result<Option<&'static str>, &'static str> {
+///     match name {
+///         "A" => Ok(Some("a value")),
+///         _ => Ok(None)
+///     }
+/// }
+///
+/// assert_eq!(
+///     shellexpand::full_with_context_and_quotes("~/\"$A\"/'$A'", home_dir, get_env).unwrap(),
+///     "/home/user/\"a value\"/'$A'"
+/// );
+/// assert_eq!(
+///     shellexpand::full_with_context_and_quotes("\"~/$A\"", home_dir, get_env).unwrap(),
+///     "\"~/a value\""
+/// );
+/// ```
+pub fn full_with_context_and_quotes<SI: ?Sized, CO, C, E, P, HD>(
+    input: &SI,
+    home_dir: HD,
+    context: C,
+) -> Result<Cow<'_, str>, ExpandError<E>>
+where
+    SI: AsRef<str>,
+    CO: AsRef<str>,
+    C: Context<Value = CO, Error = E>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    Expander::new()
+        .context(context)
+        .home_dir(home_dir_expander(home_dir))
+        .quotes(true)
+        .expand_mut(input)
+}
+
+/// Same as `full_with_context()`, but also evaluates arithmetic expansions.
+///
+/// See `env_with_context_and_arithmetic()` for the description of arithmetic expansion.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{PathBuf, Path};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+///
+/// fn get_env(name: &str) -> Result<Option<&'static str>, ()> {
+///     match name {
+///         "SHARD" => Ok(Some("3")),
+///         _ => Ok(None)
+///     }
+/// }
+///
+/// assert_eq!(
+///     shellexpand::full_with_context_and_arithmetic("~/shard-$((SHARD + 1))", home_dir, get_env)
+///         .unwrap(),
+///     "/home/user/shard-4"
+/// );
+/// ```
+pub fn full_with_context_and_arithmetic<SI: ?Sized, CO, C, E, P, HD>(
+    input: &SI,
+    home_dir: HD,
+    context: C,
+) -> Result<Cow<'_, str>, ExpandError<E>>
+where
+    SI: AsRef<str>,
+    CO: AsRef<str>,
+    C: Context<Value = CO, Error = E>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    Expander::new()
+        .context(context)
+        .home_dir(home_dir_expander(home_dir))
+        .arithmetic(true)
+        .expand_mut(input)
+}
+
+/// Same as `full_with_context()`, but also performs command substitution.
+///
+/// See `env_with_context_and_commands()` for the description of command substitution.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{PathBuf, Path};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+///
+/// fn get_env(name: &str) -> Result<Option<&'static str>, &'static str> {
+///     match name {
+///         "A" => Ok(Some("a value")),
+///         _ => Ok(None)
+///     }
+/// }
+///
+/// fn execute(command: &str) -> Result<String, &'static str> {
+///     match command {
+///         "git rev-parse --short HEAD" => Ok("a1b2c3d\n".into()),
+///         _ => Err("unknown command")
+///     }
+/// }
+///
+/// assert_eq!(
+///     shellexpand::full_with_context_and_commands(
+///         "~/$A/$(git rev-parse --short HEAD)",
+///         home_dir,
+///         get_env,
+///         execute
+///     ).unwrap(),
+///     "/home/user/a value/a1b2c3d"
+/// );
+/// ```
+pub fn full_with_context_and_commands<SI: ?Sized, CO, C, E, P, HD, X>(
+    input: &SI,
+    home_dir: HD,
+    context: C,
+    execute: X,
+) -> Result<Cow<'_, str>, ExpandError<E>>
+where
+    SI: AsRef<str>,
+    CO: AsRef<str>,
+    C: Context<Value = CO, Error = E>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+    X: FnMut(&str) -> Result<String, E>,
+{
+    Expander::new()
+        .context(Executing { context, execute })
+        .home_dir(home_dir_expander(home_dir))
+        .commands(true)
+        .expand_mut(input)
+}
+
+/// Turns a home directory context into a function expanding tilde prefixes.
+///
+/// The returned function takes the user name after the tilde and returns the home directory of
+/// the current user if the name is empty. `home_dir` is called at most once.
+fn home_dir_expander<P, HD>(home_dir: HD) -> impl FnMut(&str) -> Option<PathBuf>
+where
     P: AsRef<Path>,
     HD: FnOnce() -> Option<P>,
 {
-    env_with_context(input, context).map(|r| match r {
+    let mut home_dir = Some(home_dir);
+    let mut result = None;
+    move |user| {
+        if !user.is_empty() {
+            return None;
+        }
+        if let Some(home_dir) = home_dir.take() {
+            result = home_dir().map(|hd| hd.as_ref().to_path_buf());
+        }
+        result.clone()
+    }
+}
+
+/// Applies the provided tilde expansion to the result of environment expansion of `input`.
+///
+/// `tilde` takes the user name after the tilde and returns the home directory of that user, or
+/// `None` if the tilde should be left as is.
+fn apply_tilde<'a, T>(input: &str, expanded: Cow<'a, str>, mut tilde: T) -> Cow<'a, str>
+where
+    T: FnMut(&str) -> Option<String>,
+{
+    let mut tilde = |s: &str| {
+        let (user, input_after_user) = split_tilde_prefix(s)?;
+        tilde(user).map(|hd| format!("{}{}", hd, input_after_user))
+    };
+    match expanded {
         // variable expansion did not modify the original string, so we can apply tilde expansion
         // directly
-        Cow::Borrowed(s) => tilde_with_context(s, home_dir),
+        Cow::Borrowed(s) => tilde(s).map(Cow::Owned).unwrap_or(Cow::Borrowed(s)),
         Cow::Owned(s) => {
             // if the original string does not start with a tilde but the processed one does,
             // then the tilde is contained in one of variables and should not be expanded
-            if !input.as_ref().starts_with("~") && s.starts_with("~") {
+            if !input.starts_with('~') && s.starts_with('~') {
                 // return as is
                 s.into()
             } else {
-                if let Cow::Owned(s) = tilde_with_context(&s, home_dir) {
-                    s.into()
-                } else {
-                    s.into()
-                }
+                tilde(&s).unwrap_or(s).into()
             }
         }
-    })
+    }
 }
 
 /// Same as `full_with_context()`, but forbids the variable lookup function to return errors.
@@ -197,7 +534,7 @@ where
 /// This function also performs full shell-like expansion, but it uses
 /// `env_with_context_no_errors()` for environment expansion whose context lookup function returns
 /// just `Option<CO>` instead of `Result<Option<CO>, E>`. Therefore, the function itself also
-/// returns just `Cow<str>` instead of `Result<Cow<str>, LookupError<E>>`. Otherwise it is
+/// returns just `Cow<str>` instead of `Result<Cow<str>, ExpandError<E>>`. Otherwise it is
 /// identical to `full_with_context()`.
 ///
 /// # Examples
@@ -242,7 +579,7 @@ pub fn full_with_context_no_errors<SI: ?Sized, CO, C, P, HD>(
     input: &SI,
     home_dir: HD,
     mut context: C,
-) -> Cow<str>
+) -> Cow<'_, str>
 where
     SI: AsRef<str>,
     CO: AsRef<str>,
@@ -250,10 +587,12 @@ where
     P: AsRef<Path>,
     HD: FnOnce() -> Option<P>,
 {
-    match full_with_context(input, home_dir, move |s| Ok::<Option<CO>, ()>(context(s))) {
-        Ok(result) => result,
-        Err(_) => unreachable!(),
-    }
+    // failed `${VAR:?message}` expansions, the only possible errors, are left as they are
+    Expander::new()
+        .context(move |s: &str| Ok::<Option<CO>, ()>(context(s)))
+        .home_dir(home_dir_expander(home_dir))
+        .expand_all_mut(input, None)
+        .0
 }
 
 /// Performs both tilde and environment expansions in the default system context.
@@ -269,6 +608,11 @@ where
 /// This function behaves exactly like `full_with_context()` in regard to tilde-containing
 /// variables in the beginning of the input string.
 ///
+/// Unlike `full_with_context()`, this function also expands `~user` prefixes, like `tilde()`
+/// does, looking the user up with `user_home_dir()`. Unknown users are left as they are. A user
+/// name is only looked up if it is written literally in the input, so `~$USER` is never expanded
+/// into someone's home directory.
+///
 /// # Examples
 ///
 /// ```
@@ -291,32 +635,55 @@ where
 /// // Unknown variables cause expansion errors
 /// assert_eq!(
 ///     shellexpand::full("~/$UNKNOWN/$B"),
-///     Err(shellexpand::LookupError {
+///     Err(shellexpand::ExpandError::Lookup(shellexpand::LookupError {
 ///         var_name: "UNKNOWN".into(),
-///         cause: env::VarError::NotPresent
-///     })
+///         cause: env::VarError::NotPresent,
+///         position: shellexpand::Position::new("~/$UNKNOWN/$B", 2..10),
+///     }))
 /// );
 /// ```
 #[inline]
-pub fn full<SI: ?Sized>(input: &SI) -> Result<Cow<str>, LookupError<VarError>>
+pub fn full<SI: ?Sized>(input: &SI) -> Result<Cow<'_, str>, ExpandError<VarError>>
 where
     SI: AsRef<str>,
 {
-    full_with_context(input, dirs::home_dir, |s| std::env::var(s).map(Some))
+    Expander::new()
+        .context(|s: &str| std::env::var(s).map(Some))
+        .expand(input)
+}
+
+/// Performs `full()` with the given context, converting home directories to the strings which
+/// are inserted with `home_dir_to_string`.
+fn full_with_system_home_dirs<CO, C, F>(
+    input: &str,
+    context: C,
+    home_dir_to_string: F,
+) -> Result<Cow<'_, str>, ExpandError<VarError>>
+where
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, VarError>,
+    F: Fn(&Path) -> String,
+{
+    Expander::new()
+        .context(context)
+        .home_dir(|user: &str| expander::system_home_dir(user).map(|hd| home_dir_to_string(&hd)))
+        .expand_mut(input)
 }
 
 /// Represents a variable lookup error.
 ///
-/// This error is returned by `env_with_context()` function (and, therefore, also by `env()`,
-/// `full_with_context()` and `full()`) when the provided context function returns an error. The
-/// original error is provided in the `cause` field, while `name` contains the name of a variable
-/// whose expansion caused the error.
+/// This error is returned, wrapped into `ExpandError::Lookup`, by `env_with_context()` function
+/// (and, therefore, also by `env()`, `full_with_context()` and `full()`) when the provided context
+/// function returns an error. The original error is provided in the `cause` field, while `name`
+/// contains the name of a variable whose expansion caused the error.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct LookupError<E> {
     /// The name of the problematic variable inside the input string.
     pub var_name: String,
     /// The original error returned by the context function.
     pub cause: E,
+    /// Where the reference to the variable is in the input.
+    pub position: Position,
 }
 
 impl<E: fmt::Display> fmt::Display for LookupError<E> {
@@ -335,320 +702,266 @@ impl<E: Error + 'static> Error for LookupError<E> {
     }
 }
 
-macro_rules! try_lookup {
-    ($name:expr, $e:expr) => {
-        match $e {
-            Ok(s) => s,
-            Err(e) => {
-                return Err(LookupError {
-                    var_name: $name.into(),
-                    cause: e,
-                })
-            }
-        }
-    };
-}
-
-fn is_valid_var_name_char(c: char) -> bool {
-    c.is_alphanumeric() || c == '_'
-}
-
-/// Performs the environment expansion using the provided context.
-///
-/// This function walks through the input string `input` and attempts to construct a new string by
-/// replacing all shell-like variable sequences with the corresponding values obtained via the
-/// `context` function. The latter may return an error; in this case the error will be returned
-/// immediately, along with the name of the offending variable. Also the context function may
-/// return `Ok(None)`, indicating that the given variable is not available; in this case the
-/// variable sequence is left as it is in the output string.
-///
-/// The syntax of variables resembles the one of bash-like shells: all of `$VAR`, `${VAR}`,
-/// `$NAME_WITH_UNDERSCORES` are valid variable references, and the form with braces may be used to
-/// separate the reference from the surrounding alphanumeric text: `before${VAR}after`. Note,
-/// however, that for simplicity names like `$123` or `$1AB` are also valid, as opposed to shells
-/// where `$<number>` has special meaning of positional arguments. Also note that "alphanumericity"
-/// of variable names is checked with `char::is_alphanumeric()`, therefore lots of characters which
-/// are considered alphanumeric by the Unicode standard are also valid names for variables. When
-/// unsure, use braces to separate variables from the surrounding text.
-///
-/// This function has four generic type parameters: `SI` represents the input string, `CO` is the
-/// output of context lookups, `C` is the context closure and `E` is the type of errors returned by
-/// the context function. `SI` and `CO` must be types, a references to which can be converted to
-/// a string slice. For example, it is fine for the context function to return `&str`s, `String`s or
-/// `Cow<str>`s, which gives the user a lot of flexibility.
-///
-/// If the context function returns an error, it will be wrapped into `LookupError` and returned
-/// immediately. `LookupError`, besides the original error, also contains a string with the name of
-/// the variable whose expansion caused the error. `LookupError` implements `Error`, `Clone` and
-/// `Eq` traits for further convenience and interoperability.
+/// Performs brace expansion followed by tilde and environment expansions of every word.
 ///
-/// If you need to expand system environment variables, you can use `env()` or `full()` functions.
-/// If your context does not have errors, you may use `env_with_context_no_errors()` instead of
-/// this function because it provides a simpler API.
+/// This function expands the input with `braces()` and then expands every resulting word with
+/// `full_with_context()`, which is the order in which shells perform these expansions. Therefore
+/// brace expressions may contain variables and tilde prefixes, like in `{~,$A}/file`, but brace
+/// expressions in the values of variables are not expanded. `home_dir` is called at most once.
+/// If brace expansion would produce too many words, `ExpandError::Braces` is returned.
 ///
 /// # Examples
 ///
 /// ```
-/// fn context(s: &str) -> Result<Option<&'static str>, &'static str> {
-///     match s {
-///         "A" => Ok(Some("a value")),
-///         "B" => Ok(Some("b value")),
-///         "E" => Err("something went wrong"),
+/// use std::path::{PathBuf, Path};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+///
+/// fn get_env(name: &str) -> Result<Option<&'static str>, &'static str> {
+///     match name {
+///         "SERVICE" => Ok(Some("api")),
+///         "BRACES" => Ok(Some("{a,b}")),
 ///         _ => Ok(None)
 ///     }
 /// }
 ///
-/// // Regular variables are expanded
-/// assert_eq!(
-///     shellexpand::env_with_context("begin/$A/${B}s/end", context).unwrap(),
-///     "begin/a value/b values/end"
-/// );
-///
-/// // Expand to a default value if the variable is not defined
-/// assert_eq!(
-///     shellexpand::env_with_context("begin/${UNSET_ENV:-42}/end", context).unwrap(),
-///     "begin/42/end"
-/// );
-///
-/// // Unknown variables are left as is
 /// assert_eq!(
-///     shellexpand::env_with_context("begin/$UNKNOWN/end", context).unwrap(),
-///     "begin/$UNKNOWN/end"
+///     shellexpand::full_braces_with_context("~/logs/{$SERVICE,worker}/{01..02}.log", home_dir, get_env)
+///         .unwrap(),
+///     vec![
+///         "/home/user/logs/api/01.log",
+///         "/home/user/logs/api/02.log",
+///         "/home/user/logs/worker/01.log",
+///         "/home/user/logs/worker/02.log",
+///     ]
 /// );
 ///
-/// // Errors are propagated
 /// assert_eq!(
-///     shellexpand::env_with_context("begin${E}end", context),
-///     Err(shellexpand::LookupError {
-///         var_name: "E".into(),
-///         cause: "something went wrong"
-///     })
+///     shellexpand::full_braces_with_context("$BRACES", home_dir, get_env).unwrap(),
+///     vec!["{a,b}"]
 /// );
 /// ```
-pub fn env_with_context<SI: ?Sized, CO, C, E>(
+pub fn full_braces_with_context<SI: ?Sized, CO, C, E, P, HD>(
     input: &SI,
+    home_dir: HD,
     mut context: C,
-) -> Result<Cow<str>, LookupError<E>>
+) -> Result<Vec<String>, ExpandError<E>>
 where
     SI: AsRef<str>,
     CO: AsRef<str>,
-    C: FnMut(&str) -> Result<Option<CO>, E>,
+    C: Context<Value = CO, Error = E>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
 {
-    let input_str = input.as_ref();
-    if let Some(idx) = input_str.find('$') {
-        let mut result = String::with_capacity(input_str.len());
-
-        let mut input_str = input_str;
-        let mut next_dollar_idx = idx;
-        loop {
-            result.push_str(&input_str[..next_dollar_idx]);
-
-            input_str = &input_str[next_dollar_idx..];
-            if input_str.is_empty() {
-                break;
-            }
-
-            fn find_dollar(s: &str) -> usize {
-                s.find('$').unwrap_or(s.len())
+    let mut home_dir = Some(home_dir);
+    let mut cached_home_dir = None;
+    braces(input.as_ref())?
+        .into_iter()
+        .map(|word| {
+            if let Some(home_dir) = home_dir.take() {
+                cached_home_dir = home_dir();
             }
-
-            let next_char = input_str[1..].chars().next();
-            if next_char == Some('{') {
-                match input_str.find('}') {
-                    Some(closing_brace_idx) => {
-                        let mut default_value = None;
-
-                        // Search for the default split
-                        let var_name_end_idx = match input_str[..closing_brace_idx].find(":-") {
-                            // Only match if there's a variable name, ie. this is not valid ${:-value}
-                            Some(default_split_idx) if default_split_idx != 2 => {
-                                default_value =
-                                    Some(&input_str[default_split_idx + 2..closing_brace_idx]);
-                                default_split_idx
-                            }
-                            _ => closing_brace_idx,
-                        };
-
-                        let var_name = &input_str[2..var_name_end_idx];
-                        match context(var_name) {
-                            // if we have the variable set to some value
-                            Ok(Some(var_value)) => {
-                                result.push_str(var_value.as_ref());
-                                input_str = &input_str[closing_brace_idx + 1..];
-                                next_dollar_idx = find_dollar(input_str);
-                            }
-
-                            // if the variable is set and empty or unset
-                            not_found_or_empty => {
-                                let value = match (not_found_or_empty, default_value) {
-                                    // return an error if we don't have a default and the variable is unset
-                                    (Err(err), None) => {
-                                        return Err(LookupError {
-                                            var_name: var_name.into(),
-                                            cause: err,
-                                        });
-                                    }
-                                    // use the default value if set
-                                    (_, Some(default)) => default,
-                                    // leave the variable as it is if the environment is empty
-                                    (_, None) => &input_str[..closing_brace_idx + 1],
-                                };
-
-                                result.push_str(value);
-                                input_str = &input_str[closing_brace_idx + 1..];
-                                next_dollar_idx = find_dollar(input_str);
-                            }
-                        }
-                    }
-                    // unbalanced braces
-                    None => {
-                        result.push_str(&input_str[..2]);
-                        input_str = &input_str[2..];
-                        next_dollar_idx = find_dollar(input_str);
-                    }
-                }
-            } else if next_char.map(is_valid_var_name_char) == Some(true) {
-                let end_idx = 2 + input_str[2..]
-                    .find(|c: char| !is_valid_var_name_char(c))
-                    .unwrap_or(input_str.len() - 2);
-
-                let var_name = &input_str[1..end_idx];
-                match try_lookup!(var_name, context(var_name)) {
-                    Some(var_value) => {
-                        result.push_str(var_value.as_ref());
-                        input_str = &input_str[end_idx..];
-                        next_dollar_idx = find_dollar(input_str);
-                    }
-                    None => {
-                        result.push_str(&input_str[..end_idx]);
-                        input_str = &input_str[end_idx..];
-                        next_dollar_idx = find_dollar(input_str);
-                    }
-                }
-            } else {
-                result.push_str("$");
-                input_str = if next_char == Some('$') {
-                    &input_str[2..] // skip the next dollar for escaping
-                } else {
-                    &input_str[1..]
-                };
-                next_dollar_idx = find_dollar(input_str);
-            };
-        }
-        Ok(result.into())
-    } else {
-        Ok(input_str.into())
-    }
+            full_with_context(
+                &word,
+                || cached_home_dir.as_ref(),
+                |name: &str| context.lookup(name),
+            )
+            .map(Cow::into_owned)
+        })
+        .collect()
 }
 
-/// Same as `env_with_context()`, but forbids the variable lookup function to return errors.
+/// Performs brace expansion followed by tilde and environment expansions in the default system
+/// context.
 ///
-/// This function also performs environment expansion, but it requires context function of type
-/// `FnMut(&str) -> Option<CO>` instead of `FnMut(&str) -> Result<Option<CO>, E>`. This simplifies
-/// the API when you know in advance that the context lookups may not fail.
+/// This function is to `full_braces_with_context()` what `full()` is to `full_with_context()`:
+/// it uses `dirs::home_dir()` and `std::env::var()` as the contexts and also expands `~user`
+/// prefixes.
 ///
-/// Because of the above, instead of `Result<Cow<str>, LookupError<E>>` this function returns just
-/// `Cow<str>`.
+/// # Examples
 ///
-/// Note that if the context function returns `None`, the behavior remains the same as that of
-/// `env_with_context()`: the variable reference will remain in the output string unexpanded.
+/// ```
+/// extern crate dirs_next as dirs;
+/// use std::env;
+///
+/// env::set_var("A", "a value");
+///
+/// let home_dir = dirs::home_dir()
+///     .map(|p| p.display().to_string())
+///     .unwrap_or_else(|| "~".to_owned());
+///
+/// assert_eq!(
+///     shellexpand::full_braces("~/{$A,b}").unwrap(),
+///     vec![format!("{}/a value", home_dir), format!("{}/b", home_dir)]
+/// );
+/// ```
+pub fn full_braces<SI: ?Sized>(input: &SI) -> Result<Vec<String>, ExpandError<VarError>>
+where
+    SI: AsRef<str>,
+{
+    braces(input.as_ref())?
+        .into_iter()
+        .map(|word| full(&word).map(Cow::into_owned))
+        .collect()
+}
+
+/// Performs tilde and environment expansions followed by pathname expansion.
+///
+/// The input is expanded with `full_with_context()` first, and the result is then treated as a
+/// shell pattern: every component of the path which contains `*`, `?` or `[...]` is matched against
+/// the entries of the file system, and the sorted list of existing matching paths is returned.
+/// Files whose names start with a dot are only matched by components which start with a dot
+/// themselves, and a trailing slash, like in `*/`, matches only directories. `home_dir` is matched
+/// literally, even if it contains wildcards, but values of variables are not, as in shells. A
+/// backslash before a wildcard makes it match literally, and is removed from the result. Like in
+/// `full_os()`, `home_dir` and the names of the entries are kept exactly as they are, even if they
+/// are not valid Unicode.
+///
+/// An input without wildcards is returned as the single element of the result, whether such a
+/// path exists or not. If a pattern does not match anything, `no_match` defines what happens:
+/// `NoMatch::Keep` returns the pattern itself, with backslashes removed, and `NoMatch::Fail`
+/// returns an `ExpandError::NoMatch` error.
 ///
 /// # Examples
 ///
 /// ```
-/// fn context(s: &str) -> Option<&'static str> {
-///     match s {
-///         "A" => Some("a value"),
-///         "B" => Some("b value"),
-///         _ => None
+/// use std::fs;
+/// use std::path::{Path, PathBuf};
+/// use shellexpand::{ExpandError, NoMatch, Position};
+///
+/// let home = std::env::temp_dir().join("shellexpand-glob-example");
+/// fs::create_dir_all(home.join(".config/app")).unwrap();
+/// fs::write(home.join(".config/app/a.toml"), "").unwrap();
+/// fs::write(home.join(".config/app/b.toml"), "").unwrap();
+///
+/// let home_dir = || Some(home.clone());
+/// fn get_env(name: &str) -> Result<Option<&'static str>, &'static str> {
+///     match name {
+///         "APP" => Ok(Some("app")),
+///         _ => Ok(None)
 ///     }
 /// }
 ///
-/// // Known variables are expanded
 /// assert_eq!(
-///     shellexpand::env_with_context_no_errors("begin/$A/${B}s/end", context),
-///     "begin/a value/b values/end"
+///     shellexpand::glob_with_context("~/.config/$APP/*.toml", home_dir, get_env, NoMatch::Keep)
+///         .unwrap(),
+///     vec![home.join(".config/app/a.toml"), home.join(".config/app/b.toml")]
 /// );
 ///
-/// // Unknown variables are left as is
 /// assert_eq!(
-///     shellexpand::env_with_context_no_errors("begin/$U/end", context),
-///     "begin/$U/end"
+///     shellexpand::glob_with_context("~/.config/$APP/*.json", home_dir, get_env, NoMatch::Keep)
+///         .unwrap(),
+///     vec![PathBuf::from(format!("{}/.config/app/*.json", home.display()))]
+/// );
+/// assert_eq!(
+///     shellexpand::glob_with_context("~/*.json", home_dir, get_env, NoMatch::Fail),
+///     Err(ExpandError::NoMatch {
+///         pattern: format!("{}/*.json", home.display()),
+///         position: Position::new("~/*.json", 0..8),
+///     })
+/// );
+///
+/// // inputs without wildcards are not checked against the file system
+/// assert_eq!(
+///     shellexpand::glob_with_context("/nonexistent", home_dir, get_env, NoMatch::Fail).unwrap(),
+///     vec![Path::new("/nonexistent")]
 /// );
+/// # fs::remove_dir_all(&home).unwrap();
 /// ```
-#[inline]
-pub fn env_with_context_no_errors<SI: ?Sized, CO, C>(input: &SI, mut context: C) -> Cow<str>
+pub fn glob_with_context<SI: ?Sized, CO, C, E, P, HD>(
+    input: &SI,
+    home_dir: HD,
+    context: C,
+    no_match: NoMatch,
+) -> Result<Vec<PathBuf>, ExpandError<E>>
 where
     SI: AsRef<str>,
     CO: AsRef<str>,
-    C: FnMut(&str) -> Option<CO>,
+    C: Context<Value = CO, Error = E>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
 {
-    match env_with_context(input, move |s| Ok::<Option<CO>, ()>(context(s))) {
-        Ok(value) => value,
-        Err(_) => unreachable!(),
-    }
+    let mut home_dir = home_dir_expander(home_dir);
+    let pattern = Expander::new()
+        .context(context)
+        .home_dir(move |user: &str| {
+            home_dir(user).map(|hd| pattern::escape(&os::encode(hd.as_os_str())))
+        })
+        .expand_mut(input)?;
+    expand_pathnames(input.as_ref(), &pattern, no_match)
 }
 
-/// Performs the environment expansion using the default system context.
-///
-/// This function delegates to `env_with_context()`, using the default system source for
-/// environment variables, namely the `std::env::var()` function.
+/// Performs tilde, environment and pathname expansions in the default system context.
 ///
-/// Note that variable lookup of unknown variables will fail with an error instead of, for example,
-/// replacing the offending variables with an empty string. The author thinks that such behavior is
-/// more useful than the other ones. If you need something else, use `env_with_context()` or
-/// `env_with_context_no_errors()` with an appropriate context function.
+/// This function is to `glob_with_context()` what `full()` is to `full_with_context()`: it uses
+/// `dirs::home_dir()` and `std::env::var()` as the contexts and also expands `~user` prefixes.
 ///
 /// # Examples
 ///
 /// ```
-/// use std::env;
-///
-/// // make sure that some environment variables are set
-/// env::set_var("X", "x value");
-/// env::set_var("Y", "y value");
+/// use std::path::PathBuf;
+/// use shellexpand::NoMatch;
 ///
-/// // Known variables are expanded
-/// assert_eq!(
-///     shellexpand::env("begin/$X/${Y}s/end").unwrap(),
-///     "begin/x value/y values/end"
-/// );
+/// let manifests = shellexpand::glob("Cargo.*", NoMatch::Keep).unwrap();
+/// assert!(manifests.contains(&PathBuf::from("Cargo.toml")));
 ///
-/// // Unknown variables result in an error
 /// assert_eq!(
-///     shellexpand::env("begin/$Z/end"),
-///     Err(shellexpand::LookupError {
-///         var_name: "Z".into(),
-///         cause: env::VarError::NotPresent
-///     })
+///     shellexpand::glob("nonexistent/\\*", NoMatch::Fail).unwrap(),
+///     vec![PathBuf::from("nonexistent/*")]
 /// );
 /// ```
-#[inline]
-pub fn env<SI: ?Sized>(input: &SI) -> Result<Cow<str>, LookupError<VarError>>
+pub fn glob<SI: ?Sized>(
+    input: &SI,
+    no_match: NoMatch,
+) -> Result<Vec<PathBuf>, ExpandError<VarError>>
 where
     SI: AsRef<str>,
 {
-    env_with_context(input, |s| std::env::var(s).map(Some))
+    let pattern = full_with_system_home_dirs(
+        input.as_ref(),
+        |s| std::env::var(s).map(Some),
+        |hd| pattern::escape(&os::encode(hd.as_os_str())),
+    )?;
+    expand_pathnames(input.as_ref(), &pattern, no_match)
 }
 
-/// Performs the tilde expansion using the provided context.
-///
-/// This function expands tilde (`~`) character in the beginning of the input string into contents
-/// of the path returned by `home_dir` function. If the input string does not contain a tilde, or
-/// if it is not followed either by a slash (`/`) or by the end of string, then it is also left as
-/// is. This means, in particular, that expansions like `~anotheruser/directory` are not supported.
-/// The context function may also return a `None`, in that case even if the tilde is present in the
-/// input in the correct place, it won't be replaced (there is nothing to replace it with, after
-/// all).
+/// Matches `pattern`, the result of `full()`-like expansion of `input`, against the file system.
+fn expand_pathnames<E>(
+    input: &str,
+    pattern: &str,
+    no_match: NoMatch,
+) -> Result<Vec<PathBuf>, ExpandError<E>> {
+    let unescaped = || PathBuf::from(os::decode(&pattern::unescape(pattern)).into_owned());
+    match glob::expand(pattern) {
+        Some(ref paths) if paths.is_empty() && no_match == NoMatch::Fail => {
+            Err(ExpandError::NoMatch {
+                pattern: os::decode(pattern).to_string_lossy().into_owned(),
+                position: Position::new(input, 0..input.len()),
+            })
+        }
+        Some(ref paths) if paths.is_empty() => Ok(vec![unescaped()]),
+        Some(paths) => Ok(paths),
+        None => Ok(vec![unescaped()]),
+    }
+}
+
+/// Performs tilde and environment expansions in a command line and splits it into arguments.
 ///
-/// This function has three generic type parameters: `SI` represents the input string, `P` is the
-/// output of a context lookup, and `HD` is the context closure. `SI` must be a type, a reference
-/// to which can be converted to a string slice via `AsRef<str>`, and `P` must be a type, a
-/// reference to which can be converted to a `Path` via `AsRef<Path>`. For example, `P` may be
-/// `Path`, `PathBuf` or `Cow<Path>`, which gives a lot of flexibility.
+/// The input is split into words at unquoted blanks (spaces, tabs and newlines), like a shell
+/// does with a command line, and every word is expanded like with `full_with_context()`, with
+/// quotes and backslash escapes enabled, like in `env_with_context_and_quotes()` and
+/// `env_with_context_and_escapes()`. Then the results of unquoted variable and
+/// `${VAR:-word}`-like expansions are split into separate arguments at the characters of `IFS`,
+/// which is looked up in `context` and defaults to space, tab and newline if it is unknown, and
+/// finally quotes and backslashes which quote the next character are removed. The returned
+/// arguments are ready to be passed to `std::process::Command`. If the lookup of `IFS` fails, its
+/// error is returned as `ExpandError::Lookup`, spanning the whole input.
 ///
-/// If you need to expand the tilde into the actual user home directory, you can use `tilde()` or
-/// `full()` functions.
+/// Because splitting happens after the expansion, a value of a variable is never split at quotes
+/// or backslashes inside it, and a quoted reference, like `"$VAR"`, always stays a single argument.
+/// An unquoted reference to an empty variable produces no argument at all, while `""` produces an
+/// empty one. A tilde prefix is expanded in the beginning of every word, and its expansion is
+/// never split.
 ///
 /// # Examples
 ///
@@ -657,141 +970,2460 @@ where
 ///
 /// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
 ///
+/// fn get_env(name: &str) -> Result<Option<&'static str>, &'static str> {
+///     match name {
+///         "FLAGS" => Ok(Some("-l  -a")),
+///         "FILE" => Ok(Some("My 'Files'")),
+///         "EMPTY" => Ok(Some("")),
+///         _ => Ok(None)
+///     }
+/// }
+///
 /// assert_eq!(
-///    shellexpand::tilde_with_context("~/some/dir", home_dir),
-///    "/home/user/some/dir"
+///     shellexpand::args_with_context("ls $FLAGS \"$FILE\" ~/'a b' $EMPTY \"\"", home_dir, get_env)
+///         .unwrap(),
+///     vec!["ls", "-l", "-a", "My 'Files'", "/home/user/a b", ""]
+/// );
+///
+/// assert_eq!(
+///     shellexpand::args_with_context("echo $FILE ${UNSET:-'x y' z}", home_dir, get_env).unwrap(),
+///     vec!["echo", "My", "'Files'", "x y", "z"]
 /// );
 /// ```
-pub fn tilde_with_context<SI: ?Sized, P, HD>(input: &SI, home_dir: HD) -> Cow<str>
+pub fn args_with_context<SI: ?Sized, CO, C, E, P, HD>(
+    input: &SI,
+    home_dir: HD,
+    context: C,
+) -> Result<Vec<String>, ExpandError<E>>
 where
     SI: AsRef<str>,
+    CO: AsRef<str>,
+    C: Context<Value = CO, Error = E>,
     P: AsRef<Path>,
     HD: FnOnce() -> Option<P>,
 {
-    let input_str = input.as_ref();
-    if input_str.starts_with("~") {
-        let input_after_tilde = &input_str[1..];
-        if input_after_tilde.is_empty() || input_after_tilde.starts_with("/") {
-            if let Some(hd) = home_dir() {
-                let result = format!("{}{}", hd.as_ref().display(), input_after_tilde);
-                result.into()
-            } else {
-                // home dir is not available
-                input_str.into()
-            }
-        } else {
-            // we cannot handle `~otheruser/` paths yet
-            input_str.into()
-        }
-    } else {
-        // input doesn't start with tilde
-        input_str.into()
-    }
+    let mut expander = Expander::new()
+        .context(context)
+        .home_dir(home_dir_expander(home_dir))
+        .escape_style(EscapeStyle::Backslash)
+        .quotes(true);
+    expander::split_args(&mut expander, input.as_ref())
 }
 
-/// Performs the tilde expansion using the default system context.
+/// Performs tilde and environment expansions in a command line and splits it into arguments in
+/// the default system context.
 ///
-/// This function delegates to `tilde_with_context()`, using the default system source of home
-/// directory path, namely `dirs::home_dir()` function.
+/// This function is to `args_with_context()` what `full()` is to `full_with_context()`: it uses
+/// `dirs::home_dir()` and `std::env::var()` as the contexts and also expands `~user` prefixes.
+/// Unknown variables are errors, except for an unset `IFS`, which stands for the default one.
 ///
 /// # Examples
 ///
 /// ```
-/// extern crate dirs_next as dirs;
+/// use std::env;
 ///
-/// let hds = dirs::home_dir()
-///     .map(|p| p.display().to_string())
-///     .unwrap_or_else(|| "~".to_owned());
+/// env::set_var("GREP_PATTERN", "two words");
 ///
 /// assert_eq!(
-///     shellexpand::tilde("~/some/dir"),
-///     format!("{}/some/dir", hds)
+///     shellexpand::args("grep -e \"$GREP_PATTERN\" a\\ b.txt").unwrap(),
+///     vec!["grep", "-e", "two words", "a b.txt"]
 /// );
 /// ```
-#[inline]
-pub fn tilde<SI: ?Sized>(input: &SI) -> Cow<str>
+pub fn args<SI: ?Sized>(input: &SI) -> Result<Vec<String>, ExpandError<VarError>>
 where
     SI: AsRef<str>,
 {
-    tilde_with_context(input, dirs::home_dir)
+    let mut expander = Expander::new()
+        .context(|s: &str| match std::env::var(s) {
+            // an unset `IFS` stands for the default one rather than for an error
+            Err(VarError::NotPresent) if s == "IFS" => Ok(None),
+            value => value.map(Some),
+        })
+        .escape_style(EscapeStyle::Backslash)
+        .quotes(true);
+    expander::split_args(&mut expander, input.as_ref())
 }
 
-#[cfg(test)]
-mod tilde_tests {
-    use std::path::{Path, PathBuf};
+/// Represents a command execution error.
+///
+/// This error is returned, wrapped into `ExpandError::Command`, by the functions which perform
+/// command substitution when the provided executor function returns an error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandError<E> {
+    /// The command which failed, with backslashes of the `` `command` `` form removed.
+    pub command: String,
+    /// The original error returned by the executor function.
+    pub cause: E,
+    /// Where the command substitution is in the input.
+    pub position: Position,
+}
+
+impl<E: fmt::Display> fmt::Display for CommandError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "error executing command '{}': {}",
+            self.command, self.cause
+        )
+    }
+}
 
-    use super::{tilde, tilde_with_context};
+impl<E: Error + 'static> Error for CommandError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.cause)
+    }
+}
 
-    #[test]
-    fn test_with_tilde_no_hd() {
-        fn hd() -> Option<PathBuf> {
-            None
+/// The location of the part of the input which caused an error.
+///
+/// Errors point at the whole reference, like `${VAR:?message}`, or at the whole input if they
+/// are not caused by any particular part of it, like `ExpandError::NoMatch`. An error in the
+/// value of a variable which is expanded recursively points at the reference to that variable.
+/// The functions which transform the input before the expansion, like `full_braces()` and, for
+/// inputs which are not valid Unicode, the `*_os()` ones, report the positions in the
+/// transformed input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Position {
+    /// The byte range in the input.
+    pub span: Range<usize>,
+    /// The line of the beginning of the range, starting from 1.
+    pub line: usize,
+    /// The column of the beginning of the range in characters, starting from 1.
+    pub column: usize,
+}
+
+impl Position {
+    /// Creates the position of the given byte range of `input`, computing its line and column.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use shellexpand::Position;
+    ///
+    /// let position = Position::new("A=1\nB=ü $C", 9..11);
+    /// assert_eq!((position.line, position.column), (2, 5));
+    /// ```
+    pub fn new(input: &str, span: Range<usize>) -> Position {
+        let before = &input[..span.start];
+        let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+        Position {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            span,
         }
+    }
+}
 
-        assert_eq!(tilde_with_context("whatever", hd), "whatever");
-        assert_eq!(tilde_with_context("whatever/~", hd), "whatever/~");
-        assert_eq!(tilde_with_context("~/whatever", hd), "~/whatever");
-        assert_eq!(tilde_with_context("~", hd), "~");
-        assert_eq!(tilde_with_context("~something", hd), "~something");
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
     }
+}
 
-    #[test]
-    fn test_with_tilde() {
-        fn hd() -> Option<PathBuf> {
-            Some(Path::new("/home/dir").into())
-        }
+/// Represents an environment expansion error.
+///
+/// This error is returned by `env_with_context()` and all functions built on top of it. Besides
+/// failed variable lookups, the expansion may fail because of a `${VAR:?message}` or
+/// `${VAR?message}` reference to a variable which is not set, because of an invalid arithmetic
+/// expression, because of a failed command substitution, if the expansion is strict, because of
+/// a reference to an unknown variable or a malformed one or, if it is recursive, because of a
+/// reference cycle. Pathname expansion additionally fails if a pattern does not match anything and
+/// `NoMatch::Fail` is requested.
+///
+/// Every error carries the `Position` of its cause in the input, which `position()` returns
+/// regardless of the variant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExpandError<E> {
+    /// The context function returned an error.
+    Lookup(LookupError<E>),
+    /// A `${VAR:?message}` or `${VAR?message}` expansion was applied to a variable which is not
+    /// set (or, for the form with a colon, is empty).
+    Parameter {
+        /// The name of the variable inside the input string.
+        var_name: String,
+        /// The message after the `?` operator, or a default one if it is empty.
+        message: String,
+        /// Where the reference is in the input.
+        position: Position,
+    },
+    /// The executor function of a command substitution returned an error.
+    Command(CommandError<E>),
+    /// The expression of an arithmetic expansion is invalid or cannot be evaluated.
+    Arithmetic(ArithmeticError),
+    /// A variable is not known to the context, and the expansion is strict; see
+    /// `Expander::strict()`.
+    Unset {
+        /// The name of the variable inside the input string.
+        var_name: String,
+        /// Where the reference is in the input.
+        position: Position,
+    },
+    /// Values of variables refer to each other in a cycle, and the expansion is recursive; see
+    /// `Expander::recursion_limit()`.
+    Cycle {
+        /// The names of the variables in the cycle, starting and ending with the same one, like
+        /// `["A", "B", "A"]` for `A=$B` and `B=$A`.
+        path: Vec<String>,
+        /// Where the reference to the first variable of the cycle is in the input.
+        position: Position,
+    },
+    /// Values of variables are nested deeper than the recursion limit allows; see
+    /// `Expander::recursion_limit()`.
+    RecursionLimit {
+        /// The name of the variable whose value would exceed the limit.
+        var_name: String,
+        /// The recursion limit.
+        limit: usize,
+        /// Where the reference to the outermost variable is in the input.
+        position: Position,
+    },
+    /// A pattern of pathname expansion did not match any path.
+    NoMatch {
+        /// The pattern after tilde and environment expansions, with the parts which are not valid
+        /// Unicode replaced with `U+FFFD`.
+        pattern: String,
+        /// Spans the whole input, which the pattern was expanded from.
+        position: Position,
+    },
+    /// A reference is malformed, and syntax errors are enabled; see `Expander::syntax_errors()`.
+    Syntax(SyntaxError),
+    /// Brace expansion would produce too many words; see `braces()`.
+    Braces(BracesError),
+}
 
-        assert_eq!(tilde_with_context("whatever/path", hd), "whatever/path");
-        assert_eq!(tilde_with_context("whatever/~/path", hd), "whatever/~/path");
-        assert_eq!(tilde_with_context("~", hd), "/home/dir");
-        assert_eq!(tilde_with_context("~/path", hd), "/home/dir/path");
-        assert_eq!(tilde_with_context("~whatever/path", hd), "~whatever/path");
+impl<E> ExpandError<E> {
+    /// Returns the location of the cause of the error in the input.
+    pub fn position(&self) -> &Position {
+        match *self {
+            ExpandError::Lookup(LookupError { ref position, .. })
+            | ExpandError::Parameter { ref position, .. }
+            | ExpandError::Command(CommandError { ref position, .. })
+            | ExpandError::Arithmetic(ArithmeticError { ref position, .. })
+            | ExpandError::Unset { ref position, .. }
+            | ExpandError::Cycle { ref position, .. }
+            | ExpandError::RecursionLimit { ref position, .. }
+            | ExpandError::NoMatch { ref position, .. }
+            | ExpandError::Syntax(SyntaxError { ref position, .. })
+            | ExpandError::Braces(BracesError { ref position, .. }) => position,
+        }
     }
 
-    #[test]
-    fn test_global_tilde() {
-        match dirs::home_dir() {
-            Some(hd) => assert_eq!(tilde("~/something"), format!("{}/something", hd.display())),
-            None => assert_eq!(tilde("~/something"), "~/something"),
+    fn position_mut(&mut self) -> &mut Position {
+        match *self {
+            ExpandError::Lookup(LookupError {
+                ref mut position, ..
+            })
+            | ExpandError::Parameter {
+                ref mut position, ..
+            }
+            | ExpandError::Command(CommandError {
+                ref mut position, ..
+            })
+            | ExpandError::Arithmetic(ArithmeticError {
+                ref mut position, ..
+            })
+            | ExpandError::Unset {
+                ref mut position, ..
+            }
+            | ExpandError::Cycle {
+                ref mut position, ..
+            }
+            | ExpandError::RecursionLimit {
+                ref mut position, ..
+            }
+            | ExpandError::NoMatch {
+                ref mut position, ..
+            }
+            | ExpandError::Syntax(SyntaxError {
+                ref mut position, ..
+            })
+            | ExpandError::Braces(BracesError {
+                ref mut position, ..
+            }) => position,
         }
     }
 }
 
-#[cfg(test)]
-mod env_test {
-    use std;
+impl<E> From<LookupError<E>> for ExpandError<E> {
+    fn from(e: LookupError<E>) -> ExpandError<E> {
+        ExpandError::Lookup(e)
+    }
+}
 
-    use super::{env, env_with_context, LookupError};
+impl<E> From<ArithmeticError> for ExpandError<E> {
+    fn from(e: ArithmeticError) -> ExpandError<E> {
+        ExpandError::Arithmetic(e)
+    }
+}
 
-    macro_rules! table {
-        ($env:expr, unwrap, $($source:expr => $target:expr),+) => {
-            $(
-                assert_eq!(env_with_context($source, $env).unwrap(), $target);
-            )+
-        };
-        ($env:expr, error, $($source:expr => $name:expr),+) => {
-            $(
-                assert_eq!(env_with_context($source, $env), Err(LookupError {
-                    var_name: $name.into(),
-                    cause: ()
-                }));
-            )+
-        }
+impl<E> From<CommandError<E>> for ExpandError<E> {
+    fn from(e: CommandError<E>) -> ExpandError<E> {
+        ExpandError::Command(e)
     }
+}
 
-    #[test]
-    fn test_empty_env() {
-        fn e(_: &str) -> Result<Option<String>, ()> {
-            Ok(None)
-        }
+impl<E> From<SyntaxError> for ExpandError<E> {
+    fn from(e: SyntaxError) -> ExpandError<E> {
+        ExpandError::Syntax(e)
+    }
+}
 
-        table! { e, unwrap,
-            "whatever/path"        => "whatever/path",
-            "$VAR/whatever/path"   => "$VAR/whatever/path",
-            "whatever/$VAR/path"   => "whatever/$VAR/path",
-            "whatever/path/$VAR"   => "whatever/path/$VAR",
-            "${VAR}/whatever/path" => "${VAR}/whatever/path",
-            "whatever/${VAR}path"  => "whatever/${VAR}path",
+impl<E> From<BracesError> for ExpandError<E> {
+    fn from(e: BracesError) -> ExpandError<E> {
+        ExpandError::Braces(e)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for ExpandError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExpandError::Lookup(ref e) => e.fmt(f),
+            ExpandError::Parameter {
+                ref var_name,
+                ref message,
+                ..
+            } => write!(f, "{}: {}", var_name, message),
+            ExpandError::Command(ref e) => e.fmt(f),
+            ExpandError::Arithmetic(ref e) => e.fmt(f),
+            ExpandError::Unset { ref var_name, .. } => {
+                write!(f, "{}: unbound variable", var_name)
+            }
+            ExpandError::Cycle { ref path, .. } => {
+                write!(f, "reference cycle: {}", path.join(" -> "))
+            }
+            ExpandError::RecursionLimit {
+                ref var_name,
+                limit,
+                ..
+            } => write!(f, "{}: recursion limit of {} exceeded", var_name, limit),
+            ExpandError::NoMatch { ref pattern, .. } => {
+                write!(f, "no matches for '{}'", pattern)
+            }
+            ExpandError::Syntax(ref e) => e.fmt(f),
+            ExpandError::Braces(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for ExpandError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ExpandError::Lookup(ref e) => e.source(),
+            ExpandError::Parameter { .. } => None,
+            ExpandError::Command(ref e) => e.source(),
+            ExpandError::Arithmetic(_) => None,
+            ExpandError::Unset { .. }
+            | ExpandError::Cycle { .. }
+            | ExpandError::RecursionLimit { .. }
+            | ExpandError::NoMatch { .. } => None,
+            ExpandError::Syntax(_) | ExpandError::Braces(_) => None,
+        }
+    }
+}
+
+macro_rules! try_lookup {
+    ($name:expr, $position:expr, $e:expr) => {
+        match $e {
+            Ok(s) => s,
+            Err(e) => {
+                return Err(ExpandError::Lookup(LookupError {
+                    var_name: $name.into(),
+                    cause: e,
+                    position: $position,
+                }))
+            }
+        }
+    };
+}
+
+/// Returns the part of `value` selected by `${VAR:offset:length}`.
+///
+/// Offsets and lengths are counted in characters. A negative offset counts from the end of the
+/// value, and a negative length is the number of characters to leave out at the end.
+fn substring(value: &str, offset: isize, length: Option<isize>) -> &str {
+    let char_count = value.chars().count() as isize;
+    let start = if offset < 0 {
+        char_count + offset
+    } else {
+        offset
+    };
+    let end = match length {
+        Some(length) if length < 0 => char_count + length,
+        Some(length) => start.saturating_add(length).min(char_count),
+        None => char_count,
+    };
+    if start < 0 || start >= end {
+        return "";
+    }
+
+    let byte_idx = |n: isize| {
+        value
+            .char_indices()
+            .nth(n as usize)
+            .map_or(value.len(), |(i, _)| i)
+    };
+    &value[byte_idx(start)..byte_idx(end)]
+}
+
+/// Performs the environment expansion using the provided context.
+///
+/// This function walks through the input string `input` and attempts to construct a new string by
+/// replacing all shell-like variable sequences with the corresponding values obtained via the
+/// `context` function. The latter may return an error; in this case the error will be returned
+/// immediately, along with the name of the offending variable. Also the context function may
+/// return `Ok(None)`, indicating that the given variable is not available; in this case the
+/// variable sequence is left as it is in the output string.
+///
+/// The syntax of variables resembles the one of bash-like shells: all of `$VAR`, `${VAR}`,
+/// `$NAME_WITH_UNDERSCORES` are valid variable references, and the form with braces may be used to
+/// separate the reference from the surrounding alphanumeric text: `before${VAR}after`. Note,
+/// however, that for simplicity names like `$123` or `$1AB` are also valid, as opposed to shells
+/// where `$<number>` has special meaning of positional arguments. Also note that "alphanumericity"
+/// of variable names is checked with `char::is_alphanumeric()`, therefore lots of characters which
+/// are considered alphanumeric by the Unicode standard are also valid names for variables. When
+/// unsure, use braces to separate variables from the surrounding text.
+///
+/// This function has four generic type parameters: `SI` represents the input string, `CO` is the
+/// output of context lookups, `C` is the context closure and `E` is the type of errors returned by
+/// the context function. `SI` and `CO` must be types, a references to which can be converted to
+/// a string slice. For example, it is fine for the context function to return `&str`s, `String`s or
+/// `Cow<str>`s, which gives the user a lot of flexibility.
+///
+/// Inside braces, the variable name may be followed by one of the POSIX parameter expansion
+/// operators, which decide what to do with the variable depending on whether it is set:
+///
+/// * `${VAR:-word}` expands to `word` if `VAR` is unset or empty, and `${VAR-word}` does so only
+///   if `VAR` is unset;
+/// * `${VAR:=word}` and `${VAR=word}` work in the same way, but also assign `word` to `VAR`. This
+///   function has nowhere to assign values to, so here they are identical to `:-` and `-`; use
+///   `env_with_context_and_assign()`, or an `Expander` with a context which implements
+///   `Context::assign()`, if you need the assignments;
+/// * `${VAR:?message}` and `${VAR?message}` fail with `ExpandError::Parameter`, carrying the
+///   message, if `VAR` is unset (or empty, for the form with a colon);
+/// * `${VAR:+word}` expands to `word` if `VAR` is set and not empty, and to an empty string
+///   otherwise, while `${VAR+word}` expands to `word` if `VAR` is set at all.
+///
+/// Also the value of a variable may be trimmed with a shell pattern, in which `*` matches any
+/// string, `?` matches any character, `[...]` matches a character from the set and a backslash
+/// makes the next character match literally:
+///
+/// * `${VAR#pattern}` and `${VAR##pattern}` remove the shortest and the longest prefix of the
+///   value matching the pattern, respectively;
+/// * `${VAR%pattern}` and `${VAR%%pattern}` do the same with suffixes;
+/// * `${VAR/pattern/replacement}` replaces the first longest match of the pattern in the value
+///   with the replacement, and `${VAR//pattern/replacement}` replaces all of them;
+/// * `${VAR/#pattern/replacement}` and `${VAR/%pattern/replacement}` replace the pattern only if
+///   it matches a prefix or a suffix of the value, respectively.
+///
+/// Variables inside patterns and replacements are expanded before matching, so it is possible to
+/// write `${PWD#$HOME/}`. A slash inside the pattern of a replacement may be escaped with a
+/// backslash, and if the replacement is omitted, like in `${VAR//pattern}`, matches are deleted.
+/// If the pattern does not match, the value is left intact.
+///
+/// The case of the value may be changed with `${VAR^pattern}` and `${VAR,pattern}`, which convert
+/// the first character of the value to the upper and to the lower case, respectively, if it
+/// matches the pattern, and with `${VAR^^pattern}` and `${VAR,,pattern}`, which convert all
+/// characters matching the pattern. The pattern may be omitted, like in `${VAR^^}`, to convert
+/// all characters regardless of their value. The conversion follows the Unicode case mapping, so
+/// it works for non-ASCII characters too and may even change the length of the value.
+///
+/// Finally, `${VAR:offset}` and `${VAR:offset:length}` expand to a part of the value, and
+/// `${#VAR}` expands to the length of the value. Offsets and lengths are counted in Unicode scalar
+/// values (that is, `char`s), not bytes. A negative offset is counted from the end of the value;
+/// it has to be separated from the colon with a space or put into parentheses, like in `${VAR: -4}`
+/// or `${VAR:(-4)}`, because `${VAR:-4}` means a default value. A negative length is the number of
+/// characters to drop from the end of the value. The offset may only be left out before a length,
+/// like in `${VAR::3}`, so `${VAR:}` is malformed.
+///
+/// Arithmetic expansion, `$((expression))`, is left as it is by this function; see
+/// `env_with_context_and_arithmetic()`.
+///
+/// The word after an operator is expanded too, but only if it is used: it may contain variables,
+/// including braced ones with their own operators, like in `${A:-${B:-fallback}}`, and a closing
+/// brace may be put into it by escaping it with a backslash, like in `${A:-{\}}`. Other
+/// backslashes in the word are kept, but they prevent the next character from starting a variable
+/// reference. The word may also begin with a tilde prefix, which is expanded by `full()` and
+/// `full_with_context()`, but not by this function.
+///
+/// The context function reports unset variables by returning `Ok(None)`, and empty ones by
+/// returning an empty string. For variables with the operators from the first list, errors
+/// returned by the context function are treated as if the variable was unset, so that, for
+/// example, `${VAR:-word}` works as expected with `std::env::var()`, which returns an error for
+/// unset variables.
+///
+/// If the context function returns an error for a variable without an operator, it will be wrapped
+/// into `LookupError` and returned immediately as `ExpandError::Lookup`. `LookupError`, besides
+/// the original error, also contains a string with the name of the variable whose expansion caused
+/// the error. `ExpandError` and `LookupError` implement `Error`, `Clone` and `Eq` traits for
+/// further convenience and interoperability.
+///
+/// If you need to expand system environment variables, you can use `env()` or `full()` functions.
+/// If your context does not have errors, you may use `env_with_context_no_errors()` instead of
+/// this function because it provides a simpler API.
+///
+/// # Examples
+///
+/// ```
+/// fn context(s: &str) -> Result<Option<&'static str>, &'static str> {
+///     match s {
+///         "A" => Ok(Some("a value")),
+///         "B" => Ok(Some("b value")),
+///         "EMPTY" => Ok(Some("")),
+///         "E" => Err("something went wrong"),
+///         _ => Ok(None)
+///     }
+/// }
+///
+/// // Regular variables are expanded
+/// assert_eq!(
+///     shellexpand::env_with_context("begin/$A/${B}s/end", context).unwrap(),
+///     "begin/a value/b values/end"
+/// );
+///
+/// // Expand to a default value if the variable is not defined
+/// assert_eq!(
+///     shellexpand::env_with_context("begin/${UNSET_ENV:-42}/end", context).unwrap(),
+///     "begin/42/end"
+/// );
+///
+/// // Forms without a colon only check whether the variable is set
+/// assert_eq!(
+///     shellexpand::env_with_context("begin/${EMPTY-42}/${EMPTY:+set}/${EMPTY+set}/end", context)
+///         .unwrap(),
+///     "begin///set/end"
+/// );
+///
+/// // Default values are expanded as well
+/// assert_eq!(
+///     shellexpand::env_with_context("begin/${UNSET_ENV:-${UNSET_B:-$A}}/end", context).unwrap(),
+///     "begin/a value/end"
+/// );
+///
+/// // Prefixes and suffixes matching a pattern are removed
+/// assert_eq!(
+///     shellexpand::env_with_context("begin/${A#* }/${B%%[ ]*}/end", context).unwrap(),
+///     "begin/value/b/end"
+/// );
+///
+/// // Matches of a pattern are replaced
+/// assert_eq!(
+///     shellexpand::env_with_context("begin/${A/a/the}/${B// /_}/end", context).unwrap(),
+///     "begin/the value/b_value/end"
+/// );
+///
+/// // Case is converted
+/// assert_eq!(
+///     shellexpand::env_with_context("begin/${A^}/${B^^[a-c]}/end", context).unwrap(),
+///     "begin/A value/B vAlue/end"
+/// );
+///
+/// // Parts and lengths of values are computed
+/// assert_eq!(
+///     shellexpand::env_with_context("begin/${A:2:3}/${B: -5}/${#A}/end", context).unwrap(),
+///     "begin/val/value/7/end"
+/// );
+///
+/// // Unknown variables are left as is
+/// assert_eq!(
+///     shellexpand::env_with_context("begin/$UNKNOWN/end", context).unwrap(),
+///     "begin/$UNKNOWN/end"
+/// );
+///
+/// // Errors are propagated
+/// assert_eq!(
+///     shellexpand::env_with_context("begin${E}end", context),
+///     Err(shellexpand::ExpandError::Lookup(shellexpand::LookupError {
+///         var_name: "E".into(),
+///         cause: "something went wrong",
+///         position: shellexpand::Position::new("begin${E}end", 5..9),
+///     }))
+/// );
+///
+/// // Required variables are checked
+/// assert_eq!(
+///     shellexpand::env_with_context("begin/${UNSET_ENV:?must be set}/end", context),
+///     Err(shellexpand::ExpandError::Parameter {
+///         var_name: "UNSET_ENV".into(),
+///         message: "must be set".into(),
+///         position: shellexpand::Position::new("begin/${UNSET_ENV:?must be set}/end", 6..31),
+///     })
+/// );
+/// ```
+pub fn env_with_context<SI: ?Sized, CO, C, E>(
+    input: &SI,
+    context: C,
+) -> Result<Cow<'_, str>, ExpandError<E>>
+where
+    SI: AsRef<str>,
+    CO: AsRef<str>,
+    C: Context<Value = CO, Error = E>,
+{
+    Expander::new()
+        .context(context)
+        .tilde(false)
+        .expand_mut(input)
+}
+
+/// Same as `env_with_context()`, but also assigns values with the `:=` and `=` operators.
+///
+/// When `${VAR:=word}` or `${VAR=word}` expands to `word`, this function calls `assign` with the
+/// name of the variable and `word`, so that the value can be stored wherever the `context` takes
+/// its values from. Whether later references to the same variable in the input see the assigned
+/// value is up to the `context`, therefore it usually makes sense for both closures to share their
+/// storage. Otherwise this function is identical to `env_with_context()`.
+///
+/// An `Expander` passes assignments to `Context::assign()` of its context instead, which also
+/// receives the assignments of arithmetic expansions, like `$((N += 1))`, if they are enabled.
+///
+/// # Examples
+///
+/// ```
+/// use std::cell::RefCell;
+/// use std::collections::HashMap;
+///
+/// let vars = RefCell::new(HashMap::new());
+/// vars.borrow_mut().insert("A".to_owned(), "a value".to_owned());
+///
+/// let result = shellexpand::env_with_context_and_assign(
+///     "${A:=a default} ${B:=b default} $B",
+///     |s: &str| Ok::<_, ()>(vars.borrow().get(s).cloned()),
+///     |s, v| { vars.borrow_mut().insert(s.to_owned(), v.to_owned()); }
+/// );
+///
+/// assert_eq!(result.unwrap(), "a value b default b default");
+/// assert_eq!(vars.borrow()["B"], "b default");
+/// ```
+pub fn env_with_context_and_assign<SI: ?Sized, CO, C, E, A>(
+    input: &SI,
+    context: C,
+    assign: A,
+) -> Result<Cow<'_, str>, ExpandError<E>>
+where
+    SI: AsRef<str>,
+    CO: AsRef<str>,
+    C: Context<Value = CO, Error = E>,
+    A: FnMut(&str, &str),
+{
+    Expander::new()
+        .context(Assigning { context, assign })
+        .tilde(false)
+        .expand_mut(input)
+}
+
+/// A context which passes assignments to a closure; see `env_with_context_and_assign()`.
+struct Assigning<C, A> {
+    context: C,
+    assign: A,
+}
+
+impl<C, A> Context for Assigning<C, A>
+where
+    C: Context,
+    A: FnMut(&str, &str),
+{
+    type Value = C::Value;
+    type Error = C::Error;
+
+    fn lookup(&mut self, name: &str) -> Result<Option<C::Value>, C::Error> {
+        self.context.lookup(name)
+    }
+
+    fn assign(&mut self, name: &str, value: &str) {
+        (self.assign)(name, value)
+    }
+}
+
+/// Same as `env_with_context()`, but also supports backslash escapes.
+///
+/// `env_with_context()` only treats `$$` as an escape sequence for a dollar sign, and `\}` as one
+/// for a closing brace inside words after operators. This function, in addition, treats a
+/// backslash followed by `$`, `{`, `}`, `~` or another backslash as an escape sequence for the
+/// second character, anywhere in the input. So `\$HOME` expands to `$HOME`, and `\\` expands to a
+/// single backslash. A backslash followed by any other character, as well as a backslash at the
+/// end of the input, is left as it is, like in double quotes in POSIX shells: `\n` stays `\n`.
+///
+/// # Examples
+///
+/// ```
+/// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+///     match s {
+///         "A" => Ok(Some("a value")),
+///         _ => Ok(None)
+///     }
+/// }
+///
+/// assert_eq!(
+///     shellexpand::env_with_context_and_escapes("$A \\$A \\\\$A $$A", context).unwrap(),
+///     "a value $A \\a value $A"
+/// );
+/// assert_eq!(
+///     shellexpand::env_with_context_and_escapes("\\${A} ${X:-\\$A} \\n", context).unwrap(),
+///     "${A} $A \\n"
+/// );
+/// ```
+pub fn env_with_context_and_escapes<SI: ?Sized, CO, C, E>(
+    input: &SI,
+    context: C,
+) -> Result<Cow<'_, str>, ExpandError<E>>
+where
+    SI: AsRef<str>,
+    CO: AsRef<str>,
+    C: Context<Value = CO, Error = E>,
+{
+    Expander::new()
+        .context(context)
+        .tilde(false)
+        .escape_style(EscapeStyle::Backslash)
+        .expand_mut(input)
+}
+
+/// Same as `env_with_context()`, but also recognizes quotes, like POSIX shells do.
+///
+/// Text inside single quotes, like `'$A'`, is never expanded. Inside double quotes, like in
+/// `"$A"`, variables are expanded, but single quotes are regular characters there and tilde
+/// prefixes are not recognized. A backslash outside single quotes prevents the next character,
+/// including a quote, from having its special meaning. A quote without a matching closing quote
+/// is a regular character.
+///
+/// The quotes themselves are kept in the output, so that it can still be split into words or
+/// passed to a shell, with one exception: quotes inside a pattern, like in `${VAR%'*'}`, make the
+/// quoted characters match literally and are removed.
+///
+/// # Examples
+///
+/// ```
+/// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+///     match s {
+///         "A" => Ok(Some("a value")),
+///         "STAR" => Ok(Some("a*")),
+///         _ => Ok(None)
+///     }
+/// }
+///
+/// assert_eq!(
+///     shellexpand::env_with_context_and_quotes("$A '$A' \"$A '$A'\"", context).unwrap(),
+///     "a value '$A' \"a value 'a value'\""
+/// );
+/// assert_eq!(
+///     shellexpand::env_with_context_and_quotes("${STAR%'*'} ${STAR%*}", context).unwrap(),
+///     "a a*"
+/// );
+/// ```
+pub fn env_with_context_and_quotes<SI: ?Sized, CO, C, E>(
+    input: &SI,
+    context: C,
+) -> Result<Cow<'_, str>, ExpandError<E>>
+where
+    SI: AsRef<str>,
+    CO: AsRef<str>,
+    C: Context<Value = CO, Error = E>,
+{
+    Expander::new()
+        .context(context)
+        .tilde(false)
+        .quotes(true)
+        .expand_mut(input)
+}
+
+/// Same as `env_with_context()`, but also evaluates arithmetic expansions.
+///
+/// Arithmetic expansion, `$((expression))`, is replaced with the value of the expression, which
+/// follows the POSIX shell rules: it consists of 64-bit signed integers, variables, parentheses
+/// and the C operators, including comparisons, logical and bitwise operators, the ternary
+/// operator and assignments, like in `$((A += 1))`, which are performed like with `${VAR:=word}`.
+/// Variables may be written either by their names, like in `$((BASE_PORT + SHARD))`, in which
+/// case unset and empty variables are zeros (unless the expansion is strict, see
+/// `Expander::strict()`), or with `$`, in which case they are expanded before evaluation.
+/// Invalid expressions, overflows and divisions by zero cause `ExpandError::Arithmetic` errors.
+///
+/// Other functions leave `$((expression))` as it is; an `Expander` evaluates it if enabled with
+/// `Expander::arithmetic()`, and passes the assignments to `Context::assign()` of its context.
+///
+/// # Examples
+///
+/// ```
+/// fn context(s: &str) -> Result<Option<&'static str>, ()> {
+///     match s {
+///         "A" => Ok(Some("a value")),
+///         "BASE_PORT" => Ok(Some("8000")),
+///         _ => Ok(None)
+///     }
+/// }
+///
+/// assert_eq!(
+///     shellexpand::env_with_context_and_arithmetic("$((${#A} * 2 + 1))", context).unwrap(),
+///     "15"
+/// );
+/// assert_eq!(
+///     shellexpand::env_with_context_and_arithmetic("$A:$((BASE_PORT + 1))", context).unwrap(),
+///     "a value:8001"
+/// );
+/// assert_eq!(
+///     shellexpand::env_with_context("$((BASE_PORT + 1))", context).unwrap(),
+///     "$((BASE_PORT + 1))"
+/// );
+/// ```
+pub fn env_with_context_and_arithmetic<SI: ?Sized, CO, C, E>(
+    input: &SI,
+    context: C,
+) -> Result<Cow<'_, str>, ExpandError<E>>
+where
+    SI: AsRef<str>,
+    CO: AsRef<str>,
+    C: Context<Value = CO, Error = E>,
+{
+    Expander::new()
+        .context(context)
+        .tilde(false)
+        .arithmetic(true)
+        .expand_mut(input)
+}
+
+/// Same as `env_with_context()`, but also performs command substitution.
+///
+/// Command substitutions, `$(command)` and `` `command` ``, are replaced with the output of
+/// `command`, with trailing newlines removed. This crate does not run any commands by itself:
+/// the command is passed to the `execute` function, which may run it in a shell, look it up in
+/// some table or do whatever else is appropriate. An error returned by `execute` is wrapped into
+/// `CommandError` and returned as `ExpandError::Command`.
+///
+/// The command extends up to the matching parenthesis or backquote: parentheses inside
+/// `$(command)` may be nested, and quoted parentheses and characters after a backslash are
+/// skipped, so `$(echo "(" \) $(echo ')'))` is a single substitution. In `` `command` ``,
+/// backquotes may be escaped with a backslash, and backslashes before `$`, `` ` `` and another
+/// backslash are removed from the command before it is passed to `execute`. Commands are not
+/// expanded, so variables inside them are left for `execute` to deal with. A substitution without
+/// a closing parenthesis or backquote is left as it is.
+///
+/// Since running arbitrary commands from the input is dangerous, command substitution is only
+/// performed by this function, `full_with_context_and_commands()` and expanders with
+/// `Expander::commands()` enabled; all other functions leave `$(command)` and `` `command` `` as
+/// they are.
+///
+/// # Examples
+///
+/// ```
+/// fn context(s: &str) -> Result<Option<&'static str>, &'static str> {
+///     match s {
+///         "A" => Ok(Some("a value")),
+///         _ => Ok(None)
+///     }
+/// }
+///
+/// fn execute(command: &str) -> Result<String, &'static str> {
+///     match command {
+///         "date +%Y" => Ok("2021\n".into()),
+///         "echo $(date +%Y)" => Ok("year 2021\n".into()),
+///         _ => Err("unknown command")
+///     }
+/// }
+///
+/// assert_eq!(
+///     shellexpand::env_with_context_and_commands("$A/$(date +%Y)/`date +%Y`", context, execute)
+///         .unwrap(),
+///     "a value/2021/2021"
+/// );
+/// assert_eq!(
+///     shellexpand::env_with_context_and_commands("$(echo $(date +%Y))", context, execute)
+///         .unwrap(),
+///     "year 2021"
+/// );
+/// assert_eq!(
+///     shellexpand::env_with_context_and_commands("$(false)", context, execute),
+///     Err(shellexpand::ExpandError::Command(shellexpand::CommandError {
+///         command: "false".into(),
+///         cause: "unknown command",
+///         position: shellexpand::Position::new("$(false)", 0..8),
+///     }))
+/// );
+/// ```
+pub fn env_with_context_and_commands<SI: ?Sized, CO, C, E, X>(
+    input: &SI,
+    context: C,
+    execute: X,
+) -> Result<Cow<'_, str>, ExpandError<E>>
+where
+    SI: AsRef<str>,
+    CO: AsRef<str>,
+    C: Context<Value = CO, Error = E>,
+    X: FnMut(&str) -> Result<String, E>,
+{
+    Expander::new()
+        .context(Executing { context, execute })
+        .tilde(false)
+        .commands(true)
+        .expand_mut(input)
+}
+
+/// A context which runs commands with a closure; see `env_with_context_and_commands()`.
+struct Executing<C, X> {
+    context: C,
+    execute: X,
+}
+
+impl<C, X> Context for Executing<C, X>
+where
+    C: Context,
+    X: FnMut(&str) -> Result<String, C::Error>,
+{
+    type Value = C::Value;
+    type Error = C::Error;
+
+    fn lookup(&mut self, name: &str) -> Result<Option<C::Value>, C::Error> {
+        self.context.lookup(name)
+    }
+
+    fn assign(&mut self, name: &str, value: &str) {
+        self.context.assign(name, value)
+    }
+
+    fn execute(&mut self, command: &str) -> Result<Option<String>, C::Error> {
+        (self.execute)(command).map(Some)
+    }
+}
+
+/// Performs the environment expansion with the syntax of `cmd.exe` using the provided context.
+///
+/// This function works like `env_with_context()` and accepts the same context functions, but it
+/// recognizes the syntax of Windows batch files instead of the shell one: variables are written
+/// like `%VAR%`, and `%%` stands for a single `%`. Nothing else is special, so `$VAR` and `~` are
+/// left as they are. The name of a variable may be followed by one of the modifiers of `cmd.exe`:
+///
+/// * `%VAR:~start,length%` expands to a part of the value, like `${VAR:offset:length}` does.
+///   Both numbers may be negative, in which case they are counted from the end of the value, and
+///   `,length` may be omitted;
+/// * `%VAR:old=new%` replaces all occurrences of `old` in the value with `new`, and
+///   `%VAR:*old=new%` replaces everything up to the end of the first occurrence of `old`. `old`
+///   is matched case-insensitively, as in `cmd.exe`.
+///
+/// Positions and lengths are counted in characters. References to unknown variables and
+/// references with invalid modifiers are left as they are, and so is a `%` without a closing one.
+/// Variable names are passed to the context function exactly as they are written, so it is up to
+/// it to ignore their case, like Windows does.
+///
+/// # Examples
+///
+/// ```
+/// fn context(s: &str) -> Result<Option<&'static str>, &'static str> {
+///     match s {
+///         "DATE" => Ok(Some("2021-07-15")),
+///         "PATH" => Ok(Some(r"C:\Windows;C:\Tools")),
+///         "E" => Err("something went wrong"),
+///         _ => Ok(None)
+///     }
+/// }
+///
+/// assert_eq!(
+///     shellexpand::env_cmd_with_context("backup-%DATE:~0,4%%DATE:~5,2%.zip", context).unwrap(),
+///     "backup-202107.zip"
+/// );
+/// assert_eq!(
+///     shellexpand::env_cmd_with_context("%PATH:;=, %", context).unwrap(),
+///     r"C:\Windows, C:\Tools"
+/// );
+/// assert_eq!(
+///     shellexpand::env_cmd_with_context("100%% %UNKNOWN% $DATE", context).unwrap(),
+///     "100% %UNKNOWN% $DATE"
+/// );
+///
+/// assert_eq!(
+///     shellexpand::env_cmd_with_context("%E%", context),
+///     Err(shellexpand::ExpandError::Lookup(shellexpand::LookupError {
+///         var_name: "E".into(),
+///         cause: "something went wrong",
+///         position: shellexpand::Position::new("%E%", 0..3),
+///     }))
+/// );
+/// ```
+pub fn env_cmd_with_context<SI: ?Sized, CO, C, E>(
+    input: &SI,
+    context: C,
+) -> Result<Cow<'_, str>, ExpandError<E>>
+where
+    SI: AsRef<str>,
+    CO: AsRef<str>,
+    C: Context<Value = CO, Error = E>,
+{
+    Expander::new()
+        .context(context)
+        .dialect(Dialect::Cmd)
+        .expand_mut(input)
+}
+
+/// Performs the environment expansion with the syntax of `cmd.exe` using the default system
+/// context.
+///
+/// This function is to `env_cmd_with_context()` what `env()` is to `env_with_context()`: it
+/// looks variables up with `std::env::var()`, so unknown variables cause errors.
+///
+/// # Examples
+///
+/// ```
+/// use std::env;
+///
+/// env::set_var("X", "x value");
+///
+/// assert_eq!(shellexpand::env_cmd("%X:~0,1%%%").unwrap(), "x%");
+/// ```
+#[inline]
+pub fn env_cmd<SI: ?Sized>(input: &SI) -> Result<Cow<'_, str>, ExpandError<VarError>>
+where
+    SI: AsRef<str>,
+{
+    Expander::new()
+        .context(|s: &str| std::env::var(s).map(Some))
+        .dialect(Dialect::Cmd)
+        .expand(input)
+}
+
+/// Returns `true` if `input` contains anything which may be expanded with the given syntax.
+fn has_expansions(input: &str, syntax: Syntax) -> bool {
+    input.contains('$')
+        || (syntax.backslash_escapes && input.contains('\\'))
+        || (syntax.commands && input.contains('`'))
+}
+
+/// The state of an environment expansion.
+///
+/// The closures are borrowed, so that values of variables can be expanded by nested expansions
+/// which share them.
+struct Expansion<'a, 'c, C, A, T, X, E> {
+    input: &'a str,
+    syntax: Syntax,
+    context: &'c mut C,
+    assign: &'c mut A,
+    tilde: &'c mut T,
+    execute: &'c mut X,
+    options: Options,
+    /// The names of the variables whose values are being expanded, outermost first.
+    path: Vec<String>,
+    /// Where errors are collected if the expansion goes on after them.
+    recovery: Option<Recovery<'c, E>>,
+}
+
+/// The errors of an expansion which goes on after them, and what the failed parts of the input
+/// are replaced with.
+struct Recovery<'r, E> {
+    errors: &'r mut Vec<ExpandError<E>>,
+    /// The replacement of the failed parts, or `None` if they are left as they are.
+    placeholder: Option<&'r str>,
+}
+
+impl<'r, E> Recovery<'r, E> {
+    /// Records `error` and pushes the replacement of the failed part of the input, `original`,
+    /// to `result`.
+    fn recover(&mut self, error: ExpandError<E>, original: &str, result: &mut String) {
+        result.push_str(self.placeholder.unwrap_or(original));
+        self.errors.push(error);
+    }
+}
+
+/// Settings of an environment expansion which are not closures.
+#[derive(Debug, Clone, Copy)]
+struct Options {
+    /// Whether unknown variables cause `ExpandError::Unset` errors instead of being left as they
+    /// are.
+    strict: bool,
+    /// Whether malformed references cause `ExpandError::Syntax` errors instead of being left as
+    /// they are.
+    syntax_errors: bool,
+    /// The operators which are expanded; references with other operators are left as they are.
+    operators: Operators,
+    /// How many levels of values of variables are expanded themselves; zero means that values
+    /// are inserted as they are.
+    recursion_limit: usize,
+}
+
+impl<'a, 'c, CO, C, E, A, T, X> Expansion<'a, 'c, C, A, T, X, E>
+where
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+    A: FnMut(&str, &str),
+    T: FnMut(&str) -> Option<String>,
+    X: FnMut(&str) -> Result<Option<String>, E>,
+{
+    fn expand(&mut self) -> Result<Cow<'a, str>, ExpandError<E>> {
+        if !has_expansions(self.input, self.syntax) {
+            return Ok(self.input.into());
+        }
+        let mut result = String::with_capacity(self.input.len());
+        self.expand_segments(
+            &parse::parse_without_tilde(self.input, self.syntax),
+            &mut result,
+        )?;
+        Ok(result.into())
+    }
+
+    /// Expands the value of a variable if the expansion is recursive, and returns it as it is
+    /// otherwise.
+    ///
+    /// The value is expanded with the same closures and settings as the input, so its own
+    /// variables are expanded recursively, until the values do not contain anything to expand.
+    /// Errors in the value are reported at `span`, the reference to the variable in the input.
+    fn resolve<'v>(
+        &mut self,
+        var_name: &str,
+        value: &'v str,
+        span: &Range<usize>,
+    ) -> Result<Cow<'v, str>, ExpandError<E>> {
+        if self.options.recursion_limit == 0 || !has_expansions(value, self.syntax) {
+            return Ok(value.into());
+        }
+        if let Some(idx) = self.path.iter().position(|name| name == var_name) {
+            let mut path = self.path[idx..].to_vec();
+            path.push(var_name.into());
+            return Err(ExpandError::Cycle {
+                path,
+                position: self.position(span),
+            });
+        }
+        if self.path.len() >= self.options.recursion_limit {
+            return Err(ExpandError::RecursionLimit {
+                var_name: var_name.into(),
+                limit: self.options.recursion_limit,
+                position: self.position(span),
+            });
+        }
+
+        let mut path = self.path.clone();
+        path.push(var_name.into());
+        Expansion {
+            input: value,
+            syntax: self.syntax,
+            context: &mut *self.context,
+            assign: &mut *self.assign,
+            tilde: &mut *self.tilde,
+            execute: &mut *self.execute,
+            options: self.options,
+            path,
+            recovery: None,
+        }
+        .expand()
+        .map_err(|mut e| {
+            *e.position_mut() = self.position(span);
+            e
+        })
+    }
+
+    fn position(&self, span: &Range<usize>) -> Position {
+        Position::new(self.input, span.clone())
+    }
+
+    /// Expands `segments` into `result`.
+    ///
+    /// If the expansion recovers from errors, a segment which fails is replaced with the
+    /// placeholder or left as it is, and the expansion goes on with the next one.
+    fn expand_segments(
+        &mut self,
+        segments: &[Segment<'a>],
+        result: &mut String,
+    ) -> Result<(), ExpandError<E>> {
+        for segment in segments {
+            let len = result.len();
+            if let Err(error) = self.expand_segment(segment, result) {
+                match self.recovery {
+                    Some(ref mut recovery) => {
+                        result.truncate(len);
+                        recovery.recover(error, &self.input[segment.span()], result);
+                    }
+                    None => return Err(error),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn expand_segment(
+        &mut self,
+        segment: &Segment<'a>,
+        result: &mut String,
+    ) -> Result<(), ExpandError<E>> {
+        match *segment {
+            Segment::Literal { text, .. } | Segment::Escape { text, .. } => result.push_str(text),
+            Segment::Variable { name, ref span } => {
+                match try_lookup!(name, self.position(span), (self.context)(name)) {
+                    Some(var_value) => {
+                        result.push_str(&self.resolve(name, var_value.as_ref(), span)?)
+                    }
+                    None if self.options.strict => return Err(unset(name, self.position(span))),
+                    None => result.push_str(&self.input[span.clone()]),
+                }
+            }
+            Segment::Braced {
+                modifier: Some(ref modifier),
+                ref span,
+                ..
+            } if !is_allowed(modifier.operator, self.options.operators) => {
+                result.push_str(&self.input[span.clone()])
+            }
+            Segment::Braced {
+                name,
+                ref modifier,
+                ref span,
+            } => {
+                if !self.expand_braced(name, modifier.as_ref(), span, result)? {
+                    // leave the variable as it is if the environment is empty
+                    result.push_str(&self.input[span.clone()]);
+                }
+            }
+            Segment::Command {
+                command,
+                backquoted,
+                ref span,
+            } => {
+                let command = if backquoted {
+                    remove_backslashes(command, "$`\\")
+                } else {
+                    command.into()
+                };
+                let output = match (self.execute)(&command) {
+                    Ok(Some(output)) => output,
+                    Ok(None) => {
+                        result.push_str(&self.input[span.clone()]);
+                        return Ok(());
+                    }
+                    Err(cause) => {
+                        return Err(ExpandError::Command(CommandError {
+                            command: command.into_owned(),
+                            cause,
+                            position: self.position(span),
+                        }))
+                    }
+                };
+                // like shells do, trailing newlines of the output are removed
+                result.push_str(output.trim_end_matches('\n'));
+            }
+            Segment::Arithmetic {
+                ref expression,
+                ref span,
+            } => result.push_str(&self.evaluate(expression, span)?.to_string()),
+            Segment::SingleQuoted { ref span, .. } => result.push_str(&self.input[span.clone()]),
+            Segment::DoubleQuoted { ref segments, .. } => {
+                result.push('"');
+                self.expand_segments(segments, result)?;
+                result.push('"');
+            }
+            Segment::Tilde { user, ref span } => match (self.tilde)(user) {
+                Some(home_dir) => result.push_str(&home_dir),
+                None => result.push_str(&self.input[span.clone()]),
+            },
+            Segment::Invalid { ref kind, ref span } => {
+                if self.options.syntax_errors {
+                    return Err(ExpandError::Syntax(SyntaxError {
+                        kind: kind.clone(),
+                        position: self.position(span),
+                    }));
+                }
+                result.push_str(&self.input[span.clone()]);
+            }
+        }
+        Ok(())
+    }
+
+    fn expand_word(&mut self, word: &Word<'a>) -> Result<String, ExpandError<E>> {
+        let mut result = String::with_capacity(word.span.len());
+        self.expand_segments(&word.segments, &mut result)?;
+        Ok(result)
+    }
+
+    /// Expands the segments of a command line into `fields`, splitting unquoted expansion results.
+    ///
+    /// At the top level, blanks in the literal text separate fields and a tilde prefix is expanded
+    /// in the beginning of every field, while inside the words of braced references the literal
+    /// text is split like expansion results. Quotes, and backslashes quoting the next character,
+    /// are removed in both cases.
+    fn split_segments(
+        &mut self,
+        segments: &[Segment<'a>],
+        fields: &mut Fields,
+        in_word: bool,
+    ) -> Result<(), ExpandError<E>> {
+        for (idx, segment) in segments.iter().enumerate() {
+            match *segment {
+                Segment::Literal { text, .. } => {
+                    let starts_field = !in_word && idx == 0;
+                    let is_last = idx + 1 == segments.len();
+                    self.split_literal(text, fields, in_word, starts_field, is_last);
+                }
+                Segment::Escape { text, .. } | Segment::SingleQuoted { text, .. } => {
+                    fields.push_quoted(text)
+                }
+                Segment::DoubleQuoted { ref segments, .. } => {
+                    let mut text = String::new();
+                    for segment in segments {
+                        match *segment {
+                            Segment::Literal { text: literal, .. } => {
+                                text.push_str(&remove_backslashes(literal, "\"`\\"))
+                            }
+                            _ => self.expand_segments(slice::from_ref(segment), &mut text)?,
+                        }
+                    }
+                    fields.push_quoted(&text);
+                }
+                Segment::Braced {
+                    name,
+                    modifier: Some(ref modifier),
+                    ref span,
+                } if modifier.operator == Operator::Default
+                    || modifier.operator == Operator::Alternative =>
+                {
+                    // the variable is looked up once, both to choose and to expand the reference
+                    let var_value = (self.context)(name);
+                    if expands_to_word(&var_value, modifier) {
+                        self.split_segments(&modifier.word.segments, fields, true)?
+                    } else {
+                        let mut text = String::new();
+                        if !self.expand_braced_value(
+                            name,
+                            var_value,
+                            Some(modifier),
+                            span,
+                            &mut text,
+                        )? {
+                            text.push_str(&self.input[span.clone()]);
+                        }
+                        fields.push_split(&text);
+                    }
+                }
+                _ => {
+                    let mut text = String::new();
+                    self.expand_segments(slice::from_ref(segment), &mut text)?;
+                    // the results of tilde expansion are never split
+                    if let Segment::Tilde { .. } = *segment {
+                        fields.push_quoted(&text);
+                    } else {
+                        fields.push_split(&text);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Splits a literal part of a command line into `fields`; see `split_segments()`.
+    ///
+    /// `starts_field` tells whether the text is at the beginning of the input, and `is_last`
+    /// whether nothing follows it, so that a tilde prefix may end with the text.
+    fn split_literal(
+        &mut self,
+        text: &str,
+        fields: &mut Fields,
+        in_word: bool,
+        mut starts_field: bool,
+        is_last: bool,
+    ) {
+        let mut pos = 0;
+        while pos < text.len() {
+            let rest = &text[pos..];
+            let mut chars = rest.chars();
+            let c = chars.next().unwrap();
+            match (c, chars.next()) {
+                ('\\', Some(next_char)) => {
+                    fields.push_quoted(&rest[1..1 + next_char.len_utf8()]);
+                    pos += 1 + next_char.len_utf8();
+                }
+                (c, _) if in_word => {
+                    fields.push_split(&rest[..c.len_utf8()]);
+                    pos += c.len_utf8();
+                }
+                (c, _) if fields::is_blank(c) => {
+                    fields.delimit();
+                    pos += 1;
+                    starts_field = true;
+                    continue;
+                }
+                ('~', _) if starts_field => {
+                    let prefix_end = rest.find(|c| c == '/' || fields::is_blank(c));
+                    let tilde_prefix = match prefix_end {
+                        Some(prefix_end) => &rest[..prefix_end],
+                        None if is_last => rest,
+                        // the prefix continues with an expansion or a quoted string
+                        None => "~",
+                    };
+                    let user = &tilde_prefix[1..];
+                    let home_dir = if prefix_end.is_some() || is_last {
+                        (self.tilde)(user)
+                    } else {
+                        None
+                    };
+                    fields.push_quoted(home_dir.as_ref().map_or(tilde_prefix, |hd| hd));
+                    pos += tilde_prefix.len();
+                }
+                (c, _) => {
+                    fields.push_quoted(&rest[..c.len_utf8()]);
+                    pos += c.len_utf8();
+                }
+            }
+            starts_field = false;
+        }
+    }
+
+    /// Evaluates the expression of `$((expression))`.
+    fn evaluate(
+        &mut self,
+        expression: &Word<'a>,
+        span: &Range<usize>,
+    ) -> Result<i64, ExpandError<E>> {
+        let expression = self.expand_word(expression)?;
+        let position = self.position(span);
+        // the lookups may expand values recursively, which needs the whole expansion, so the
+        // assignments are only performed after the evaluation, and only if it succeeds
+        let mut assignments = Vec::new();
+        let evaluation = arith::evaluate(
+            &expression,
+            |var_name: &str| match (self.context)(var_name) {
+                Ok(Some(var_value)) => Ok(Some(
+                    self.resolve(var_name, var_value.as_ref(), span)?
+                        .into_owned(),
+                )),
+                Ok(None) if self.options.strict => Err(unset(var_name, position.clone())),
+                Ok(None) => Ok(None),
+                Err(cause) => Err(ExpandError::Lookup(LookupError {
+                    var_name: var_name.into(),
+                    cause,
+                    position: position.clone(),
+                })),
+            },
+            |var_name: &str, value: &str| assignments.push((var_name.to_owned(), value.to_owned())),
+        );
+        let value = match evaluation {
+            Ok(value) => value,
+            Err(arith::EvalError::Lookup(e)) => return Err(e),
+            Err(arith::EvalError::Arithmetic(kind)) => {
+                return Err(ExpandError::Arithmetic(ArithmeticError {
+                    expression,
+                    kind,
+                    position,
+                }))
+            }
+        };
+        for (var_name, value) in &assignments {
+            (self.assign)(var_name, value);
+        }
+        Ok(value)
+    }
+
+    /// Expands a word which is used as a pattern.
+    ///
+    /// Quoted parts of the word match themselves literally, so their special characters are
+    /// escaped and the quotes are removed.
+    fn expand_pattern(&mut self, word: &Word<'a>) -> Result<String, ExpandError<E>> {
+        let mut result = String::with_capacity(word.span.len());
+        for segment in &word.segments {
+            match *segment {
+                Segment::SingleQuoted { text, .. } => result.push_str(&pattern::escape(text)),
+                Segment::DoubleQuoted { ref segments, .. } => {
+                    let mut text = String::new();
+                    self.expand_segments(segments, &mut text)?;
+                    result.push_str(&pattern::escape(&text));
+                }
+                _ => self.expand_segments(slice::from_ref(segment), &mut result)?,
+            }
+        }
+        Ok(result)
+    }
+
+    /// Expands a braced reference into `result`.
+    ///
+    /// Returns `false` if nothing was pushed to `result` because the variable is unknown, in
+    /// which case the whole reference should be left as it is.
+    fn expand_braced(
+        &mut self,
+        var_name: &str,
+        modifier: Option<&Modifier<'a>>,
+        span: &Range<usize>,
+        result: &mut String,
+    ) -> Result<bool, ExpandError<E>> {
+        let var_value = (self.context)(var_name);
+        self.expand_braced_value(var_name, var_value, modifier, span, result)
+    }
+
+    /// Same as `expand_braced()`, but with the result of the lookup of the variable.
+    fn expand_braced_value(
+        &mut self,
+        var_name: &str,
+        var_value: Result<Option<CO>, E>,
+        modifier: Option<&Modifier<'a>>,
+        span: &Range<usize>,
+        result: &mut String,
+    ) -> Result<bool, ExpandError<E>> {
+        let modifier = match modifier {
+            Some(modifier) => modifier,
+            None => {
+                return match try_lookup!(var_name, self.position(span), var_value) {
+                    Some(var_value) => {
+                        result.push_str(&self.resolve(var_name, var_value.as_ref(), span)?);
+                        Ok(true)
+                    }
+                    None if self.options.strict => Err(unset(var_name, self.position(span))),
+                    None => Ok(false),
+                }
+            }
+        };
+        let operator = modifier.operator;
+
+        if operator.checks_if_set() {
+            // lookup errors are treated as unset variables here
+            let var_value = var_value.ok().and_then(|v| v);
+            let var_value = match var_value {
+                Some(ref v) => Some(self.resolve(var_name, v.as_ref(), span)?),
+                None => None,
+            };
+            let is_set = match var_value {
+                Some(ref v) => !modifier.colon || !v.as_ref().is_empty(),
+                None => false,
+            };
+
+            match (operator, var_value) {
+                (Operator::Alternative, _) if is_set => {
+                    result.push_str(&self.expand_word(&modifier.word)?)
+                }
+                (Operator::Alternative, _) => {}
+                (_, Some(ref v)) if is_set => result.push_str(v.as_ref()),
+                (Operator::Assign, _) => {
+                    let word = self.expand_word(&modifier.word)?;
+                    (self.assign)(var_name, &word);
+                    result.push_str(&word);
+                }
+                (Operator::Error, _) => {
+                    let message = match (self.expand_word(&modifier.word)?, modifier.colon) {
+                        (ref word, true) if word.is_empty() => "parameter null or not set".into(),
+                        (ref word, false) if word.is_empty() => "parameter not set".into(),
+                        (word, _) => word,
+                    };
+                    return Err(ExpandError::Parameter {
+                        var_name: var_name.into(),
+                        message,
+                        position: self.position(span),
+                    });
+                }
+                _ => result.push_str(&self.expand_word(&modifier.word)?),
+            }
+            return Ok(true);
+        }
+
+        let var_value = match try_lookup!(var_name, self.position(span), var_value) {
+            Some(var_value) => var_value,
+            None if self.options.strict => return Err(unset(var_name, self.position(span))),
+            None => return Ok(false),
+        };
+        let var_value = self.resolve(var_name, var_value.as_ref(), span)?;
+        let var_value = &*var_value;
+
+        match operator {
+            Operator::RemoveSmallestPrefix | Operator::RemoveLargestPrefix => {
+                let pattern = Pattern::new(&self.expand_pattern(&modifier.word)?);
+                let longest = operator == Operator::RemoveLargestPrefix;
+                let start = pattern.match_prefix(var_value, longest).unwrap_or(0);
+                result.push_str(&var_value[start..]);
+            }
+            Operator::RemoveSmallestSuffix | Operator::RemoveLargestSuffix => {
+                let pattern = Pattern::new(&self.expand_pattern(&modifier.word)?);
+                let longest = operator == Operator::RemoveLargestSuffix;
+                let end = pattern
+                    .match_suffix(var_value, longest)
+                    .unwrap_or(var_value.len());
+                result.push_str(&var_value[..end]);
+            }
+            Operator::Substring { offset, length } => {
+                result.push_str(substring(var_value, offset, length));
+            }
+            Operator::Length => {
+                result.push_str(&var_value.chars().count().to_string());
+            }
+            Operator::UpperFirst
+            | Operator::UpperAll
+            | Operator::LowerFirst
+            | Operator::LowerAll => {
+                // an empty pattern matches every character
+                let pattern = match self.expand_pattern(&modifier.word)? {
+                    ref pattern if pattern.is_empty() => Pattern::new("?"),
+                    pattern => Pattern::new(&pattern),
+                };
+                let upper = operator == Operator::UpperFirst || operator == Operator::UpperAll;
+                let all = operator == Operator::UpperAll || operator == Operator::LowerAll;
+                convert_case(var_value, &pattern, upper, all, result);
+            }
+            _ => {
+                let pattern = Pattern::new(&self.expand_pattern(&modifier.word)?);
+                let replacement = match modifier.replacement {
+                    Some(ref replacement) => self.expand_word(replacement)?,
+                    None => String::new(),
+                };
+                replace_matches(var_value, &pattern, &replacement, operator, result);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Returns the error for an unknown variable in strict mode.
+fn unset<E>(var_name: &str, position: Position) -> ExpandError<E> {
+    ExpandError::Unset {
+        var_name: var_name.into(),
+        position,
+    }
+}
+
+/// Returns `true` if `${VAR-word}` or `${VAR+word}`, with or without a colon, expands to its
+/// word, given the result of the lookup of `VAR`.
+fn expands_to_word<CO, E>(var_value: &Result<Option<CO>, E>, modifier: &Modifier<'_>) -> bool
+where
+    CO: AsRef<str>,
+{
+    let is_set = match *var_value {
+        Ok(Some(ref var_value)) => !modifier.colon || !var_value.as_ref().is_empty(),
+        _ => false,
+    };
+    match modifier.operator {
+        Operator::Default => !is_set,
+        Operator::Alternative => is_set,
+        _ => false,
+    }
+}
+
+/// Returns `true` if `operator` belongs to one of the groups allowed by `operators`.
+fn is_allowed(operator: Operator, operators: Operators) -> bool {
+    match operator {
+        Operator::Default | Operator::Assign | Operator::Error | Operator::Alternative => {
+            operators.defaults
+        }
+        Operator::RemoveSmallestPrefix
+        | Operator::RemoveLargestPrefix
+        | Operator::RemoveSmallestSuffix
+        | Operator::RemoveLargestSuffix
+        | Operator::ReplaceFirst
+        | Operator::ReplaceAll
+        | Operator::ReplacePrefix
+        | Operator::ReplaceSuffix => operators.patterns,
+        Operator::Substring { .. } | Operator::Length => operators.substrings,
+        Operator::UpperFirst | Operator::UpperAll | Operator::LowerFirst | Operator::LowerAll => {
+            operators.case
+        }
+    }
+}
+
+/// Removes backslashes before the `special_chars`, like the ones before `$`, `` ` `` and `\\` in
+/// the command of `` `command` `` or before `"` in double quotes.
+fn remove_backslashes<'t>(text: &'t str, special_chars: &str) -> Cow<'t, str> {
+    if !text.contains('\\') {
+        return text.into();
+    }
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(&next_char)) if special_chars.contains(next_char) => {
+                result.push(next_char);
+                chars.next();
+            }
+            (c, _) => result.push(c),
+        }
+    }
+    result.into()
+}
+
+/// Pushes `value` with characters matching `pattern` converted to the upper or lower case.
+///
+/// If `all` is false, only the first character of the value is converted, provided it matches.
+fn convert_case(value: &str, pattern: &Pattern, upper: bool, all: bool, result: &mut String) {
+    for (i, c) in value.char_indices() {
+        if !all && i > 0 {
+            result.push_str(&value[i..]);
+            break;
+        }
+        if !pattern.matches_char(c) {
+            result.push(c);
+        } else if upper {
+            result.extend(c.to_uppercase());
+        } else {
+            result.extend(c.to_lowercase());
+        }
+    }
+}
+
+/// Pushes `value` with matches of `pattern` replaced according to `operator` to `result`.
+///
+/// The longest match is replaced at each position. Anchored replacements may replace an empty
+/// match, so that `${VAR/#/prefix}` prepends a prefix to the value.
+fn replace_matches(
+    value: &str,
+    pattern: &Pattern,
+    replacement: &str,
+    operator: Operator,
+    result: &mut String,
+) {
+    match operator {
+        Operator::ReplacePrefix => match pattern.match_prefix(value, true) {
+            Some(end) => {
+                result.push_str(replacement);
+                result.push_str(&value[end..]);
+            }
+            None => result.push_str(value),
+        },
+        Operator::ReplaceSuffix => match pattern.match_suffix(value, true) {
+            Some(start) => {
+                result.push_str(&value[..start]);
+                result.push_str(replacement);
+            }
+            None => result.push_str(value),
+        },
+        _ => {
+            let limit = if operator == Operator::ReplaceFirst {
+                1
+            } else {
+                usize::MAX
+            };
+            let mut from = 0;
+            for (start, end) in pattern.find_iter(value).take(limit) {
+                result.push_str(&value[from..start]);
+                result.push_str(replacement);
+                from = end;
+            }
+            result.push_str(&value[from..]);
+        }
+    }
+}
+
+/// Same as `env_with_context()`, but forbids the variable lookup function to return errors.
+///
+/// This function also performs environment expansion, but it requires context function of type
+/// `FnMut(&str) -> Option<CO>` instead of `FnMut(&str) -> Result<Option<CO>, E>`. This simplifies
+/// the API when you know in advance that the context lookups may not fail.
+///
+/// Because of the above, instead of `Result<Cow<str>, ExpandError<E>>` this function returns just
+/// `Cow<str>`.
+///
+/// Note that if the context function returns `None`, the behavior remains the same as that of
+/// `env_with_context()`: the variable reference will remain in the output string unexpanded.
+/// The same happens to `${VAR:?message}` references to unset variables, which make
+/// `env_with_context()` fail.
+///
+/// # Examples
+///
+/// ```
+/// fn context(s: &str) -> Option<&'static str> {
+///     match s {
+///         "A" => Some("a value"),
+///         "B" => Some("b value"),
+///         _ => None
+///     }
+/// }
+///
+/// // Known variables are expanded
+/// assert_eq!(
+///     shellexpand::env_with_context_no_errors("begin/$A/${B}s/end", context),
+///     "begin/a value/b values/end"
+/// );
+///
+/// // Unknown variables are left as is
+/// assert_eq!(
+///     shellexpand::env_with_context_no_errors("begin/$U/end", context),
+///     "begin/$U/end"
+/// );
+/// ```
+#[inline]
+pub fn env_with_context_no_errors<SI: ?Sized, CO, C>(input: &SI, mut context: C) -> Cow<'_, str>
+where
+    SI: AsRef<str>,
+    CO: AsRef<str>,
+    C: FnMut(&str) -> Option<CO>,
+{
+    // failed `${VAR:?message}` expansions, the only possible errors, are left as they are
+    Expander::new()
+        .context(move |s: &str| Ok::<Option<CO>, ()>(context(s)))
+        .tilde(false)
+        .expand_all_mut(input, None)
+        .0
+}
+
+/// Performs the environment expansion using the default system context.
+///
+/// This function delegates to `env_with_context()`, using the default system source for
+/// environment variables, namely the `std::env::var()` function.
+///
+/// Note that variable lookup of unknown variables will fail with an error instead of, for example,
+/// replacing the offending variables with an empty string. The author thinks that such behavior is
+/// more useful than the other ones. If you need something else, use `env_with_context()` or
+/// `env_with_context_no_errors()` with an appropriate context function.
+///
+/// # Examples
+///
+/// ```
+/// use std::env;
+///
+/// // make sure that some environment variables are set
+/// env::set_var("X", "x value");
+/// env::set_var("Y", "y value");
+///
+/// // Known variables are expanded
+/// assert_eq!(
+///     shellexpand::env("begin/$X/${Y}s/end").unwrap(),
+///     "begin/x value/y values/end"
+/// );
+///
+/// // Unknown variables result in an error
+/// assert_eq!(
+///     shellexpand::env("begin/$Z/end"),
+///     Err(shellexpand::ExpandError::Lookup(shellexpand::LookupError {
+///         var_name: "Z".into(),
+///         cause: env::VarError::NotPresent,
+///         position: shellexpand::Position::new("begin/$Z/end", 6..8),
+///     }))
+/// );
+/// ```
+#[inline]
+pub fn env<SI: ?Sized>(input: &SI) -> Result<Cow<'_, str>, ExpandError<VarError>>
+where
+    SI: AsRef<str>,
+{
+    Expander::new()
+        .context(|s: &str| std::env::var(s).map(Some))
+        .tilde(false)
+        .expand(input)
+}
+
+/// Performs the tilde expansion using the provided context.
+///
+/// This function expands tilde (`~`) character in the beginning of the input string into contents
+/// of the path returned by `home_dir` function. If the input string does not contain a tilde, or
+/// if it is not followed either by a slash (`/`) or by the end of string, then it is also left as
+/// is. This means, in particular, that expansions like `~anotheruser/directory` are not handled by
+/// this function; use `tilde_with_user_context()` if you need them.
+/// The context function may also return a `None`, in that case even if the tilde is present in the
+/// input in the correct place, it won't be replaced (there is nothing to replace it with, after
+/// all).
+///
+/// This function has three generic type parameters: `SI` represents the input string, `P` is the
+/// output of a context lookup, and `HD` is the context closure. `SI` must be a type, a reference
+/// to which can be converted to a string slice via `AsRef<str>`, and `P` must be a type, a
+/// reference to which can be converted to a `Path` via `AsRef<Path>`. For example, `P` may be
+/// `Path`, `PathBuf` or `Cow<Path>`, which gives a lot of flexibility.
+///
+/// If you need to expand the tilde into the actual user home directory, you can use `tilde()` or
+/// `full()` functions.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{PathBuf, Path};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+///
+/// assert_eq!(
+///    shellexpand::tilde_with_context("~/some/dir", home_dir),
+///    "/home/user/some/dir"
+/// );
+/// ```
+pub fn tilde_with_context<SI: ?Sized, P, HD>(input: &SI, home_dir: HD) -> Cow<'_, str>
+where
+    SI: AsRef<str>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    let input_str = input.as_ref();
+    match split_tilde_prefix(input_str) {
+        Some(("", input_after_tilde)) => match home_dir() {
+            Some(hd) => format!("{}{}", hd.as_ref().display(), input_after_tilde).into(),
+            // home dir is not available
+            None => input_str.into(),
+        },
+        // `~otheruser/` paths are handled by `tilde_with_user_context()`
+        Some(_) => input_str.into(),
+        // input doesn't start with tilde
+        None => input_str.into(),
+    }
+}
+
+/// Performs the tilde expansion, including `~user` prefixes, using the provided contexts.
+///
+/// This function works like `tilde_with_context()`, but it also expands a tilde followed by a
+/// user name, like in `~user/directory` or just `~user`, into the home directory of that user.
+/// The user name is everything between the tilde and the first slash (or the end of string), and
+/// its home directory is obtained by calling `user_home_dir` with it. `home_dir` is still used
+/// for the plain `~` and `~/` prefixes, in exactly the same way as in `tilde_with_context()`.
+///
+/// Unlike the missing home directory of the current user, a user which is unknown to
+/// `user_home_dir` is reported as an `UnknownUserError` carrying the name of the user. Use
+/// `user_home_dir()` as the context if you need to look users up in the system user database.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{PathBuf, Path};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+///
+/// fn user_home_dir(name: &str) -> Option<PathBuf> {
+///     match name {
+///         "deploy" => Some(Path::new("/srv/deploy").into()),
+///         _ => None
+///     }
+/// }
+///
+/// // Known users are expanded into their home directories
+/// assert_eq!(
+///     shellexpand::tilde_with_user_context("~deploy/releases", home_dir, user_home_dir).unwrap(),
+///     "/srv/deploy/releases"
+/// );
+///
+/// // The current user is still handled with the `home_dir` context
+/// assert_eq!(
+///     shellexpand::tilde_with_user_context("~/some/dir", home_dir, user_home_dir).unwrap(),
+///     "/home/user/some/dir"
+/// );
+///
+/// // Unknown users cause an error
+/// assert_eq!(
+///     shellexpand::tilde_with_user_context("~nobody/dir", home_dir, user_home_dir),
+///     Err(shellexpand::UnknownUserError {
+///         user_name: "nobody".into()
+///     })
+/// );
+/// ```
+pub fn tilde_with_user_context<SI: ?Sized, P, HD, UP, UHD>(
+    input: &SI,
+    home_dir: HD,
+    user_home_dir: UHD,
+) -> Result<Cow<'_, str>, UnknownUserError>
+where
+    SI: AsRef<str>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+    UP: AsRef<Path>,
+    UHD: FnOnce(&str) -> Option<UP>,
+{
+    let input_str = input.as_ref();
+    match split_tilde_prefix(input_str) {
+        Some(("", _)) => Ok(tilde_with_context(input_str, home_dir)),
+        Some((user_name, input_after_user)) => match user_home_dir(user_name) {
+            Some(hd) => Ok(format!("{}{}", hd.as_ref().display(), input_after_user).into()),
+            None => Err(UnknownUserError {
+                user_name: user_name.into(),
+            }),
+        },
+        // input doesn't start with tilde
+        None => Ok(input_str.into()),
+    }
+}
+
+/// Splits a tilde prefix off the input string.
+///
+/// Returns the user name after the tilde, which is empty for the current user, and the rest of
+/// the string starting with the first slash, or `None` if the input does not start with a tilde.
+fn split_tilde_prefix(input: &str) -> Option<(&str, &str)> {
+    let input_after_tilde = input.strip_prefix('~')?;
+    let user_name_end_idx = input_after_tilde
+        .find('/')
+        .unwrap_or(input_after_tilde.len());
+    Some(input_after_tilde.split_at(user_name_end_idx))
+}
+
+/// Represents a failure to expand a `~user` prefix.
+///
+/// This error is returned by `tilde_with_user_context()` when its user home directory context
+/// does not know the user named after the tilde.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownUserError {
+    /// The name of the unknown user inside the input string.
+    pub user_name: String,
+}
+
+impl fmt::Display for UnknownUserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown user '{}'", self.user_name)
+    }
+}
+
+impl Error for UnknownUserError {}
+
+/// Performs the tilde expansion using the default system context.
+///
+/// This function delegates to `tilde_with_user_context()`, using the default system sources of
+/// home directory paths, namely `dirs::home_dir()` function for the current user and
+/// `user_home_dir()` for `~user` prefixes. If the user is unknown, the input is left as is, just
+/// like shells do; call `tilde_with_user_context()` directly if you need to detect this case.
+///
+/// # Examples
+///
+/// ```
+/// extern crate dirs_next as dirs;
+///
+/// let hds = dirs::home_dir()
+///     .map(|p| p.display().to_string())
+///     .unwrap_or_else(|| "~".to_owned());
+///
+/// assert_eq!(
+///     shellexpand::tilde("~/some/dir"),
+///     format!("{}/some/dir", hds)
+/// );
+///
+/// assert_eq!(
+///     shellexpand::tilde("~surely-there-is-no-such-user/dir"),
+///     "~surely-there-is-no-such-user/dir"
+/// );
+/// ```
+#[inline]
+pub fn tilde<SI: ?Sized>(input: &SI) -> Cow<'_, str>
+where
+    SI: AsRef<str>,
+{
+    tilde_with_user_context(input, dirs::home_dir, user_home_dir)
+        .unwrap_or_else(|_| input.as_ref().into())
+}
+
+/// Same as `tilde_with_context()`, but works with `OsStr`s instead of strings.
+///
+/// The home directory is inserted exactly as it is, so it does not have to be valid Unicode. On
+/// Unix, the input does not have to be valid UTF-8 either, and the bytes after the tilde prefix are
+/// copied to the result as they are. On other platforms, invalid Unicode in the input and in the
+/// home directory is replaced with `U+FFFD`. The input may be a `Path` as well, since it
+/// implements `AsRef<OsStr>`.
+///
+/// # Examples
+///
+/// ```
+/// use std::ffi::OsStr;
+/// use std::path::{PathBuf, Path};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+///
+/// assert_eq!(
+///     shellexpand::tilde_os_with_context(Path::new("~/some/dir"), home_dir),
+///     OsStr::new("/home/user/some/dir")
+/// );
+///
+/// #[cfg(unix)]
+/// {
+///     use std::os::unix::ffi::OsStrExt;
+///
+///     let home_dir = || Some(OsStr::from_bytes(b"/home/\xff"));
+///     assert_eq!(
+///         shellexpand::tilde_os_with_context(OsStr::from_bytes(b"~/\xfe"), home_dir),
+///         OsStr::from_bytes(b"/home/\xff/\xfe")
+///     );
+/// }
+/// ```
+pub fn tilde_os_with_context<SI: ?Sized, P, HD>(input: &SI, home_dir: HD) -> Cow<'_, OsStr>
+where
+    SI: AsRef<OsStr>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    let input = input.as_ref();
+    let encoded_input = os::encode(input);
+    let result = tilde_with_context(&*encoded_input, || {
+        home_dir().map(|hd| os::encode(hd.as_ref().as_os_str()).into_owned())
+    });
+    decode_result(input, result)
+}
+
+/// Same as `tilde()`, but works with `OsStr`s instead of strings.
+///
+/// See `tilde_os_with_context()` for the details of how `OsStr`s are handled.
+///
+/// # Examples
+///
+/// ```
+/// extern crate dirs_next as dirs;
+/// use std::path::Path;
+///
+/// let expected = match dirs::home_dir() {
+///     Some(hd) => hd.join("some/dir"),
+///     None => "~/some/dir".into(),
+/// };
+/// assert_eq!(shellexpand::tilde_os(Path::new("~/some/dir")), expected.as_os_str());
+/// ```
+#[inline]
+pub fn tilde_os<SI: ?Sized>(input: &SI) -> Cow<'_, OsStr>
+where
+    SI: AsRef<OsStr>,
+{
+    let input = input.as_ref();
+    let encoded_input = os::encode(input);
+    let result = tilde_with_user_context(
+        &*encoded_input,
+        || dirs::home_dir().map(|hd| os::encode(hd.as_os_str()).into_owned()),
+        |user| user_home_dir(user).map(|hd| os::encode(hd.as_os_str()).into_owned()),
+    )
+    .unwrap_or_else(|_| Cow::Borrowed(&*encoded_input));
+    decode_result(input, result)
+}
+
+/// Same as `tilde_os()`, but works with `Path`s.
+///
+/// # Examples
+///
+/// ```
+/// extern crate dirs_next as dirs;
+/// use std::path::Path;
+///
+/// let expected = match dirs::home_dir() {
+///     Some(hd) => hd.join("some/dir"),
+///     None => "~/some/dir".into(),
+/// };
+/// assert_eq!(shellexpand::tilde_path("~/some/dir"), expected);
+/// ```
+#[inline]
+pub fn tilde_path<P: ?Sized>(input: &P) -> Cow<'_, Path>
+where
+    P: AsRef<Path>,
+{
+    path_from_os(tilde_os(input.as_ref()))
+}
+
+/// Same as `env_with_context()`, but works with `OsStr`s instead of strings.
+///
+/// The context function returns values which may be converted to `OsStr`s, like `OsString`s
+/// returned by `std::env::var_os()`, and they are inserted exactly as they are, so they do not
+/// have to be valid Unicode. On Unix, the input does not have to be valid UTF-8 either: its
+/// valid parts are expanded as usual, and the rest of the bytes are copied to the result as they
+/// are. Variable names, though, are always valid Unicode. Patterns, like in `${VAR#pattern}`,
+/// match invalid bytes one by one, and lengths and offsets, like in `${#VAR}`, count every
+/// invalid byte as a character. On other platforms, invalid Unicode in the input and in the
+/// values is replaced with `U+FFFD`.
+///
+/// # Examples
+///
+/// ```
+/// use std::ffi::{OsStr, OsString};
+///
+/// fn context(s: &str) -> Result<Option<OsString>, ()> {
+///     match s {
+///         "A" => Ok(Some("a value".into())),
+///         _ => Ok(None),
+///     }
+/// }
+///
+/// assert_eq!(
+///     shellexpand::env_os_with_context(OsStr::new("$A/${B:-b}"), context).unwrap(),
+///     OsStr::new("a value/b")
+/// );
+///
+/// #[cfg(unix)]
+/// {
+///     use std::os::unix::ffi::{OsStrExt, OsStringExt};
+///
+///     let context = |_: &str| -> Result<_, ()> { Ok(Some(OsString::from_vec(b"\xff".to_vec()))) };
+///     assert_eq!(
+///         shellexpand::env_os_with_context(OsStr::from_bytes(b"\xfe/$A"), context).unwrap(),
+///         OsStr::from_bytes(b"\xfe/\xff")
+///     );
+/// }
+/// ```
+pub fn env_os_with_context<SI: ?Sized, CO, C, E>(
+    input: &SI,
+    mut context: C,
+) -> Result<Cow<'_, OsStr>, ExpandError<E>>
+where
+    SI: AsRef<OsStr>,
+    CO: AsRef<OsStr>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+{
+    let input = input.as_ref();
+    let encoded_input = os::encode(input);
+    let result = env_with_context(&*encoded_input, |var_name: &str| {
+        context(var_name).map(|var_value| var_value.map(|v| os::encode(v.as_ref()).into_owned()))
+    })?;
+    Ok(decode_result(input, result))
+}
+
+/// Same as `env()`, but works with `OsStr`s instead of strings.
+///
+/// Variables are looked up with `std::env::var_os()`, so their values do not have to be valid
+/// Unicode. Unset variables are still reported as `VarError::NotPresent` errors, like with
+/// `env()`. See `env_os_with_context()` for the details of how `OsStr`s are handled.
+///
+/// # Examples
+///
+/// ```
+/// use std::env;
+/// use std::ffi::OsStr;
+///
+/// env::set_var("X", "x value");
+///
+/// assert_eq!(shellexpand::env_os(OsStr::new("$X/end")).unwrap(), OsStr::new("x value/end"));
+/// ```
+#[inline]
+pub fn env_os<SI: ?Sized>(input: &SI) -> Result<Cow<'_, OsStr>, ExpandError<VarError>>
+where
+    SI: AsRef<OsStr>,
+{
+    env_os_with_context(input, |s| {
+        std::env::var_os(s).map(Some).ok_or(VarError::NotPresent)
+    })
+}
+
+/// Same as `full_with_context()`, but works with `OsStr`s instead of strings.
+///
+/// See `tilde_os_with_context()` and `env_os_with_context()` for the details of how `OsStr`s are
+/// handled.
+///
+/// # Examples
+///
+/// ```
+/// use std::ffi::{OsStr, OsString};
+/// use std::path::{PathBuf, Path};
+///
+/// fn home_dir() -> Option<PathBuf> { Some(Path::new("/home/user").into()) }
+///
+/// fn context(s: &str) -> Result<Option<OsString>, ()> {
+///     match s {
+///         "A" => Ok(Some("a value".into())),
+///         _ => Ok(None),
+///     }
+/// }
+///
+/// assert_eq!(
+///     shellexpand::full_os_with_context(Path::new("~/$A"), home_dir, context).unwrap(),
+///     OsStr::new("/home/user/a value")
+/// );
+/// ```
+pub fn full_os_with_context<SI: ?Sized, CO, C, E, P, HD>(
+    input: &SI,
+    home_dir: HD,
+    mut context: C,
+) -> Result<Cow<'_, OsStr>, ExpandError<E>>
+where
+    SI: AsRef<OsStr>,
+    CO: AsRef<OsStr>,
+    C: FnMut(&str) -> Result<Option<CO>, E>,
+    P: AsRef<Path>,
+    HD: FnOnce() -> Option<P>,
+{
+    let input = input.as_ref();
+    let encoded_input = os::encode(input);
+    let result = full_with_context(
+        &*encoded_input,
+        || home_dir().map(|hd| os::encode(hd.as_ref().as_os_str()).into_owned()),
+        |var_name: &str| {
+            context(var_name)
+                .map(|var_value| var_value.map(|v| os::encode(v.as_ref()).into_owned()))
+        },
+    )?;
+    Ok(decode_result(input, result))
+}
+
+/// Same as `full()`, but works with `OsStr`s instead of strings.
+///
+/// Variables are looked up with `std::env::var_os()`, like in `env_os()`, and home directories
+/// are inserted exactly as they are. See `full_os_with_context()` for the details of how
+/// `OsStr`s are handled.
+///
+/// # Examples
+///
+/// ```
+/// use std::env;
+/// use std::ffi::OsStr;
+///
+/// env::set_var("A", "a value");
+///
+/// assert_eq!(shellexpand::full_os(OsStr::new("$A/end")).unwrap(), OsStr::new("a value/end"));
+/// ```
+pub fn full_os<SI: ?Sized>(input: &SI) -> Result<Cow<'_, OsStr>, ExpandError<VarError>>
+where
+    SI: AsRef<OsStr>,
+{
+    let input = input.as_ref();
+    let encoded_input = os::encode(input);
+    let result = full_with_system_home_dirs(
+        &encoded_input,
+        |s| match std::env::var_os(s) {
+            Some(var_value) => Ok(Some(os::encode(&var_value).into_owned())),
+            None => Err(VarError::NotPresent),
+        },
+        |hd| os::encode(hd.as_os_str()).into_owned(),
+    )?;
+    Ok(decode_result(input, result))
+}
+
+/// Same as `full_os()`, but works with `Path`s.
+///
+/// # Examples
+///
+/// ```
+/// use std::env;
+/// use std::path::Path;
+///
+/// env::set_var("A", "a value");
+///
+/// assert_eq!(shellexpand::full_path("/$A/end").unwrap(), Path::new("/a value/end"));
+/// ```
+#[inline]
+pub fn full_path<P: ?Sized>(input: &P) -> Result<Cow<'_, Path>, ExpandError<VarError>>
+where
+    P: AsRef<Path>,
+{
+    full_os(input.as_ref()).map(path_from_os)
+}
+
+/// Converts the result of an expansion of `os::encode(input)` back to an `OsStr`.
+///
+/// The expansion functions only borrow their input if they did not change it, so in this case
+/// `input` itself is returned.
+fn decode_result<'a>(input: &'a OsStr, result: Cow<'_, str>) -> Cow<'a, OsStr> {
+    match result {
+        Cow::Borrowed(_) => input.into(),
+        Cow::Owned(result) => os::decode(&result).into_owned().into(),
+    }
+}
+
+fn path_from_os(s: Cow<'_, OsStr>) -> Cow<'_, Path> {
+    match s {
+        Cow::Borrowed(s) => Path::new(s).into(),
+        Cow::Owned(s) => PathBuf::from(s).into(),
+    }
+}
+
+#[cfg(test)]
+mod tilde_tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{
+        tilde, tilde_with_context, tilde_with_user_context, user_home_dir, UnknownUserError,
+    };
+
+    #[test]
+    fn test_with_tilde_no_hd() {
+        fn hd() -> Option<PathBuf> {
+            None
+        }
+
+        assert_eq!(tilde_with_context("whatever", hd), "whatever");
+        assert_eq!(tilde_with_context("whatever/~", hd), "whatever/~");
+        assert_eq!(tilde_with_context("~/whatever", hd), "~/whatever");
+        assert_eq!(tilde_with_context("~", hd), "~");
+        assert_eq!(tilde_with_context("~something", hd), "~something");
+    }
+
+    #[test]
+    fn test_with_tilde() {
+        fn hd() -> Option<PathBuf> {
+            Some(Path::new("/home/dir").into())
+        }
+
+        assert_eq!(tilde_with_context("whatever/path", hd), "whatever/path");
+        assert_eq!(tilde_with_context("whatever/~/path", hd), "whatever/~/path");
+        assert_eq!(tilde_with_context("~", hd), "/home/dir");
+        assert_eq!(tilde_with_context("~/path", hd), "/home/dir/path");
+        assert_eq!(tilde_with_context("~whatever/path", hd), "~whatever/path");
+    }
+
+    #[test]
+    fn test_with_user_tilde() {
+        fn hd() -> Option<PathBuf> {
+            Some(Path::new("/home/dir").into())
+        }
+        fn uhd(name: &str) -> Option<PathBuf> {
+            match name {
+                "user" => Some(Path::new("/home/user").into()),
+                "other.user" => Some(Path::new("/other").into()),
+                _ => None,
+            }
+        }
+
+        assert_eq!(
+            tilde_with_user_context("whatever/~user/path", hd, uhd).unwrap(),
+            "whatever/~user/path"
+        );
+        assert_eq!(tilde_with_user_context("~", hd, uhd).unwrap(), "/home/dir");
+        assert_eq!(
+            tilde_with_user_context("~/path", hd, uhd).unwrap(),
+            "/home/dir/path"
+        );
+        assert_eq!(
+            tilde_with_user_context("~user", hd, uhd).unwrap(),
+            "/home/user"
+        );
+        assert_eq!(
+            tilde_with_user_context("~user/", hd, uhd).unwrap(),
+            "/home/user/"
+        );
+        assert_eq!(
+            tilde_with_user_context("~other.user/path/~user", hd, uhd).unwrap(),
+            "/other/path/~user"
+        );
+        assert_eq!(
+            tilde_with_user_context("~unknown/path", hd, uhd),
+            Err(UnknownUserError {
+                user_name: "unknown".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_global_user_tilde() {
+        assert_eq!(
+            tilde("~surely-there-is-no-such-user/something"),
+            "~surely-there-is-no-such-user/something"
+        );
+        if let Some(hd) = user_home_dir("root") {
+            assert_eq!(
+                tilde("~root/something"),
+                format!("{}/something", hd.display())
+            );
+        }
+    }
+
+    #[test]
+    fn test_global_tilde() {
+        match dirs::home_dir() {
+            Some(hd) => assert_eq!(tilde("~/something"), format!("{}/something", hd.display())),
+            None => assert_eq!(tilde("~/something"), "~/something"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod env_test {
+    use std;
+
+    use super::{
+        env, env_with_context, env_with_context_and_arithmetic, env_with_context_and_commands,
+        env_with_context_and_escapes, env_with_context_and_quotes, env_with_context_no_errors,
+        ArithmeticError, ArithmeticErrorKind, CommandError, ExpandError, Expander, LookupError,
+        Position, SyntaxError, SyntaxErrorKind,
+    };
+
+    macro_rules! table {
+        ($env:expr, unwrap, $($source:expr => $target:expr),+) => {
+            $(
+                assert_eq!(env_with_context($source, $env).unwrap(), $target);
+            )+
+        };
+        ($env:expr, error, $($source:expr => ($name:expr, $span:expr)),+) => {
+            $(
+                assert_eq!(env_with_context($source, $env), Err(ExpandError::Lookup(LookupError {
+                    var_name: $name.into(),
+                    cause: (),
+                    position: Position::new($source, $span)
+                })));
+            )+
+        };
+        ($env:expr, required, $($source:expr => ($name:expr, $message:expr, $span:expr)),+) => {
+            $(
+                assert_eq!(env_with_context($source, $env), Err(ExpandError::Parameter {
+                    var_name: $name.into(),
+                    message: $message.into(),
+                    position: Position::new($source, $span)
+                }));
+            )+
+        }
+    }
+
+    #[test]
+    fn test_empty_env() {
+        fn e(_: &str) -> Result<Option<String>, ()> {
+            Ok(None)
+        }
+
+        table! { e, unwrap,
+            "whatever/path"        => "whatever/path",
+            "$VAR/whatever/path"   => "$VAR/whatever/path",
+            "whatever/$VAR/path"   => "whatever/$VAR/path",
+            "whatever/path/$VAR"   => "whatever/path/$VAR",
+            "${VAR}/whatever/path" => "${VAR}/whatever/path",
+            "whatever/${VAR}path"  => "whatever/${VAR}path",
             "whatever/path/${VAR}" => "whatever/path/${VAR}",
             "${}/whatever/path"    => "${}/whatever/path",
             "whatever/${}path"     => "whatever/${}path",
@@ -808,93 +3440,826 @@ mod env_test {
     }
 
     #[test]
-    fn test_error_env() {
-        fn e(_: &str) -> Result<Option<String>, ()> {
-            Err(())
+    fn test_error_env() {
+        fn e(_: &str) -> Result<Option<String>, ()> {
+            Err(())
+        }
+
+        table! { e, unwrap,
+            "whatever/path" => "whatever/path",
+            // check that escaped $ does nothing
+            "whatever/$/path" => "whatever/$/path",
+            "whatever/path$" => "whatever/path$",
+            "whatever/$$path" => "whatever/$path"
+        };
+
+        table! { e, error,
+            "$VAR/something" => ("VAR", 0..4),
+            "${VAR}/something" => ("VAR", 0..6),
+            "whatever/${VAR}/something" => ("VAR", 9..15),
+            "whatever/${VAR}" => ("VAR", 9..15),
+            "whatever/$VAR/something" => ("VAR", 9..13),
+            "whatever/$VARsomething" => ("VARsomething", 9..22),
+            "whatever/$VAR" => ("VAR", 9..13),
+            "whatever/$VAR_VAR_VAR" => ("VAR_VAR_VAR", 9..21)
+        };
+    }
+
+    #[test]
+    fn test_regular_env() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "VAR" => Ok(Some("value")),
+                "a_b" => Ok(Some("X_Y")),
+                "EMPTY" => Ok(Some("")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        table! { e, unwrap,
+            // no variables
+            "whatever/path" => "whatever/path",
+
+            // empty string
+            "" => "",
+
+            // existing variable without braces in various positions
+            "$VAR/whatever/path" => "value/whatever/path",
+            "whatever/$VAR/path" => "whatever/value/path",
+            "whatever/path/$VAR" => "whatever/path/value",
+            "whatever/$VARpath" => "whatever/$VARpath",
+            "$VAR$VAR/whatever" => "valuevalue/whatever",
+            "/whatever$VAR$VAR" => "/whatevervaluevalue",
+            "$VAR $VAR" => "value value",
+            "$a_b" => "X_Y",
+            "$a_b$VAR" => "X_Yvalue",
+
+            // existing variable with braces in various positions
+            "${VAR}/whatever/path" => "value/whatever/path",
+            "whatever/${VAR}/path" => "whatever/value/path",
+            "whatever/path/${VAR}" => "whatever/path/value",
+            "whatever/${VAR}path" => "whatever/valuepath",
+            "${VAR}${VAR}/whatever" => "valuevalue/whatever",
+            "/whatever${VAR}${VAR}" => "/whatevervaluevalue",
+            "${VAR} ${VAR}" => "value value",
+            "${VAR}$VAR" => "valuevalue",
+
+            // default values
+            "/answer/${UNKNOWN:-42}" => "/answer/42",
+            "/answer/${:-42}" => "/answer/${:-42}",
+            "/whatever/${UNKNOWN:-other}$VAR" => "/whatever/othervalue",
+            "/whatever/${UNKNOWN:-other}/$VAR" => "/whatever/other/value",
+            ":-/whatever/${UNKNOWN:-other}/$VAR :-" => ":-/whatever/other/value :-",
+            "/whatever/${VAR:-other}" => "/whatever/value",
+            "/whatever/${VAR:-other}$VAR" => "/whatever/valuevalue",
+            "/whatever/${VAR} :-" => "/whatever/value :-",
+            "/whatever/${:-}" => "/whatever/${:-}",
+            "/whatever/${UNKNOWN:-}" => "/whatever/",
+
+            // empty variable in various positions
+            "${EMPTY}/whatever/path" => "/whatever/path",
+            "whatever/${EMPTY}/path" => "whatever//path",
+            "whatever/path/${EMPTY}" => "whatever/path/"
+        };
+
+        table! { e, error,
+            "$ERR" => ("ERR", 0..4),
+            "${ERR}" => ("ERR", 0..6)
+        };
+    }
+
+    #[test]
+    fn test_operators() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "VAR" => Ok(Some("value")),
+                "EMPTY" => Ok(Some("")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        table! { e, unwrap,
+            // default values
+            "${VAR:-x}|${EMPTY:-x}|${UNSET:-x}|${ERR:-x}" => "value|x|x|x",
+            "${VAR-x}|${EMPTY-x}|${UNSET-x}|${ERR-x}" => "value||x|x",
+
+            // assignments without an assignment context
+            "${VAR:=x}|${EMPTY:=x}|${UNSET:=x}|${ERR:=x}" => "value|x|x|x",
+            "${VAR=x}|${EMPTY=x}|${UNSET=x}|${ERR=x}" => "value||x|x",
+
+            // required values which are present
+            "${VAR:?x}|${EMPTY?x}" => "value|",
+
+            // alternative values
+            "${VAR:+x}|${EMPTY:+x}|${UNSET:+x}|${ERR:+x}" => "x|||",
+            "${VAR+x}|${EMPTY+x}|${UNSET+x}|${ERR+x}" => "x|x||",
+
+            // operators are only recognized right after a valid name
+            "${:+x}|${-x}|${VAR.:-x}" => "${:+x}|${-x}|${VAR.:-x}",
+
+            // words may contain operator characters
+            "${UNSET:-a:-b=c?d+e}" => "a:-b=c?d+e"
+        };
+
+        table! { e, required,
+            "${EMPTY:?must not be empty}" => ("EMPTY", "must not be empty", 0..27),
+            "${UNSET:?}" => ("UNSET", "parameter null or not set", 0..10),
+            "${UNSET?}" => ("UNSET", "parameter not set", 0..9),
+            "${ERR?error}" => ("ERR", "error", 0..12)
+        };
+
+        assert_eq!(
+            env_with_context_no_errors("${UNSET:?x}/${VAR:?x}", |s| e(s).unwrap()),
+            "${UNSET:?x}/value"
+        );
+    }
+
+    #[test]
+    fn test_pattern_removal() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "ARCHIVE" => Ok(Some("backup.tar.gz")),
+                "PWD" => Ok(Some("/home/user/projects/x")),
+                "HOME" => Ok(Some("/home/user")),
+                "GLOB" => Ok(Some("*")),
+                "EMPTY" => Ok(Some("")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        table! { e, unwrap,
+            "${ARCHIVE%.tar.gz}" => "backup",
+            "${ARCHIVE%.*}" => "backup.tar",
+            "${ARCHIVE%%.*}" => "backup",
+            "${ARCHIVE#*.}" => "tar.gz",
+            "${ARCHIVE##*.}" => "gz",
+            "${PWD##*/}" => "x",
+            "${PWD#*/}" => "home/user/projects/x",
+            "${PWD#/home/[a-z]*/}" => "projects/x",
+            "${PWD%/*}" => "/home/user/projects",
+            "${PWD%%/*}" => "",
+
+            // non-matching patterns leave the value intact
+            "${ARCHIVE%.zip}" => "backup.tar.gz",
+            "${ARCHIVE#.*}" => "backup.tar.gz",
+            "${EMPTY%x}" => "",
+
+            // patterns are expanded
+            "${PWD#$HOME/}" => "projects/x",
+            "${ARCHIVE#$GLOB.}" => "tar.gz",
+            "${ARCHIVE#\\*}" => "backup.tar.gz",
+
+            // unknown variables are left as is
+            "${UNKNOWN%.*}/${UNKNOWN##*/}" => "${UNKNOWN%.*}/${UNKNOWN##*/}",
+
+            // colons are not allowed
+            "${ARCHIVE:%.*}" => "${ARCHIVE:%.*}"
+        };
+
+        table! { e, error,
+            "${ERR%.*}" => ("ERR", 0..9),
+            "${ARCHIVE%$ERR}" => ("ERR", 10..14)
+        };
+    }
+
+    #[test]
+    fn test_pattern_replacement() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "VERSION" => Ok(Some("1.22.3")),
+                "PATH" => Ok(Some("/usr/bin:/bin")),
+                "SEP" => Ok(Some(":")),
+                "TEXT" => Ok(Some("aaa bbb aaa")),
+                "EMPTY" => Ok(Some("")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        table! { e, unwrap,
+            "${VERSION/./_}" => "1_22.3",
+            "${VERSION//./_}" => "1_22_3",
+            "${VERSION//.}" => "1223",
+            "${VERSION//./}" => "1223",
+            "${VERSION//[0-9]/N}" => "N.NN.N",
+            "${VERSION//2*/x}" => "1.x",
+            "${TEXT/a*a/x}" => "x",
+            "${TEXT// /}" => "aaabbbaaa",
+
+            // anchored replacements
+            "${TEXT/#aaa/x}" => "x bbb aaa",
+            "${TEXT/%aaa/x}" => "aaa bbb x",
+            "${TEXT/#bbb/x}" => "aaa bbb aaa",
+            "${TEXT/#/>}" => ">aaa bbb aaa",
+            "${TEXT/%/<}" => "aaa bbb aaa<",
+            "${TEXT/%a*/x}" => "x",
+
+            // patterns and replacements are expanded
+            "${PATH//$SEP/ }" => "/usr/bin /bin",
+            "${PATH//:/$SEP$SEP}" => "/usr/bin::/bin",
+
+            // slashes in patterns may be escaped
+            "${PATH//\\//|}" => "|usr|bin:|bin",
+
+            // empty patterns and values
+            "${TEXT//}" => "aaa bbb aaa",
+            "${TEXT///x}" => "aaa bbb aaa",
+            "${EMPTY//a/b}" => "",
+
+            // unknown variables are left as is
+            "${UNKNOWN//a/b}" => "${UNKNOWN//a/b}"
+        };
+
+        table! { e, error,
+            "${ERR/a/b}" => ("ERR", 0..10),
+            "${TEXT/a/$ERR}" => ("ERR", 9..13)
+        };
+    }
+
+    #[test]
+    fn test_substring_and_length() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "VAR" => Ok(Some("0123456789")),
+                "UNI" => Ok(Some("añb€c")),
+                "EMPTY" => Ok(Some("")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        table! { e, unwrap,
+            "${VAR:2}" => "23456789",
+            "${VAR:2:5}" => "23456",
+            "${VAR:0:0}" => "",
+            "${VAR::3}" => "012",
+            "${VAR:8:10}" => "89",
+            "${VAR:10}" => "",
+            "${VAR:20:2}" => "",
+            "${VAR: -4}" => "6789",
+            "${VAR:(-4)}" => "6789",
+            "${VAR: -4:2}" => "67",
+            "${VAR:2:-3}" => "23456",
+            "${VAR: -4:-2}" => "67",
+            "${VAR:5:-6}" => "",
+            "${VAR: -20}" => "",
+            "${VAR: 3 : 2 }" => "34",
+            "${VAR:-4}" => "0123456789",
+            "${#VAR}" => "10",
+
+            // offsets and lengths are counted in characters
+            "${UNI:1:3}" => "ñb€",
+            "${UNI: -2}" => "€c",
+            "${#UNI}" => "5",
+
+            "${EMPTY:1}|${EMPTY: -1}|${#EMPTY}" => "||0",
+
+            // unknown variables and invalid forms are left as is
+            "${UNKNOWN:1:2}|${#UNKNOWN}" => "${UNKNOWN:1:2}|${#UNKNOWN}",
+            "${VAR:x}|${VAR:1:x}|${VAR:(1}|${#VAR:1}|${#}" => "${VAR:x}|${VAR:1:x}|${VAR:(1}|${#VAR:1}|${#}",
+            "${VAR:}|${VAR: }" => "${VAR:}|${VAR: }"
+        };
+
+        table! { e, error,
+            "${ERR:1}" => ("ERR", 0..8),
+            "${#ERR}" => ("ERR", 0..7)
+        };
+    }
+
+    #[test]
+    fn test_case_conversion() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "LOWER" => Ok(Some("hello world")),
+                "UPPER" => Ok(Some("HELLO WORLD")),
+                "HEX" => Ok(Some("deadbeef-xyz")),
+                "UNI" => Ok(Some("éclair straße")),
+                "RANGE" => Ok(Some("[a-f]")),
+                "EMPTY" => Ok(Some("")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        table! { e, unwrap,
+            "${LOWER^}" => "Hello world",
+            "${LOWER^^}" => "HELLO WORLD",
+            "${UPPER,}" => "hELLO WORLD",
+            "${UPPER,,}" => "hello world",
+
+            // patterns select characters to convert
+            "${HEX^^[a-f]}" => "DEADBEEF-xyz",
+            "${HEX^^$RANGE}" => "DEADBEEF-xyz",
+            "${LOWER^^o}" => "hellO wOrld",
+            "${LOWER^h}" => "Hello world",
+            "${LOWER^w}" => "hello world",
+            "${UPPER,,[!L]}" => "heLLo worLd",
+
+            // Unicode case mapping is used
+            "${UNI^}" => "Éclair straße",
+            "${UNI^^}" => "ÉCLAIR STRASSE",
+
+            "${EMPTY^^}" => "",
+
+            // unknown variables are left as is
+            "${UNKNOWN^^}" => "${UNKNOWN^^}"
+        };
+
+        table! { e, error,
+            "${ERR,,}" => ("ERR", 0..8),
+            "${LOWER^^$ERR}" => ("ERR", 9..13)
+        };
+    }
+
+    #[test]
+    fn test_nested_words() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "A" => Ok(Some("a")),
+                "EMPTY" => Ok(Some("")),
+                "ERR" => Err(()),
+                "DIR" => Ok(Some("/some/dir")),
+                _ => Ok(None),
+            }
+        }
+
+        table! { e, unwrap,
+            "${X:-${Y:-fallback}}" => "fallback",
+            "${X:-${A:-fallback}}" => "a",
+            "${X:-${EMPTY-fallback}}" => "",
+            "${X:-$A/${A}}" => "a/a",
+            "${X:-${DIR%/*}/b}" => "/some/b",
+            "${A:+<${A}>}" => "<a>",
+            "${X:-{\\}}" => "{}",
+            "${X:-a\\b}" => "a\\b",
+            "${X:-\\$A}" => "\\$A",
+            "${X:-$$}" => "$",
+            "${X:-$UNKNOWN}" => "$UNKNOWN",
+            "${X:-~}" => "~",
+            "${DIR#${DIR%/*}/}" => "dir",
+            "${DIR/${A:-x}/${A}${A}}" => "/some/dir",
+            "${DIR/dir/${A}${A}}" => "/some/aa",
+            "${A:-$ERR}" => "a",
+            "${X+${ERR}}${X:+$ERR}" => "",
+            "${X:-${A}" => "${X:-a",
+            "${X:-${A}}}" => "a}",
+            "${A:-x}${" => "a${"
+        };
+
+        table! { e, error,
+            "${X:-$ERR}" => ("ERR", 5..9),
+            "${X:-${Y:-${ERR}}}" => ("ERR", 10..16),
+            "${X:?${ERR}}" => ("ERR", 5..11),
+            "${A#$ERR}" => ("ERR", 4..8)
+        };
+
+        table! { e, required,
+            "${X:?${A} is missing}" => ("X", "a is missing", 0..21),
+            "${X:?${Y:?nested}}" => ("Y", "nested", 5..17)
+        };
+    }
+
+    #[test]
+    fn test_backslash_escapes() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "A" => Ok(Some("a")),
+                _ => Ok(None),
+            }
+        }
+
+        let cases = [
+            ("no escapes", "no escapes"),
+            ("\\$A|\\${A}|$\\{A}|\\\\$A", "$A|${A}|${A}|\\a"),
+            ("\\~|\\}|\\{|\\\\", "~|}|{|\\"),
+            ("\\n|\\a|\\é|\\", "\\n|\\a|\\é|\\"),
+            ("$$A|\\$$A", "$A|$a"),
+            ("${X:-\\$A\\}}|${X:-\\\\}|${X:-\\~}", "$A}|\\|~"),
+            ("${X:-\\n}|${A/a/\\$}", "\\n|$"),
+        ];
+        for &(source, target) in cases.iter() {
+            assert_eq!(
+                env_with_context_and_escapes(source, e).unwrap(),
+                target,
+                "{:?}",
+                source
+            );
+        }
+
+        // without escapes, backslashes are only special inside words
+        assert_eq!(
+            env_with_context("\\$A|${X:-\\$A\\}}", e).unwrap(),
+            "\\a|\\$A}"
+        );
+    }
+
+    #[test]
+    fn test_quotes() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "A" => Ok(Some("a")),
+                "PATH" => Ok(Some("/usr/bin:/bin")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        let cases = [
+            ("'$A'|\"$A\"|$A", "'$A'|\"a\"|a"),
+            ("'${ERR}'|'$$'", "'${ERR}'|'$$'"),
+            ("\"'$A'\"|'\"$A\"'", "\"'a'\"|'\"$A\"'"),
+            ("\"${A}\\\"$A\"", "\"a\\\"a\""),
+            ("\\'$A\\'|\\\"$A\"", "\\'a\\'|\\\"a\""),
+            ("'$A|\"$A", "'a|\"a"),
+            ("${X:-'}'}|${X:-\"}\"}", "'}'|\"}\""),
+            ("\"${X:-'}'\"", "\"''\""),
+            ("\"${X:-\"$A\"}\"", "\"\"a\"\""),
+            (
+                "${PATH##*':'}|${PATH%%\":\"*}|${PATH##'*'}",
+                "/bin|/usr/bin|/usr/bin:/bin",
+            ),
+            (
+                "${PATH/':'/' '}|${PATH/'/'usr/}",
+                "/usr/bin' '/bin|/bin:/bin",
+            ),
+            ("${PATH//\"$A\"}", "/usr/bin:/bin"),
+        ];
+        for &(source, target) in cases.iter() {
+            assert_eq!(
+                env_with_context_and_quotes(source, e).unwrap(),
+                target,
+                "{:?}",
+                source
+            );
+        }
+
+        // without quotes, variables inside them are expanded
+        assert_eq!(env_with_context("'$A'", e).unwrap(), "'a'");
+    }
+
+    #[test]
+    fn test_commands() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "A" => Ok(Some("a")),
+                _ => Ok(None),
+            }
+        }
+        fn x(command: &str) -> Result<String, ()> {
+            match command {
+                "fail" => Err(()),
+                "lines" => Ok("1\n2\n\n".into()),
+                command => Ok(format!("<{}>", command)),
+            }
+        }
+
+        let cases = [
+            ("$(cmd)|`cmd`", "<cmd>|<cmd>"),
+            (
+                "$(echo $(inner) (a) ')' \")\" \\))",
+                "<echo $(inner) (a) ')' \")\" \\)>",
+            ),
+            (
+                "`echo \\`inner\\` \\$A \\\\ \\n`",
+                "<echo `inner` $A \\ \\n>",
+            ),
+            ("$(lines)|`lines`", "1\n2|1\n2"),
+            ("$A$(echo $A)", "a<echo $A>"),
+            (
+                "${X:-$(cmd)}|${A:+`cmd`}|${X:-$(echo })}",
+                "<cmd>|<cmd>|<echo }>",
+            ),
+            ("$(unclosed|`unclosed|$((", "$(unclosed|`unclosed|$(("),
+            ("$$(cmd)", "$(cmd)"),
+        ];
+        for &(source, target) in cases.iter() {
+            assert_eq!(
+                env_with_context_and_commands(source, e, x).unwrap(),
+                target,
+                "{:?}",
+                source
+            );
+        }
+
+        assert_eq!(
+            env_with_context_and_commands("a$(fail)b", e, x),
+            Err(ExpandError::Command(CommandError {
+                command: "fail".into(),
+                cause: (),
+                position: Position::new("a$(fail)b", 1..8),
+            }))
+        );
+
+        // commands are never executed in the default mode
+        assert_eq!(
+            env_with_context("$(cmd)|`cmd`|${X:-$(echo })}", e).unwrap(),
+            "$(cmd)|`cmd`|$(echo )}"
+        );
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "BASE_PORT" => Ok(Some("8000")),
+                "SHARD" => Ok(Some("3")),
+                "OP" => Ok(Some("*")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        for &(input, expected) in &[
+            ("$((1 + 2))|$(( 7 / 2 ))|$((-1))", "3|3|-1"),
+            ("port=$((BASE_PORT + SHARD))", "port=8003"),
+            ("$(($BASE_PORT + ${SHARD} + UNSET))", "8003"),
+            ("$((SHARD $OP 2))|$((SHARD > 2 ? 1 : 0))", "6|1"),
+            ("$(( (1 + 2) * $((SHARD - 1)) ))", "6"),
+            ("${UNSET:-$((SHARD * 2))}", "6"),
+            ("$((1 + 2)|$((1 + 2) )|$((", "$((1 + 2)|$((1 + 2) )|$(("),
+            ("$$((1))", "$((1))"),
+        ] {
+            assert_eq!(
+                env_with_context_and_arithmetic(input, e).unwrap(),
+                expected,
+                "{:?}",
+                input
+            );
+        }
+
+        for &(input, ref span) in &[("$((ERR + 1))", 0..12), ("$(($ERR + 1))", 3..7)] {
+            assert_eq!(
+                env_with_context_and_arithmetic(input, e),
+                Err(ExpandError::Lookup(LookupError {
+                    var_name: "ERR".into(),
+                    cause: (),
+                    position: Position::new(input, span.clone()),
+                }))
+            );
+        }
+
+        assert_eq!(
+            env_with_context_and_arithmetic("a$((SHARD / (SHARD - 3)))b", e),
+            Err(ExpandError::Arithmetic(ArithmeticError {
+                expression: "SHARD / (SHARD - 3)".into(),
+                kind: ArithmeticErrorKind::DivisionByZero,
+                position: Position::new("a$((SHARD / (SHARD - 3)))b", 1..25),
+            }))
+        );
+        assert_eq!(
+            env_with_context_and_arithmetic("$((1 +))", e),
+            Err(ExpandError::Arithmetic(ArithmeticError {
+                expression: "1 +".into(),
+                kind: ArithmeticErrorKind::Syntax("unexpected end of expression".into()),
+                position: Position::new("$((1 +))", 0..8),
+            }))
+        );
+        assert_eq!(
+            env_with_context_and_arithmetic("$((BASE_PORT $OP 9999999999999999))", e),
+            Err(ExpandError::Arithmetic(ArithmeticError {
+                expression: "BASE_PORT * 9999999999999999".into(),
+                kind: ArithmeticErrorKind::Overflow,
+                position: Position::new("$((BASE_PORT $OP 9999999999999999))", 0..35),
+            }))
+        );
+
+        // arithmetic expansion is opt-in
+        assert_eq!(
+            env_with_context("$((1 + 2))|${UNSET:-$((SHARD))}", e).unwrap(),
+            "$((1 + 2))|$((SHARD))"
+        );
+        assert_eq!(
+            env_with_context_no_errors("$((1 / 0))", |_| None::<&str>),
+            "$((1 / 0))"
+        );
+    }
+
+    #[test]
+    fn test_assign() {
+        let vars = [("VAR", "value"), ("EMPTY", "")];
+        let mut assigned = Vec::new();
+        let result = super::env_with_context_and_assign(
+            "${VAR:=x}|${EMPTY=y}|${EMPTY:=z}|${UNSET=w}|${VAR+v}|$((N += 2))",
+            |s: &str| Ok::<_, ()>(vars.iter().find(|&&(n, _)| n == s).map(|&(_, v)| v)),
+            |s, v| assigned.push((s.to_owned(), v.to_owned())),
+        );
+        // arithmetic expansion is not enabled
+        assert_eq!(result.unwrap(), "value||z|w|v|$((N += 2))");
+        assert_eq!(
+            assigned,
+            vec![("EMPTY".into(), "z".into()), ("UNSET".into(), "w".into())]
+        );
+    }
+
+    #[test]
+    fn test_strict() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "VAR" => Ok(Some("value")),
+                "EMPTY" => Ok(Some("")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        let expander = Expander::new()
+            .context(e)
+            .tilde(false)
+            .strict(true)
+            .arithmetic(true);
+        assert_eq!(
+            expander
+                .expand(
+                    "$VAR|${EMPTY}|${UNSET:-x}|${UNSET-y}|${UNSET+z}|${UNSET=w}|$$UNSET|$((VAR2 = 1))"
+                )
+                .unwrap(),
+            "value||x|y||w|$UNSET|1"
+        );
+        for &(input, ref span) in &[
+            ("$VAR/$UNSET", 5..11),
+            ("${UNSET}", 0..8),
+            ("${UNSET#x}", 0..10),
+            ("${#UNSET}", 0..9),
+            ("${UNSET^^}", 0..10),
+            ("${EMPTY:-$UNSET}", 9..15),
+            ("$((UNSET + 1))", 0..14),
+            ("$((VAR2 += 1))", 0..14),
+        ] {
+            assert_eq!(
+                expander.expand(input),
+                Err(ExpandError::Unset {
+                    var_name: input
+                        .split(|c: char| !c.is_ascii_alphanumeric())
+                        .find(|name| name.starts_with("UNSET") || *name == "VAR2")
+                        .unwrap()
+                        .into(),
+                    position: Position::new(input, span.clone()),
+                }),
+                "{:?}",
+                input
+            );
+        }
+        assert_eq!(
+            expander.expand("$UNSET ${EMPTY:?}"),
+            Err(ExpandError::Unset {
+                var_name: "UNSET".into(),
+                position: Position::new("$UNSET ${EMPTY:?}", 0..6),
+            })
+        );
+        assert_eq!(
+            expander.expand("$ERR"),
+            Err(ExpandError::Lookup(LookupError {
+                var_name: "ERR".into(),
+                cause: (),
+                position: Position::new("$ERR", 0..4),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_all_errors() {
+        fn e(s: &str) -> Result<Option<&'static str>, &'static str> {
+            match s {
+                "VAR" => Ok(Some("value")),
+                "EMPTY" => Ok(Some("")),
+                "ERR" => Err("failed"),
+                _ => Ok(None),
+            }
         }
 
-        table! { e, unwrap,
-            "whatever/path" => "whatever/path",
-            // check that escaped $ does nothing
-            "whatever/$/path" => "whatever/$/path",
-            "whatever/path$" => "whatever/path$",
-            "whatever/$$path" => "whatever/$path"
-        };
+        let input = "$ERR ${EMPTY:?empty} $VAR ${X:-$ERR} $((1 / 0)) \"${ERR#x}\" $UNSET";
+        let expander = Expander::new().context(e).tilde(false).arithmetic(true);
+        let (result, errors) = expander.expand_all(input, None);
+        assert_eq!(
+            result,
+            "$ERR ${EMPTY:?empty} value $ERR $((1 / 0)) \"${ERR#x}\" $UNSET"
+        );
+        assert_eq!(
+            errors
+                .iter()
+                .map(|e| (e.to_string(), e.position().span.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("error looking key 'ERR' up: failed".to_owned(), 0..4),
+                ("EMPTY: empty".to_owned(), 5..20),
+                ("error looking key 'ERR' up: failed".to_owned(), 31..35),
+                (
+                    "error evaluating '1 / 0': division by zero".to_owned(),
+                    37..47
+                ),
+                ("error looking key 'ERR' up: failed".to_owned(), 49..57),
+            ]
+        );
 
-        table! { e, error,
-            "$VAR/something" => "VAR",
-            "${VAR}/something" => "VAR",
-            "whatever/${VAR}/something" => "VAR",
-            "whatever/${VAR}" => "VAR",
-            "whatever/$VAR/something" => "VAR",
-            "whatever/$VARsomething" => "VARsomething",
-            "whatever/$VAR" => "VAR",
-            "whatever/$VAR_VAR_VAR" => "VAR_VAR_VAR"
-        };
+        let (result, errors) = expander.expand_all("a$ERR$VAR", Some(""));
+        assert_eq!(result, "avalue");
+        assert_eq!(errors.len(), 1);
+        let (result, errors) = expander.expand_all("$VAR $UNSET", Some(""));
+        assert_eq!(result, "value $UNSET");
+        assert!(errors.is_empty());
     }
 
     #[test]
-    fn test_regular_env() {
+    fn test_syntax_errors() {
         fn e(s: &str) -> Result<Option<&'static str>, ()> {
             match s {
                 "VAR" => Ok(Some("value")),
-                "a_b" => Ok(Some("X_Y")),
-                "EMPTY" => Ok(Some("")),
-                "ERR" => Err(()),
+                "NESTED" => Ok(Some("${VAR")),
                 _ => Ok(None),
             }
         }
 
-        table! { e, unwrap,
-            // no variables
-            "whatever/path" => "whatever/path",
-
-            // empty string
-            "" => "",
-
-            // existing variable without braces in various positions
-            "$VAR/whatever/path" => "value/whatever/path",
-            "whatever/$VAR/path" => "whatever/value/path",
-            "whatever/path/$VAR" => "whatever/path/value",
-            "whatever/$VARpath" => "whatever/$VARpath",
-            "$VAR$VAR/whatever" => "valuevalue/whatever",
-            "/whatever$VAR$VAR" => "/whatevervaluevalue",
-            "$VAR $VAR" => "value value",
-            "$a_b" => "X_Y",
-            "$a_b$VAR" => "X_Yvalue",
+        // malformed references are left as they are unless syntax errors are enabled
+        assert_eq!(
+            env_with_context("${VAR ${} ${-x} ${VAR", e).unwrap(),
+            "${VAR ${} ${-x} ${VAR"
+        );
+        let expander = Expander::new().context(e).tilde(false).syntax_errors(true);
+        assert_eq!(
+            expander
+                .expand("$VAR $UNSET ${VAR")
+                .unwrap_err()
+                .position()
+                .span,
+            12..14
+        );
+        let cases = vec![
+            ("${VAR", SyntaxErrorKind::UnterminatedBrace, 0..2),
+            ("$VAR ${}", SyntaxErrorKind::EmptyName, 5..8),
+            ("${A@b}", SyntaxErrorKind::BadOperator("@b".into()), 0..6),
+            (
+                "${UNSET:-${.}}",
+                SyntaxErrorKind::InvalidName(".".into()),
+                9..13,
+            ),
+        ];
+        let strict = expander.syntax_errors(false).strict(true);
+        for (input, kind, span) in cases {
+            assert_eq!(expander.expand(input), strict.expand(input), "{:?}", input);
+            assert_eq!(
+                expander.expand(input),
+                Err(ExpandError::Syntax(SyntaxError {
+                    kind,
+                    position: Position::new(input, span),
+                })),
+                "{:?}",
+                input
+            );
+        }
 
-            // existing variable with braces in various positions
-            "${VAR}/whatever/path" => "value/whatever/path",
-            "whatever/${VAR}/path" => "whatever/value/path",
-            "whatever/path/${VAR}" => "whatever/path/value",
-            "whatever/${VAR}path" => "whatever/valuepath",
-            "${VAR}${VAR}/whatever" => "valuevalue/whatever",
-            "/whatever${VAR}${VAR}" => "/whatevervaluevalue",
-            "${VAR} ${VAR}" => "value value",
-            "${VAR}$VAR" => "valuevalue",
+        // columns are counted in characters
+        assert_eq!(
+            env_with_context("first line\n  ünïcode $VAR ${UNSET:?missing}", e)
+                .unwrap_err()
+                .position(),
+            &Position {
+                span: 28..45,
+                line: 2,
+                column: 16,
+            }
+        );
 
-            // default values
-            "/answer/${UNKNOWN:-42}" => "/answer/42",
-            "/answer/${:-42}" => "/answer/${:-42}",
-            "/whatever/${UNKNOWN:-other}$VAR" => "/whatever/othervalue",
-            "/whatever/${UNKNOWN:-other}/$VAR" => "/whatever/other/value",
-            ":-/whatever/${UNKNOWN:-other}/$VAR :-" => ":-/whatever/other/value :-",
-            "/whatever/${VAR:-other}" => "/whatever/value",
-            "/whatever/${VAR:-other}$VAR" => "/whatever/valuevalue",
-            "/whatever/${VAR} :-" => "/whatever/value :-",
-            "/whatever/${:-}" => "/whatever/${:-}",
-            "/whatever/${UNKNOWN:-}" => "/whatever/",
+        // errors in values of variables point at the references to the variables
+        let error = Expander::new()
+            .context(e)
+            .strict(true)
+            .recursion_limit(2)
+            .expand("$VAR $NESTED")
+            .unwrap_err();
+        assert_eq!(
+            error,
+            ExpandError::Syntax(SyntaxError {
+                kind: SyntaxErrorKind::UnterminatedBrace,
+                position: Position::new("$VAR $NESTED", 5..12),
+            })
+        );
+    }
 
-            // empty variable in various positions
-            "${EMPTY}/whatever/path" => "/whatever/path",
-            "whatever/${EMPTY}/path" => "whatever//path",
-            "whatever/path/${EMPTY}" => "whatever/path/"
-        };
+    #[test]
+    fn test_deep_nesting() {
+        fn e(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "VAR" => Ok(Some("value")),
+                _ => Ok(None),
+            }
+        }
 
-        table! { e, error,
-            "$ERR" => "ERR",
-            "${ERR}" => "ERR"
-        };
+        // words nested up to the limit are expanded, deeper ones are malformed, but never
+        // overflow the stack
+        let input = format!("{}$VAR{}", "${UNSET:-".repeat(100), "}".repeat(100));
+        assert_eq!(env_with_context(&input, e).unwrap(), "value");
+        let input = format!("a{}$VAR{}", "${UNSET:-".repeat(10_000), "}".repeat(10_000));
+        assert_eq!(env_with_context(&input, e).unwrap(), input);
+        assert_eq!(
+            Expander::new().context(e).strict(true).expand(&input),
+            Err(ExpandError::Syntax(SyntaxError {
+                kind: SyntaxErrorKind::TooDeep,
+                position: Position::new(&input, 1..input.len()),
+            }))
+        );
     }
 
     #[test]
@@ -903,10 +4268,11 @@ mod env_test {
             Ok(value) => assert_eq!(env("x/$PATH/x").unwrap(), format!("x/{}/x", value)),
             Err(e) => assert_eq!(
                 env("x/$PATH/x"),
-                Err(LookupError {
+                Err(ExpandError::Lookup(LookupError {
                     var_name: "PATH".into(),
-                    cause: e
-                })
+                    cause: e,
+                    position: Position::new("x/$PATH/x", 2..7),
+                }))
             ),
         }
         match std::env::var("SOMETHING_DEFINITELY_NONEXISTING") {
@@ -916,10 +4282,11 @@ mod env_test {
             ),
             Err(e) => assert_eq!(
                 env("x/$SOMETHING_DEFINITELY_NONEXISTING/x"),
-                Err(LookupError {
+                Err(ExpandError::Lookup(LookupError {
                     var_name: "SOMETHING_DEFINITELY_NONEXISTING".into(),
-                    cause: e
-                })
+                    cause: e,
+                    position: Position::new("x/$SOMETHING_DEFINITELY_NONEXISTING/x", 2..35),
+                }))
             ),
         }
     }
@@ -929,7 +4296,366 @@ mod env_test {
 mod full_tests {
     use std::path::{Path, PathBuf};
 
-    use super::full_with_context;
+    use super::{
+        args_with_context, full_braces_with_context, full_with_context,
+        full_with_context_and_escapes, full_with_context_and_quotes, glob_with_context,
+        BracesError, ExpandError, LookupError, NoMatch, Position,
+    };
+
+    #[test]
+    fn test_tilde_in_words() {
+        fn hd() -> Option<PathBuf> {
+            Some(Path::new("/home/user").into())
+        }
+        fn env(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "A" => Ok(Some("a")),
+                _ => Ok(None),
+            }
+        }
+
+        assert_eq!(
+            full_with_context("${X:-~/a}", hd, env),
+            Ok("/home/user/a".into())
+        );
+        assert_eq!(
+            full_with_context("~/${X:-~}/${A:+~}", hd, env),
+            Ok("/home/user//home/user//home/user".into())
+        );
+        assert_eq!(
+            full_with_context("${X:-a~}:${X:-~other/a}:${X:-~$A}", hd, env),
+            Ok("a~:~other/a:~a".into())
+        );
+        assert_eq!(
+            full_with_context("${X:-~}", || None::<PathBuf>, env),
+            Ok("~".into())
+        );
+    }
+
+    #[test]
+    fn test_escaped_tilde() {
+        fn hd() -> Option<PathBuf> {
+            Some(Path::new("/home/user").into())
+        }
+        fn env(_: &str) -> Result<Option<&'static str>, ()> {
+            Ok(None)
+        }
+
+        assert_eq!(
+            full_with_context_and_escapes("\\~/a", hd, env),
+            Ok("~/a".into())
+        );
+        assert_eq!(
+            full_with_context_and_escapes("~/\\~", hd, env),
+            Ok("/home/user/~".into())
+        );
+        assert_eq!(
+            full_with_context_and_escapes("${X:-\\~}|${X:-~}", hd, env),
+            Ok("~|/home/user".into())
+        );
+        assert_eq!(full_with_context("\\~/a", hd, env), Ok("\\~/a".into()));
+    }
+
+    #[test]
+    fn test_quoted_tilde() {
+        fn hd() -> Option<PathBuf> {
+            Some(Path::new("/home/user").into())
+        }
+        fn env(_: &str) -> Result<Option<&'static str>, ()> {
+            Ok(None)
+        }
+
+        assert_eq!(
+            full_with_context_and_quotes("~/'~'", hd, env),
+            Ok("/home/user/'~'".into())
+        );
+        assert_eq!(
+            full_with_context_and_quotes("'~'/a|\"~\"", hd, env),
+            Ok("'~'/a|\"~\"".into())
+        );
+        assert_eq!(
+            full_with_context_and_quotes("${X:-~}|${X:-'~'}|\"${X:-~}\"|${X:-~'a'}", hd, env),
+            Ok("/home/user|'~'|\"~\"|~'a'".into())
+        );
+    }
+
+    #[test]
+    fn test_braces() {
+        fn hd() -> Option<PathBuf> {
+            Some(Path::new("/home/user").into())
+        }
+        fn env(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "A" => Ok(Some("a")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        assert_eq!(
+            full_braces_with_context("{~,$A}/{x,${B:-b}}", hd, env),
+            Ok(vec![
+                "/home/user/x".to_owned(),
+                "/home/user/b".to_owned(),
+                "a/x".to_owned(),
+                "a/b".to_owned(),
+            ])
+        );
+        assert_eq!(
+            full_braces_with_context("~/{1..2}", || None::<PathBuf>, env),
+            Ok(vec!["~/1".to_owned(), "~/2".to_owned()])
+        );
+        assert_eq!(
+            full_braces_with_context("{a,$ERR}", hd, env),
+            Err(ExpandError::Lookup(LookupError {
+                var_name: "ERR".into(),
+                cause: (),
+                position: Position::new("$ERR", 0..4),
+            }))
+        );
+        let input = "{a,b}".repeat(17);
+        assert_eq!(
+            full_braces_with_context(&input, hd, env),
+            Err(ExpandError::Braces(BracesError {
+                limit: 100_000,
+                position: Position::new(&input, 0..input.len()),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_args() {
+        fn hd() -> Option<PathBuf> {
+            Some(Path::new("/home/some user").into())
+        }
+        fn env(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "A" => Ok(Some(" a  b ")),
+                "Q" => Ok(Some("'q' \\\"")),
+                "EMPTY" => Ok(Some("")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+        fn env_with_ifs(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "IFS" => Ok(Some(":")),
+                "PATH" => Ok(Some("/bin::/usr/bin")),
+                _ => Ok(None),
+            }
+        }
+
+        let cases: &[(&str, &[&str])] = &[
+            ("", &[]),
+            ("  \t\n ", &[]),
+            (" x  y\tz ", &["x", "y", "z"]),
+            ("$A", &["a", "b"]),
+            ("x${A}y", &["x", "a", "b", "y"]),
+            ("\"$A\" '$A'", &[" a  b ", "$A"]),
+            ("\"x\\\"$Q\\$\"", &["x\"'q' \\\"$"]),
+            ("$Q", &["'q'", "\\\""]),
+            ("$EMPTY \"$EMPTY\" '' x$EMPTY", &["", "", "x"]),
+            ("a\\ b \\'c\\' d\\\\", &["a b", "'c'", "d\\"]),
+            ("$UNSET ${UNSET}", &["$UNSET", "${UNSET}"]),
+            ("${UNSET:-'x  y'  z} ${A:+\"$A\"}", &["x  y", "z", " a  b "]),
+            ("${A:-x y} ${A#?}", &["a", "b", "a", "b"]),
+            ("$((1 + 2))", &["$((1", "+", "2))"]),
+            (
+                "~ ~/x x/~ ~\"/\"",
+                &["/home/some user", "/home/some user/x", "x/~", "~/"],
+            ),
+            ("~$A ~other/x \\~", &["~", "a", "b", "~other/x", "~"]),
+            ("${UNSET:-~/x}", &["/home/some user/x"]),
+        ];
+        for &(input, expected) in cases {
+            assert_eq!(
+                args_with_context(input, hd, env),
+                Ok(expected.iter().map(|s| s.to_string()).collect()),
+                "{:?}",
+                input
+            );
+        }
+
+        assert_eq!(
+            args_with_context("ls $PATH", hd, env_with_ifs),
+            Ok(vec![
+                "ls".to_owned(),
+                "/bin".to_owned(),
+                "".to_owned(),
+                "/usr/bin".to_owned()
+            ])
+        );
+        let mut lookups = 0;
+        assert_eq!(
+            args_with_context("${A:-x} ${A:+y} ${UNSET-z}", hd, |s: &str| {
+                lookups += 1;
+                env(s)
+            }),
+            Ok(vec![
+                "a".to_owned(),
+                "b".to_owned(),
+                "y".to_owned(),
+                "z".to_owned()
+            ])
+        );
+        // one lookup of `IFS`, and one for each reference
+        assert_eq!(lookups, 4);
+        assert_eq!(
+            args_with_context("x $ERR", hd, env),
+            Err(ExpandError::Lookup(LookupError {
+                var_name: "ERR".into(),
+                cause: (),
+                position: Position::new("x $ERR", 2..6),
+            }))
+        );
+        assert_eq!(
+            args_with_context("x y", hd, |s: &str| match s {
+                "IFS" => Err(()),
+                _ => Ok(None::<&str>),
+            }),
+            Err(ExpandError::Lookup(LookupError {
+                var_name: "IFS".into(),
+                cause: (),
+                position: Position::new("x y", 0..3),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_glob() {
+        let root = std::env::temp_dir().join(format!("shellexpand-[glob]-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("a*")).unwrap();
+        std::fs::write(root.join("a*/1.toml"), "").unwrap();
+        std::fs::write(root.join("a*/2.toml"), "").unwrap();
+        let hd = || Some(root.clone());
+        fn env(s: &str) -> Result<Option<&'static str>, ()> {
+            match s {
+                "STAR" => Ok(Some("*")),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+
+        assert_eq!(
+            glob_with_context("~/*/$STAR.toml", hd, env, NoMatch::Keep),
+            Ok(vec![root.join("a*/1.toml"), root.join("a*/2.toml")])
+        );
+        assert_eq!(
+            glob_with_context("~/a\\*/1.*", hd, env, NoMatch::Fail),
+            Ok(vec![root.join("a*/1.toml")])
+        );
+        assert_eq!(
+            glob_with_context("~/a\\*/[3-9].toml", hd, env, NoMatch::Keep),
+            Ok(vec![root.join("a*/[3-9].toml")])
+        );
+        let pattern = format!(
+            "{}/a\\*/?",
+            super::pattern::escape(&root.display().to_string())
+        );
+        assert_eq!(
+            glob_with_context("~/a\\*/?", hd, env, NoMatch::Fail),
+            Err(ExpandError::NoMatch {
+                pattern,
+                position: Position::new("~/a\\*/?", 0..7),
+            })
+        );
+        assert_eq!(
+            glob_with_context("~/a*", hd, env, NoMatch::Keep),
+            Ok(vec![root.join("a*")])
+        );
+        assert_eq!(
+            glob_with_context("$STAR/$ERR", hd, env, NoMatch::Keep),
+            Err(ExpandError::Lookup(LookupError {
+                var_name: "ERR".into(),
+                cause: (),
+                position: Position::new("$STAR/$ERR", 6..10),
+            }))
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_glob_non_unicode() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut dir_name = OsStr::from_bytes(b"shellexpand-\xff-[glob]-").to_os_string();
+        dir_name.push(std::process::id().to_string());
+        let root = std::env::temp_dir().join(dir_name);
+        std::fs::create_dir_all(&root).unwrap();
+        let name = OsStr::from_bytes(b"\xfe.toml");
+        std::fs::write(root.join(name), "").unwrap();
+        let hd = || Some(root.clone());
+        fn env(_: &str) -> Result<Option<&'static str>, ()> {
+            Ok(None)
+        }
+
+        assert_eq!(
+            glob_with_context("~/*.toml", hd, env, NoMatch::Fail),
+            Ok(vec![root.join(name)])
+        );
+        assert_eq!(
+            glob_with_context("~/*.json", hd, env, NoMatch::Keep),
+            Ok(vec![root.join("*.json")])
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8() {
+        use std::ffi::{OsStr, OsString};
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+        use super::{env_os_with_context, full_os_with_context, tilde_os_with_context};
+
+        fn hd() -> Option<PathBuf> {
+            Some(PathBuf::from(OsString::from_vec(b"/home/\xe9".to_vec())))
+        }
+        fn env(s: &str) -> Result<Option<OsString>, ()> {
+            match s {
+                "A" => Ok(Some(OsString::from_vec(b"a\xff\xfeb".to_vec()))),
+                "T" => Ok(Some("~".into())),
+                "ERR" => Err(()),
+                _ => Ok(None),
+            }
+        }
+        let os = OsStr::from_bytes;
+
+        assert_eq!(
+            full_os_with_context(os(b"~/$A/\xc3"), hd, env),
+            Ok(os(b"/home/\xe9/a\xff\xfeb/\xc3").into())
+        );
+        assert_eq!(
+            full_os_with_context(os(b"$T/\xff"), hd, env),
+            Ok(os(b"~/\xff").into())
+        );
+        assert_eq!(
+            env_os_with_context(os(b"${#A} ${A#a?} ${A%\xfe*} ${B:-\xff}"), env),
+            Ok(os(b"4 \xfeb a\xff \xff").into())
+        );
+        assert_eq!(
+            env_os_with_context(os(b"\xff$ERR"), env),
+            Err(ExpandError::Lookup(LookupError {
+                var_name: "ERR".into(),
+                cause: (),
+                // the invalid byte is a single character of the encoded input
+                position: Position {
+                    span: 4..8,
+                    line: 1,
+                    column: 2,
+                },
+            }))
+        );
+        assert_eq!(tilde_os_with_context(os(b"~\xff/x"), hd), os(b"~\xff/x"));
+        assert_eq!(
+            tilde_os_with_context(os("~/\u{10FE00}".as_bytes()), hd),
+            os(b"/home/\xe9/\xf4\x8f\xb8\x80")
+        );
+    }
 
     #[test]
     fn test_quirks() {
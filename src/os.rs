@@ -0,0 +1,139 @@
+//! Lossless conversion between `OsStr`s and the strings which the expansion works with.
+//!
+//! On Unix, an `OsStr` is an arbitrary sequence of bytes. Its valid UTF-8 parts are converted to
+//! the same characters, and every other byte is converted to one of 256 characters from the end of
+//! the last private use plane, which are used for nothing else: these characters themselves are
+//! converted byte by byte, so that every `OsStr` has exactly one encoding. Bytes of the syntax,
+//! like `$` and `{`, are ASCII, so the encoded string is parsed in the same way as the original
+//! bytes would be. On other platforms, strings which are not valid Unicode are converted lossily.
+
+use std::borrow::Cow;
+use std::ffi::OsStr;
+
+/// Converts `s` to a string which `decode()` converts back to `s`.
+#[cfg(unix)]
+pub fn encode(s: &OsStr) -> Cow<'_, str> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::str;
+
+    let mut bytes = s.as_bytes();
+    if let Ok(text) = str::from_utf8(bytes) {
+        if !text.chars().any(is_escape) {
+            return text.into();
+        }
+    }
+
+    let mut result = String::with_capacity(bytes.len());
+    loop {
+        match str::from_utf8(bytes) {
+            Ok(text) => {
+                push_escaped(&mut result, text);
+                return result.into();
+            }
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                push_escaped(&mut result, str::from_utf8(valid).unwrap());
+                let invalid_len = e.error_len().unwrap_or(rest.len());
+                result.extend(rest[..invalid_len].iter().map(|&b| escape(b)));
+                bytes = &rest[invalid_len..];
+            }
+        }
+    }
+}
+
+/// Converts `s` to a string, replacing invalid Unicode with `U+FFFD`.
+#[cfg(not(unix))]
+pub fn encode(s: &OsStr) -> Cow<'_, str> {
+    s.to_string_lossy()
+}
+
+/// Converts a string produced by `encode()`, or an expansion of it, back to an `OsStr`.
+#[cfg(unix)]
+pub fn decode(s: &str) -> Cow<'_, OsStr> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    if !s.chars().any(is_escape) {
+        return OsStr::from_bytes(s.as_bytes()).into();
+    }
+    let mut bytes = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        if is_escape(c) {
+            bytes.push((c as u32 - ESCAPE_START) as u8);
+        } else {
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+    }
+    OsString::from_vec(bytes).into()
+}
+
+/// Converts a string produced by `encode()`, or an expansion of it, back to an `OsStr`.
+#[cfg(not(unix))]
+pub fn decode(s: &str) -> Cow<'_, OsStr> {
+    OsStr::new(s).into()
+}
+
+/// The first of the characters which stand for single bytes.
+#[cfg(unix)]
+const ESCAPE_START: u32 = 0x10FE00;
+
+#[cfg(unix)]
+fn is_escape(c: char) -> bool {
+    (ESCAPE_START..=ESCAPE_START + 0xFF).contains(&(c as u32))
+}
+
+#[cfg(unix)]
+fn escape(b: u8) -> char {
+    std::char::from_u32(ESCAPE_START + u32::from(b)).unwrap()
+}
+
+/// Pushes valid UTF-8 text, escaping the characters which stand for single bytes.
+#[cfg(unix)]
+fn push_escaped(result: &mut String, text: &str) {
+    for c in text.chars() {
+        if is_escape(c) {
+            result.extend(c.encode_utf8(&mut [0; 4]).bytes().map(escape));
+        } else {
+            result.push(c);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod os_tests {
+    use std::borrow::Cow;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::{decode, encode};
+
+    #[test]
+    fn test_round_trip() {
+        let cases: &[&[u8]] = &[
+            b"",
+            b"plain ${ASCII}",
+            "ünïcödé".as_bytes(),
+            b"\xff",
+            b"a\xffb\xc3",
+            b"\xc3\xa9\xe2\x82",
+            "\u{10FE24}\u{10FEFF}".as_bytes(),
+            b"\xf4\x8f\xb8\xa4\xff\xf4\x8f",
+        ];
+        for &bytes in cases {
+            let s = OsStr::from_bytes(bytes);
+            let encoded = encode(s);
+            assert_eq!(decode(&encoded), s, "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        assert!(matches!(encode(OsStr::new("$A/é")), Cow::Borrowed("$A/é")));
+        assert!(matches!(decode("$A/é"), Cow::Borrowed(_)));
+        assert_eq!(encode(OsStr::from_bytes(b"$A\xff")), "$A\u{10FEFF}");
+        assert_eq!(
+            encode(OsStr::new("\u{10FE00}")),
+            "\u{10FEF4}\u{10FE8F}\u{10FEB8}\u{10FE80}"
+        );
+    }
+}
@@ -0,0 +1,1327 @@
+//! Parsing of strings with variable references.
+//!
+//! The input is split into a list of segments: literal text, escape sequences and variable
+//! references. Braced references may contain words after their operators, which are parsed
+//! recursively in the same way, so that they can be expanded too. Quoted regions, if quotes are
+//! enabled, are represented by their own segments. Every segment remembers its byte range in the
+//! input.
+//!
+//! The parser is public, so that tools like syntax highlighters and linters can work with exactly
+//! the same grammar as the expansion functions; see `parse()` and `Visitor`.
+
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+
+use Position;
+
+/// A part of a parsed string; see `parse()`.
+///
+/// Every segment has a `span`, which is its byte range in the input, including the delimiters,
+/// like the `${` and `}` of a braced reference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment<'a> {
+    /// Text which is copied to the output as it is.
+    Literal { text: &'a str, span: Range<usize> },
+    /// An escape sequence, like `$$`, which stands for `text`.
+    Escape { text: &'a str, span: Range<usize> },
+    /// `$NAME`
+    Variable { name: &'a str, span: Range<usize> },
+    /// `${NAME}`, optionally with an operator, like in `${NAME:-word}`.
+    Braced {
+        name: &'a str,
+        modifier: Option<Modifier<'a>>,
+        span: Range<usize>,
+    },
+    /// A tilde prefix, like `~` or `~user`, in the beginning of the input or of a word.
+    Tilde { user: &'a str, span: Range<usize> },
+    /// `'text'`, whose text is never expanded.
+    SingleQuoted { text: &'a str, span: Range<usize> },
+    /// A command substitution, `$(command)` or `` `command` ``.
+    ///
+    /// In the latter form, backslashes inside `command` still have to be removed before `$`,
+    /// `` ` `` and another backslash.
+    Command {
+        command: &'a str,
+        backquoted: bool,
+        span: Range<usize>,
+    },
+    /// An arithmetic expansion, `$((expression))`.
+    ///
+    /// The expression is parsed like the contents of double quotes, so it may contain variables,
+    /// which are expanded before the expression is evaluated.
+    Arithmetic {
+        expression: Word<'a>,
+        span: Range<usize>,
+    },
+    /// `"..."`, in which only variables, arithmetic expansions, command substitutions and escape
+    /// sequences are recognized.
+    DoubleQuoted {
+        segments: Vec<Segment<'a>>,
+        span: Range<usize>,
+    },
+    /// A malformed reference, like `${` without a closing brace or `${}`.
+    ///
+    /// The expansion functions leave such references as they are, unless the expansion is
+    /// strict, in which case they report them with `ExpandError::Syntax`.
+    Invalid {
+        kind: SyntaxErrorKind,
+        span: Range<usize>,
+    },
+}
+
+/// An operator inside braces along with its arguments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Modifier<'a> {
+    /// The operator itself.
+    pub operator: Operator,
+    /// Whether the operator is prefixed with a colon, like in `${VAR:-word}`.
+    pub colon: bool,
+    /// The word after the operator; it is the pattern for the replacement operators.
+    pub word: Word<'a>,
+    /// The replacement for the replacement operators.
+    pub replacement: Option<Word<'a>>,
+}
+
+/// A word after an operator inside braces, or the expression of an arithmetic expansion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Word<'a> {
+    /// The parts of the word.
+    pub segments: Vec<Segment<'a>>,
+    /// The byte range of the word in the input.
+    pub span: Range<usize>,
+}
+
+impl<'a> Segment<'a> {
+    /// Returns the byte range of the segment in the input.
+    pub fn span(&self) -> Range<usize> {
+        match *self {
+            Segment::Literal { ref span, .. }
+            | Segment::Escape { ref span, .. }
+            | Segment::Variable { ref span, .. }
+            | Segment::Braced { ref span, .. }
+            | Segment::Tilde { ref span, .. }
+            | Segment::SingleQuoted { ref span, .. }
+            | Segment::Command { ref span, .. }
+            | Segment::Arithmetic { ref span, .. }
+            | Segment::DoubleQuoted { ref span, .. }
+            | Segment::Invalid { ref span, .. } => span.clone(),
+        }
+    }
+}
+
+/// Syntax features which are not always enabled.
+///
+/// All of them are disabled by default, which is the syntax of `env_with_context()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Syntax {
+    /// Whether a backslash escapes `$`, `{`, `}`, `~` and another backslash everywhere, not only
+    /// `}` inside words.
+    pub backslash_escapes: bool,
+    /// Whether single and double quotes are recognized.
+    pub quotes: bool,
+    /// Whether command substitutions are recognized.
+    pub commands: bool,
+    /// Whether arithmetic expansions are recognized.
+    pub arithmetic: bool,
+}
+
+/// Describes what is wrong with a malformed reference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyntaxErrorKind {
+    /// `${` is not followed by a closing brace.
+    UnterminatedBrace,
+    /// The braces contain no variable name, like in `${}`.
+    EmptyName,
+    /// The braces do not start with a valid variable name, like in `${-x}`; contains the text
+    /// inside the braces.
+    InvalidName(String),
+    /// The variable name is followed by something which is not an operator, like in `${A!}` or
+    /// `${A:x}`; contains the text after the name.
+    BadOperator(String),
+    /// References, quotes or substitutions are nested in each other more than 100 levels deep;
+    /// the malformed part is the rest of the input, or of the enclosing word, from the reference
+    /// whose contents are too deep.
+    TooDeep,
+}
+
+impl fmt::Display for SyntaxErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SyntaxErrorKind::UnterminatedBrace => f.write_str("unterminated '${'"),
+            SyntaxErrorKind::EmptyName => f.write_str("empty variable name"),
+            SyntaxErrorKind::InvalidName(ref name) => {
+                write!(f, "invalid variable name '{}'", name)
+            }
+            SyntaxErrorKind::BadOperator(ref operator) => write!(f, "bad operator '{}'", operator),
+            SyntaxErrorKind::TooDeep => f.write_str("nested too deeply"),
+        }
+    }
+}
+
+/// Represents a malformed reference in the input.
+///
+/// This error is returned, wrapped into `ExpandError::Syntax`, by strict expansions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    /// What is wrong.
+    pub kind: SyntaxErrorKind,
+    /// Where the malformed reference is in the input.
+    pub position: Position,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "syntax error: {}", self.kind)
+    }
+}
+
+impl Error for SyntaxError {}
+
+/// Parses a string with the grammar of the expansion functions.
+///
+/// `syntax` selects the optional features, like `Syntax { quotes: true, ..Syntax::default() }`
+/// for the grammar of `env_with_context_and_quotes()`. Parsing never fails: malformed references,
+/// like `${` without a closing brace, become `Segment::Invalid`, and anything else which is not
+/// a valid expansion, like a `$` followed by a space, is a part of the literal text. The spans of
+/// the segments cover the whole input without gaps, so the input can be reconstructed from them.
+///
+/// Tilde prefixes are recognized in the beginning of the input and in the beginning of words
+/// inside braced references. Whether one is expanded depends on the function: `env_with_context()`
+/// leaves them as they are, while `full_with_context()` expands them.
+///
+/// # Examples
+///
+/// ```
+/// use shellexpand::{parse, Segment, Syntax};
+///
+/// let segments = parse("ls $DIR/${FILE:-~/a}", Syntax::default());
+///
+/// assert_eq!(segments.len(), 4);
+/// assert_eq!(segments[0], Segment::Literal { text: "ls ", span: 0..3 });
+/// assert_eq!(segments[1], Segment::Variable { name: "DIR", span: 3..7 });
+/// match segments[3] {
+///     Segment::Braced { name: "FILE", modifier: Some(ref modifier), ref span } => {
+///         assert_eq!(*span, 8..20);
+///         assert_eq!(modifier.word.span, 16..19);
+///         assert_eq!(modifier.word.segments[0], Segment::Tilde { user: "", span: 16..17 });
+///     }
+///     _ => unreachable!(),
+/// }
+/// ```
+pub fn parse(input: &str, syntax: Syntax) -> Vec<Segment<'_>> {
+    let parser = Parser { input, syntax };
+    match tilde_prefix_end(input, syntax.quotes) {
+        Some(tilde_end) => {
+            let mut segments = vec![Segment::Tilde {
+                user: &input[1..tilde_end],
+                span: 0..tilde_end,
+            }];
+            segments.extend(parser.segments(tilde_end..input.len(), false, false, 0));
+            segments
+        }
+        None => parser.segments(0..input.len(), false, false, 0),
+    }
+}
+
+/// Same as `parse()`, but leaves a tilde prefix in the beginning of the input as a part of the
+/// literal text.
+///
+/// The expansion functions use it, because they expand such a prefix after the rest of the input
+/// (see `apply_tilde()`), and `split_args()` recognizes one in the beginning of every field.
+pub fn parse_without_tilde(input: &str, syntax: Syntax) -> Vec<Segment<'_>> {
+    Parser { input, syntax }.segments(0..input.len(), false, false, 0)
+}
+
+/// Walks the segments produced by `parse()`.
+///
+/// Every method is called for the respective kind of segments and does nothing by default,
+/// except for the methods of the segments which contain other segments: they walk into them, so
+/// that a visitor which overrides only `visit_variable()` sees all variables, including the ones
+/// in words after operators and inside quotes. A method which is overridden may call the
+/// respective `walk_*()` function to keep walking into the children.
+///
+/// The segments are plain data with public fields, so a visitor may also collect their spans to
+/// transform the input, like in the example below.
+///
+/// # Examples
+///
+/// ```
+/// use std::ops::Range;
+/// use shellexpand::{parse, walk, Syntax, Visitor};
+///
+/// // Collects the variables which are referenced like `$NAME`
+/// struct Names<'a>(Vec<(&'a str, Range<usize>)>);
+///
+/// impl<'a> Visitor<'a> for Names<'a> {
+///     fn visit_variable(&mut self, name: &'a str, span: Range<usize>) {
+///         self.0.push((name, span));
+///     }
+/// }
+///
+/// let input = "$A/${B:-$C}";
+/// let mut names = Names(Vec::new());
+/// walk(&mut names, &parse(input, Syntax::default()));
+/// assert_eq!(names.0, [("A", 0..2), ("C", 8..10)]);
+///
+/// // Renames `$A` to `${RENAMED}`
+/// let mut output = input.to_owned();
+/// for &(_, ref span) in names.0.iter().rev().filter(|&&(name, _)| name == "A") {
+///     output.replace_range(span.clone(), "${RENAMED}");
+/// }
+/// assert_eq!(output, "${RENAMED}/${B:-$C}");
+/// ```
+pub trait Visitor<'a> {
+    /// Visits any segment; by default, calls the method for its kind with `walk_segment()`.
+    fn visit_segment(&mut self, segment: &Segment<'a>) {
+        walk_segment(self, segment);
+    }
+
+    /// Visits literal text.
+    fn visit_literal(&mut self, text: &'a str, span: Range<usize>) {
+        let _ = (text, span);
+    }
+
+    /// Visits an escape sequence, like `$$`, which stands for `text`.
+    fn visit_escape(&mut self, text: &'a str, span: Range<usize>) {
+        let _ = (text, span);
+    }
+
+    /// Visits `$NAME`.
+    fn visit_variable(&mut self, name: &'a str, span: Range<usize>) {
+        let _ = (name, span);
+    }
+
+    /// Visits `${NAME}` or `${NAME<operator>...}`; by default, walks into the words of the
+    /// operator with `walk_modifier()`.
+    fn visit_braced(&mut self, name: &'a str, modifier: Option<&Modifier<'a>>, span: Range<usize>) {
+        let _ = (name, span);
+        if let Some(modifier) = modifier {
+            walk_modifier(self, modifier);
+        }
+    }
+
+    /// Visits a tilde prefix, like `~` or `~user`.
+    fn visit_tilde(&mut self, user: &'a str, span: Range<usize>) {
+        let _ = (user, span);
+    }
+
+    /// Visits `'text'`.
+    fn visit_single_quoted(&mut self, text: &'a str, span: Range<usize>) {
+        let _ = (text, span);
+    }
+
+    /// Visits `$(command)` or `` `command` ``.
+    fn visit_command(&mut self, command: &'a str, backquoted: bool, span: Range<usize>) {
+        let _ = (command, backquoted, span);
+    }
+
+    /// Visits `$((expression))`; by default, walks into the expression with `visit_word()`.
+    fn visit_arithmetic(&mut self, expression: &Word<'a>, span: Range<usize>) {
+        let _ = span;
+        self.visit_word(expression);
+    }
+
+    /// Visits `"..."`; by default, walks into its segments with `walk()`.
+    fn visit_double_quoted(&mut self, segments: &[Segment<'a>], span: Range<usize>) {
+        let _ = span;
+        walk(self, segments);
+    }
+
+    /// Visits a malformed reference.
+    fn visit_invalid(&mut self, kind: &SyntaxErrorKind, span: Range<usize>) {
+        let _ = (kind, span);
+    }
+
+    /// Visits a word after an operator or an arithmetic expression; by default, walks into its
+    /// segments with `walk()`.
+    fn visit_word(&mut self, word: &Word<'a>) {
+        walk(self, &word.segments);
+    }
+}
+
+/// Calls `visitor.visit_segment()` for every segment.
+pub fn walk<'a, V>(visitor: &mut V, segments: &[Segment<'a>])
+where
+    V: ?Sized + Visitor<'a>,
+{
+    for segment in segments {
+        visitor.visit_segment(segment);
+    }
+}
+
+/// Calls the method of `visitor` for the kind of `segment`.
+pub fn walk_segment<'a, V>(visitor: &mut V, segment: &Segment<'a>)
+where
+    V: ?Sized + Visitor<'a>,
+{
+    match *segment {
+        Segment::Literal { text, ref span } => visitor.visit_literal(text, span.clone()),
+        Segment::Escape { text, ref span } => visitor.visit_escape(text, span.clone()),
+        Segment::Variable { name, ref span } => visitor.visit_variable(name, span.clone()),
+        Segment::Braced {
+            name,
+            ref modifier,
+            ref span,
+        } => visitor.visit_braced(name, modifier.as_ref(), span.clone()),
+        Segment::Tilde { user, ref span } => visitor.visit_tilde(user, span.clone()),
+        Segment::SingleQuoted { text, ref span } => visitor.visit_single_quoted(text, span.clone()),
+        Segment::Command {
+            command,
+            backquoted,
+            ref span,
+        } => visitor.visit_command(command, backquoted, span.clone()),
+        Segment::Arithmetic {
+            ref expression,
+            ref span,
+        } => visitor.visit_arithmetic(expression, span.clone()),
+        Segment::DoubleQuoted {
+            ref segments,
+            ref span,
+        } => visitor.visit_double_quoted(segments, span.clone()),
+        Segment::Invalid { ref kind, ref span } => visitor.visit_invalid(kind, span.clone()),
+    }
+}
+
+/// Calls `visitor.visit_word()` for the word and the replacement of `modifier`.
+pub fn walk_modifier<'a, V>(visitor: &mut V, modifier: &Modifier<'a>)
+where
+    V: ?Sized + Visitor<'a>,
+{
+    visitor.visit_word(&modifier.word);
+    if let Some(ref replacement) = modifier.replacement {
+        visitor.visit_word(replacement);
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    syntax: Syntax,
+}
+
+impl<'a> Parser<'a> {
+    /// Parses the given range of the input, which is nested `depth` levels deep.
+    ///
+    /// Inside words, `\}` is an escape sequence for a closing brace, other backslashes prevent the
+    /// next character from starting a variable reference, and a tilde prefix is recognized in the
+    /// beginning. With backslash escapes enabled, backslashes work in the same way everywhere and
+    /// also escape the rest of the special characters.
+    ///
+    /// Inside double quotes, neither quotes nor tilde prefixes are recognized, and backslashes
+    /// work in the same way as in words.
+    fn segments(
+        &self,
+        range: Range<usize>,
+        in_word: bool,
+        in_double_quotes: bool,
+        depth: usize,
+    ) -> Vec<Segment<'a>> {
+        let input = self.input;
+        let quotes = self.syntax.quotes && !in_double_quotes;
+        let mut segments = Vec::new();
+        let end = range.end;
+        let mut pos = range.start;
+        let mut literal_start = pos;
+
+        if in_word && !in_double_quotes {
+            if let Some(tilde_end) = tilde_prefix_end(&input[pos..end], quotes) {
+                segments.push(Segment::Tilde {
+                    user: &input[pos + 1..pos + tilde_end],
+                    span: pos..pos + tilde_end,
+                });
+                pos += tilde_end;
+                literal_start = pos;
+            }
+        }
+
+        macro_rules! flush_literal {
+            () => {
+                if literal_start < pos {
+                    segments.push(Segment::Literal {
+                        text: &input[literal_start..pos],
+                        span: literal_start..pos,
+                    });
+                }
+            };
+        }
+
+        // the rest of the input is nested too deeply to be parsed
+        macro_rules! too_deep {
+            () => {{
+                flush_literal!();
+                segments.push(Segment::Invalid {
+                    kind: SyntaxErrorKind::TooDeep,
+                    span: pos..end,
+                });
+                pos = end;
+                literal_start = pos;
+                continue;
+            }};
+        }
+
+        // once a `$((` turns out to be unclosed, the ones after it are not scanned again, so that
+        // many of them do not make parsing quadratic
+        let mut arithmetic_unclosed = false;
+
+        while pos < end {
+            let rest = &input[pos..end];
+            let mut chars = rest.chars();
+            let c = chars.next().unwrap();
+            let next_char = chars.next();
+
+            let closing_arithmetic =
+                if self.syntax.arithmetic && !arithmetic_unclosed && rest.starts_with("$((") {
+                    match self.find_closing_arithmetic(pos + 3, end, depth) {
+                        Err(ScanError::Unclosed) => {
+                            arithmetic_unclosed = true;
+                            None
+                        }
+                        scan => Some(scan),
+                    }
+                } else {
+                    None
+                };
+
+            match (c, next_char) {
+                ('\\', Some(next_char)) if self.is_escapable(next_char, in_word) => {
+                    flush_literal!();
+                    segments.push(Segment::Escape {
+                        text: &rest[1..2],
+                        span: pos..pos + 2,
+                    });
+                    pos += 2;
+                    literal_start = pos;
+                }
+                ('\\', Some(next_char))
+                    if in_word || in_double_quotes || quotes || self.syntax.backslash_escapes =>
+                {
+                    pos += 1 + next_char.len_utf8()
+                }
+                ('\'', _) if quotes => match input[pos + 1..end].find('\'') {
+                    Some(idx) => {
+                        flush_literal!();
+                        let closing_quote_idx = pos + 1 + idx;
+                        segments.push(Segment::SingleQuoted {
+                            text: &input[pos + 1..closing_quote_idx],
+                            span: pos..closing_quote_idx + 1,
+                        });
+                        pos = closing_quote_idx + 1;
+                        literal_start = pos;
+                    }
+                    // unbalanced quotes are left as they are
+                    None => pos += 1,
+                },
+                ('"', _) if quotes => match self.find_closing_double_quote(pos + 1, end, depth) {
+                    Ok(closing_quote_idx) => {
+                        flush_literal!();
+                        segments.push(Segment::DoubleQuoted {
+                            segments: self.segments(
+                                pos + 1..closing_quote_idx,
+                                in_word,
+                                true,
+                                depth + 1,
+                            ),
+                            span: pos..closing_quote_idx + 1,
+                        });
+                        pos = closing_quote_idx + 1;
+                        literal_start = pos;
+                    }
+                    Err(ScanError::Unclosed) => pos += 1,
+                    Err(ScanError::TooDeep) => too_deep!(),
+                },
+                ('$', Some('(')) if closing_arithmetic.is_some() => {
+                    let closing_idx = match closing_arithmetic {
+                        Some(Ok(closing_idx)) => closing_idx,
+                        _ => too_deep!(),
+                    };
+                    flush_literal!();
+                    let range = pos + 3..closing_idx;
+                    segments.push(Segment::Arithmetic {
+                        expression: Word {
+                            segments: self.segments(range.clone(), false, true, depth + 1),
+                            span: range,
+                        },
+                        span: pos..closing_idx + 2,
+                    });
+                    pos = closing_idx + 2;
+                    literal_start = pos;
+                }
+                // without the closing parentheses, `$((` may still start a command substitution
+                ('$', Some('(')) | ('`', _) if self.syntax.commands => {
+                    match self.skip_command(pos, end, depth) {
+                        Ok(command_len) => {
+                            flush_literal!();
+                            let backquoted = c == '`';
+                            let command_start = if backquoted { pos + 1 } else { pos + 2 };
+                            segments.push(Segment::Command {
+                                command: &input[command_start..pos + command_len - 1],
+                                backquoted,
+                                span: pos..pos + command_len,
+                            });
+                            pos += command_len;
+                            literal_start = pos;
+                        }
+                        // unbalanced substitutions are left as they are
+                        Err(ScanError::Unclosed) => pos += 1,
+                        Err(ScanError::TooDeep) => too_deep!(),
+                    }
+                }
+                ('$', Some('{')) => match self.find_closing_brace(pos + 2, end, quotes, depth) {
+                    Ok(closing_brace_idx) => {
+                        flush_literal!();
+                        segments.push(self.braced_segment(
+                            pos,
+                            closing_brace_idx,
+                            in_double_quotes,
+                            depth,
+                        ));
+                        pos = closing_brace_idx + 1;
+                        literal_start = pos;
+                    }
+                    Err(ScanError::TooDeep) => too_deep!(),
+                    Err(ScanError::Unclosed) => {
+                        flush_literal!();
+                        segments.push(Segment::Invalid {
+                            kind: SyntaxErrorKind::UnterminatedBrace,
+                            span: pos..pos + 2,
+                        });
+                        pos += 2;
+                        literal_start = pos;
+                    }
+                },
+                ('$', Some('$')) => {
+                    flush_literal!();
+                    segments.push(Segment::Escape {
+                        text: "$",
+                        span: pos..pos + 2,
+                    });
+                    pos += 2;
+                    literal_start = pos;
+                }
+                ('$', Some(next_char)) if is_valid_var_name_char(next_char) => {
+                    flush_literal!();
+                    let name_end_idx = rest[1..]
+                        .find(|c: char| !is_valid_var_name_char(c))
+                        .map_or(end, |idx| pos + 1 + idx);
+                    segments.push(Segment::Variable {
+                        name: &input[pos + 1..name_end_idx],
+                        span: pos..name_end_idx,
+                    });
+                    pos = name_end_idx;
+                    literal_start = pos;
+                }
+                (c, _) => pos += c.len_utf8(),
+            }
+        }
+        flush_literal!();
+
+        segments
+    }
+
+    /// Returns `true` if a backslash followed by `c` is an escape sequence.
+    fn is_escapable(&self, c: char, in_word: bool) -> bool {
+        match c {
+            '}' => in_word || self.syntax.backslash_escapes,
+            '$' | '{' | '~' | '\\' => self.syntax.backslash_escapes,
+            _ => false,
+        }
+    }
+
+    /// Parses a `${...}` reference starting at `start` and ending with the brace at
+    /// `closing_brace_idx`.
+    fn braced_segment(
+        &self,
+        start: usize,
+        closing_brace_idx: usize,
+        in_double_quotes: bool,
+        depth: usize,
+    ) -> Segment<'a> {
+        let quotes = self.syntax.quotes && !in_double_quotes;
+        let body_start = start + 2;
+        let body = &self.input[body_start..closing_brace_idx];
+        let (name, modifier) = parse_braced(body);
+        if modifier.is_none() {
+            if let Some(kind) = name_error(name) {
+                return Segment::Invalid {
+                    kind,
+                    span: start..closing_brace_idx + 1,
+                };
+            }
+        }
+
+        let modifier = modifier.map(|(operator, colon, word)| {
+            let word_start = closing_brace_idx - word.len();
+            let (word, replacement) = match operator {
+                Operator::ReplaceFirst
+                | Operator::ReplaceAll
+                | Operator::ReplacePrefix
+                | Operator::ReplaceSuffix => match find_replacement_separator(word, quotes) {
+                    Some(separator_idx) => {
+                        let pattern_end = word_start + separator_idx;
+                        (
+                            self.word(word_start..pattern_end, in_double_quotes, depth + 1),
+                            Some(self.word(
+                                pattern_end + 1..closing_brace_idx,
+                                in_double_quotes,
+                                depth + 1,
+                            )),
+                        )
+                    }
+                    None => (
+                        self.word(word_start..closing_brace_idx, in_double_quotes, depth + 1),
+                        None,
+                    ),
+                },
+                _ => (
+                    self.word(word_start..closing_brace_idx, in_double_quotes, depth + 1),
+                    None,
+                ),
+            };
+            Modifier {
+                operator,
+                colon,
+                word,
+                replacement,
+            }
+        });
+
+        Segment::Braced {
+            name,
+            modifier,
+            span: start..closing_brace_idx + 1,
+        }
+    }
+
+    fn word(&self, range: Range<usize>, in_double_quotes: bool, depth: usize) -> Word<'a> {
+        Word {
+            segments: self.segments(range.clone(), true, in_double_quotes, depth),
+            span: range,
+        }
+    }
+
+    /// Finds the brace which closes a `${` ending right before `start`.
+    ///
+    /// Nested `${...}` references are skipped, and so are characters after a backslash and `$$`.
+    /// If `quotes` is true, quoted regions are skipped as well, and if command substitutions are
+    /// enabled, they are skipped too. `depth` is the nesting level of the reference, which grows with
+    /// every construct skipped inside.
+    fn find_closing_brace(&self, start: usize, end: usize, quotes: bool, depth: usize) -> Scan {
+        if depth >= MAX_NESTING {
+            return Err(ScanError::TooDeep);
+        }
+        let mut pos = start;
+        while pos < end {
+            let rest = &self.input[pos..end];
+            let mut chars = rest.chars();
+            match (chars.next().unwrap(), chars.next()) {
+                ('}', _) => return Ok(pos),
+                ('\\', Some(c)) => pos += 1 + c.len_utf8(),
+                ('$', Some('$')) => pos += 2,
+                ('$', Some('{')) => {
+                    pos = self.find_closing_brace(pos + 2, end, quotes, depth + 1)? + 1
+                }
+                ('\'', _) if quotes => match rest[1..].find('\'') {
+                    Some(idx) => pos += idx + 2,
+                    None => pos += 1,
+                },
+                ('"', _) if quotes => {
+                    pos = or_skip(self.find_closing_double_quote(pos + 1, end, depth + 1), pos)? + 1
+                }
+                (c, _) => pos += or_skip(self.skip_command(pos, end, depth + 1), c.len_utf8())?,
+            }
+        }
+        Err(ScanError::Unclosed)
+    }
+
+    /// Finds the quote which closes a `"` ending right before `start`.
+    ///
+    /// `${...}` references and command substitutions inside the quotes are skipped, and so are
+    /// characters after a backslash.
+    fn find_closing_double_quote(&self, start: usize, end: usize, depth: usize) -> Scan {
+        if depth >= MAX_NESTING {
+            return Err(ScanError::TooDeep);
+        }
+        let mut pos = start;
+        while pos < end {
+            let rest = &self.input[pos..end];
+            let mut chars = rest.chars();
+            match (chars.next().unwrap(), chars.next()) {
+                ('"', _) => return Ok(pos),
+                ('\\', Some(c)) => pos += 1 + c.len_utf8(),
+                ('$', Some('$')) => pos += 2,
+                ('$', Some('{')) => {
+                    pos = or_skip(
+                        self.find_closing_brace(pos + 2, end, false, depth + 1),
+                        pos + 1,
+                    )? + 1
+                }
+                (c, _) => pos += or_skip(self.skip_command(pos, end, depth + 1), c.len_utf8())?,
+            }
+        }
+        Err(ScanError::Unclosed)
+    }
+
+    /// Returns the length of the command substitution starting at `pos`, or
+    /// `Err(ScanError::Unclosed)` if there is none or command substitutions are disabled.
+    fn skip_command(&self, pos: usize, end: usize, depth: usize) -> Scan {
+        if !self.syntax.commands {
+            return Err(ScanError::Unclosed);
+        }
+        let rest = &self.input[pos..end];
+        if rest.starts_with("$(") {
+            self.find_closing_paren(pos + 2, end, depth)
+                .map(|closing_paren_idx| closing_paren_idx + 1 - pos)
+        } else if rest.starts_with('`') {
+            find_closing_backquote(self.input, pos + 1, end)
+                .map(|closing_quote_idx| closing_quote_idx + 1 - pos)
+                .ok_or(ScanError::Unclosed)
+        } else {
+            Err(ScanError::Unclosed)
+        }
+    }
+
+    /// Finds the parentheses which close a `$((` ending right before `start`.
+    ///
+    /// Returns the index of the first of the closing parentheses. Parentheses inside are counted,
+    /// and references, command substitutions and characters after a backslash are skipped.
+    fn find_closing_arithmetic(&self, start: usize, end: usize, depth: usize) -> Scan {
+        if depth >= MAX_NESTING {
+            return Err(ScanError::TooDeep);
+        }
+        let mut parens = 0;
+        let mut pos = start;
+        while pos < end {
+            let rest = &self.input[pos..end];
+            let mut chars = rest.chars();
+            match (chars.next().unwrap(), chars.next()) {
+                (')', Some(')')) if parens == 0 => return Ok(pos),
+                (')', _) if parens == 0 => return Err(ScanError::Unclosed),
+                (')', _) => {
+                    parens -= 1;
+                    pos += 1;
+                }
+                ('(', _) => {
+                    parens += 1;
+                    pos += 1;
+                }
+                ('\\', Some(c)) => pos += 1 + c.len_utf8(),
+                ('$', Some('{')) => {
+                    pos = or_skip(
+                        self.find_closing_brace(pos + 2, end, false, depth + 1),
+                        pos + 1,
+                    )? + 1
+                }
+                (c, _) => pos += or_skip(self.skip_command(pos, end, depth + 1), c.len_utf8())?,
+            }
+        }
+        Err(ScanError::Unclosed)
+    }
+
+    /// Finds the parenthesis which closes a `$(` ending right before `start`.
+    ///
+    /// The command inside follows the shell syntax, so parentheses are counted, and quoted
+    /// regions, characters after a backslash and nested substitutions are skipped.
+    fn find_closing_paren(&self, start: usize, end: usize, depth: usize) -> Scan {
+        if depth >= MAX_NESTING {
+            return Err(ScanError::TooDeep);
+        }
+        let mut parens = 0;
+        let mut pos = start;
+        while pos < end {
+            let rest = &self.input[pos..end];
+            let mut chars = rest.chars();
+            match (chars.next().unwrap(), chars.next()) {
+                (')', _) if parens == 0 => return Ok(pos),
+                (')', _) => {
+                    parens -= 1;
+                    pos += 1;
+                }
+                ('(', _) => {
+                    parens += 1;
+                    pos += 1;
+                }
+                ('\\', Some(c)) => pos += 1 + c.len_utf8(),
+                ('$', Some('(')) | ('`', _) => pos += self.skip_command(pos, end, depth + 1)?,
+                ('\'', _) => pos += rest[1..].find('\'').ok_or(ScanError::Unclosed)? + 2,
+                ('"', _) => pos = self.find_closing_double_quote(pos + 1, end, depth + 1)? + 1,
+                (c, _) => pos += c.len_utf8(),
+            }
+        }
+        Err(ScanError::Unclosed)
+    }
+}
+
+/// The maximum number of references, quotes and substitutions which may be nested in each other.
+///
+/// Parsing and expansion are recursive, so deeper nesting could overflow the stack.
+const MAX_NESTING: usize = 100;
+
+/// Why a scan for a closing delimiter failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScanError {
+    /// There is no closing delimiter.
+    Unclosed,
+    /// The input is nested more than `MAX_NESTING` levels deep.
+    TooDeep,
+}
+
+/// The index of a closing delimiter or the length of a skipped construct, or why it was not
+/// found.
+type Scan = Result<usize, ScanError>;
+
+/// Returns `fallback` instead of a missing closing delimiter, but keeps `ScanError::TooDeep`.
+fn or_skip(scan: Scan, fallback: usize) -> Scan {
+    match scan {
+        Err(ScanError::Unclosed) => Ok(fallback),
+        scan => scan,
+    }
+}
+
+/// Finds the backquote which closes a `` ` `` ending right before `start`.
+///
+/// Characters after a backslash are skipped.
+fn find_closing_backquote(input: &str, start: usize, end: usize) -> Option<usize> {
+    let mut pos = start;
+    while pos < end {
+        let rest = &input[pos..end];
+        let mut chars = rest.chars();
+        match (chars.next().unwrap(), chars.next()) {
+            ('`', _) => return Some(pos),
+            ('\\', Some(c)) => pos += 1 + c.len_utf8(),
+            (c, _) => pos += c.len_utf8(),
+        }
+    }
+    None
+}
+
+/// Returns the length of the tilde prefix in the beginning of a word, if there is one.
+///
+/// The prefix only counts if it consists of literal characters.
+fn tilde_prefix_end(word: &str, quotes: bool) -> Option<usize> {
+    if !word.starts_with('~') {
+        return None;
+    }
+    let end_idx = word.find('/').unwrap_or(word.len());
+    let special_chars: &[char] = if quotes {
+        &['$', '\\', '\'', '"']
+    } else {
+        &['$', '\\']
+    };
+    if word[..end_idx].contains(special_chars) {
+        None
+    } else {
+        Some(end_idx)
+    }
+}
+
+/// Finds the first unescaped slash in the word of `${VAR/pattern/replacement}`.
+///
+/// If `quotes` is true, slashes inside quotes are skipped as well.
+fn find_replacement_separator(word: &str, quotes: bool) -> Option<usize> {
+    let mut escaped = false;
+    let mut quote = None;
+    for (i, c) in word.char_indices() {
+        match c {
+            _ if quote == Some(c) => quote = None,
+            _ if quote.is_some() => {}
+            '\\' if !escaped => {
+                escaped = true;
+                continue;
+            }
+            '/' if !escaped => return Some(i),
+            '\'' | '"' if quotes && !escaped => quote = Some(c),
+            _ => {}
+        }
+        escaped = false;
+    }
+    None
+}
+
+/// Returns what is wrong with the contents of braces which `parse_braced()` did not recognize as
+/// a reference with an operator, if anything.
+fn name_error(body: &str) -> Option<SyntaxErrorKind> {
+    let name_end_idx = body
+        .find(|c: char| !is_valid_var_name_char(c))
+        .unwrap_or(body.len());
+    if body.is_empty() {
+        Some(SyntaxErrorKind::EmptyName)
+    } else if name_end_idx == 0 {
+        Some(SyntaxErrorKind::InvalidName(body.into()))
+    } else if name_end_idx < body.len() {
+        Some(SyntaxErrorKind::BadOperator(body[name_end_idx..].into()))
+    } else {
+        None
+    }
+}
+
+pub fn is_valid_var_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// A parameter expansion operator which may follow a variable name inside braces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    /// `${VAR:-word}` and `${VAR-word}`
+    Default,
+    /// `${VAR:=word}` and `${VAR=word}`
+    Assign,
+    /// `${VAR:?word}` and `${VAR?word}`
+    Error,
+    /// `${VAR:+word}` and `${VAR+word}`
+    Alternative,
+    /// `${VAR#pattern}`
+    RemoveSmallestPrefix,
+    /// `${VAR##pattern}`
+    RemoveLargestPrefix,
+    /// `${VAR%pattern}`
+    RemoveSmallestSuffix,
+    /// `${VAR%%pattern}`
+    RemoveLargestSuffix,
+    /// `${VAR/pattern/replacement}`
+    ReplaceFirst,
+    /// `${VAR//pattern/replacement}`
+    ReplaceAll,
+    /// `${VAR/#pattern/replacement}`
+    ReplacePrefix,
+    /// `${VAR/%pattern/replacement}`
+    ReplaceSuffix,
+    /// `${VAR:offset}` and `${VAR:offset:length}`
+    Substring {
+        /// The offset of the substring in characters, counted from the end if it is negative.
+        offset: isize,
+        /// The length of the substring, or the number of characters to leave out at the end if it
+        /// is negative.
+        length: Option<isize>,
+    },
+    /// `${#VAR}`
+    Length,
+    /// `${VAR^pattern}`
+    UpperFirst,
+    /// `${VAR^^pattern}`
+    UpperAll,
+    /// `${VAR,pattern}`
+    LowerFirst,
+    /// `${VAR,,pattern}`
+    LowerAll,
+}
+
+impl Operator {
+    /// Returns `true` if the operator decides what to do based on whether the variable is set.
+    pub fn checks_if_set(self) -> bool {
+        matches!(
+            self,
+            Operator::Default | Operator::Assign | Operator::Error | Operator::Alternative
+        )
+    }
+}
+
+/// Splits the contents of `${...}` into a variable name and an optional operator.
+///
+/// The operator is returned along with a flag telling whether it has a colon, which makes it
+/// treat empty variables as unset ones, and the word following it. If the contents do not look
+/// like a variable name followed by an operator, they are returned as a name in their entirety.
+fn parse_braced(body: &str) -> (&str, Option<(Operator, bool, &str)>) {
+    if let Some(var_name) = body.strip_prefix('#') {
+        if !var_name.is_empty() && var_name.chars().all(is_valid_var_name_char) {
+            return (var_name, Some((Operator::Length, false, "")));
+        }
+    }
+
+    let name_end_idx = body
+        .find(|c: char| !is_valid_var_name_char(c))
+        .unwrap_or(body.len());
+    // ${:-value} and the like are not valid
+    if name_end_idx == 0 {
+        return (body, None);
+    }
+
+    let (var_name, rest) = body.split_at(name_end_idx);
+    let (colon, rest) = match rest.strip_prefix(':') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    let (operator, operator_len) = match (colon, rest.chars().next()) {
+        (_, Some('-')) => (Operator::Default, 1),
+        (_, Some('=')) => (Operator::Assign, 1),
+        (_, Some('?')) => (Operator::Error, 1),
+        (_, Some('+')) => (Operator::Alternative, 1),
+        (false, Some('#')) if rest.starts_with("##") => (Operator::RemoveLargestPrefix, 2),
+        (false, Some('#')) => (Operator::RemoveSmallestPrefix, 1),
+        (false, Some('%')) if rest.starts_with("%%") => (Operator::RemoveLargestSuffix, 2),
+        (false, Some('%')) => (Operator::RemoveSmallestSuffix, 1),
+        (false, Some('^')) if rest.starts_with("^^") => (Operator::UpperAll, 2),
+        (false, Some('^')) => (Operator::UpperFirst, 1),
+        (false, Some(',')) if rest.starts_with(",,") => (Operator::LowerAll, 2),
+        (false, Some(',')) => (Operator::LowerFirst, 1),
+        (false, Some('/')) => match rest[1..].chars().next() {
+            Some('/') => (Operator::ReplaceAll, 2),
+            Some('#') => (Operator::ReplacePrefix, 2),
+            Some('%') => (Operator::ReplaceSuffix, 2),
+            _ => (Operator::ReplaceFirst, 1),
+        },
+        (true, _) => match parse_substring(rest) {
+            Some((offset, length)) => (Operator::Substring { offset, length }, rest.len()),
+            None => return (body, None),
+        },
+        _ => return (body, None),
+    };
+    (var_name, Some((operator, colon, &rest[operator_len..])))
+}
+
+/// Parses the `offset` or `offset:length` part of `${VAR:offset:length}`.
+///
+/// Both numbers may be surrounded by whitespace or put into parentheses, which allows writing
+/// negative offsets without them being confused with the `:-` operator, like in `${VAR: -1}`.
+/// Empty numbers are zeros, except that an offset without a length may not be empty, so that
+/// `${VAR:}` and `${VAR: }` are not valid, like in bash.
+fn parse_substring(s: &str) -> Option<(isize, Option<isize>)> {
+    /// Returns `Some(None)` for an empty number.
+    fn parse_number(s: &str) -> Option<Option<isize>> {
+        let s = s.trim();
+        let s = match s.strip_prefix('(') {
+            Some(s) => s.strip_suffix(')')?.trim(),
+            None => s,
+        };
+        if s.is_empty() {
+            Some(None)
+        } else {
+            s.parse().ok().map(Some)
+        }
+    }
+
+    match s.find(':') {
+        Some(idx) => Some((
+            parse_number(&s[..idx])?.unwrap_or(0),
+            Some(parse_number(&s[idx + 1..])?.unwrap_or(0)),
+        )),
+        None => Some((parse_number(s)??, None)),
+    }
+}
+
+#[cfg(test)]
+mod parse_tests {
+    use std::ops::Range;
+
+    use super::{
+        parse, parse_without_tilde, walk, walk_modifier, Modifier, Operator, Segment, Syntax,
+        SyntaxErrorKind, Visitor, Word, MAX_NESTING,
+    };
+
+    #[test]
+    fn test_spans() {
+        let syntax = Syntax {
+            backslash_escapes: true,
+            quotes: true,
+            commands: true,
+            arithmetic: true,
+        };
+        for &input in &[
+            "",
+            "plain text",
+            "~/$A/${B:-~user/$C}x$$y\\$z",
+            "'$A' \"$B ${C#\"*\"}\" $(date) `id` $((X + $Y))",
+            "${A/x/y} ${#B} ${C:1:2} ${D^^} ${unbalanced",
+        ] {
+            let segments = parse(input, syntax);
+            let mut end = 0;
+            for segment in &segments {
+                assert_eq!(segment.span().start, end, "{:?}", input);
+                end = segment.span().end;
+            }
+            assert_eq!(end, input.len(), "{:?}", input);
+        }
+
+        assert_eq!(
+            parse("$A${B%x}", Syntax::default())[1],
+            Segment::Braced {
+                name: "B",
+                modifier: Some(Modifier {
+                    operator: Operator::RemoveSmallestSuffix,
+                    colon: false,
+                    word: Word {
+                        segments: vec![Segment::Literal {
+                            text: "x",
+                            span: 6..7
+                        }],
+                        span: 6..7
+                    },
+                    replacement: None
+                }),
+                span: 2..8
+            }
+        );
+
+        assert_eq!(
+            parse("~user/$A", Syntax::default())[0],
+            Segment::Tilde {
+                user: "user",
+                span: 0..5
+            }
+        );
+        assert_eq!(
+            parse_without_tilde("~user/$A", Syntax::default())[0],
+            Segment::Literal {
+                text: "~user/",
+                span: 0..6
+            }
+        );
+        for &input in &["~$A/x", "\\~/x", "x~/y"] {
+            if let Segment::Tilde { .. } = parse(input, syntax)[0] {
+                panic!("{:?}", input);
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        let cases = vec![
+            ("${", SyntaxErrorKind::UnterminatedBrace, 0..2),
+            ("a ${B:-${C}", SyntaxErrorKind::UnterminatedBrace, 2..4),
+            ("${}", SyntaxErrorKind::EmptyName, 0..3),
+            ("${-x}", SyntaxErrorKind::InvalidName("-x".into()), 0..5),
+            ("x${A B}", SyntaxErrorKind::BadOperator(" B".into()), 1..7),
+            ("${A!}", SyntaxErrorKind::BadOperator("!".into()), 0..5),
+            ("${A:}", SyntaxErrorKind::BadOperator(":".into()), 0..5),
+            ("${A: }", SyntaxErrorKind::BadOperator(": ".into()), 0..6),
+            ("${A:()}", SyntaxErrorKind::BadOperator(":()".into()), 0..7),
+        ];
+        for (input, kind, span) in cases {
+            let segments = parse(input, Syntax::default());
+            assert!(
+                segments.contains(&Segment::Invalid { kind, span }),
+                "{:?}: {:?}",
+                input,
+                segments
+            );
+        }
+        assert_eq!(
+            parse("${A} ${B:-} ${#C} $", Syntax::default())
+                .iter()
+                .filter(|segment| matches!(segment, Segment::Invalid { .. }))
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let syntax = Syntax {
+            backslash_escapes: false,
+            quotes: true,
+            commands: true,
+            arithmetic: true,
+        };
+        let nested = |open: &str, close: &str, depth: usize| {
+            format!("x{}1{}", open.repeat(depth), close.repeat(depth))
+        };
+        // every repetition of `open` nests `levels` constructs
+        for &(open, close, levels) in &[
+            ("${A:-", "}", 1),
+            ("$(echo \"", "\")", 2),
+            ("$((", "))", 1),
+            ("$(", ")", 1),
+            ("$(( ${A:-", "} ))", 2),
+        ] {
+            let input = nested(open, close, MAX_NESTING / levels);
+            let segments = parse(&input, syntax);
+            assert_eq!(segments.len(), 2, "{:?}", open);
+            assert!(
+                !matches!(segments[1], Segment::Invalid { .. }),
+                "{:?}",
+                open
+            );
+
+            for &depth in &[MAX_NESTING / levels + 1, 100_000] {
+                let input = nested(open, close, depth);
+                let mut invalid = Invalid(Vec::new());
+                walk(&mut invalid, &parse(&input, syntax));
+                assert_eq!(invalid.0.len(), 1, "{:?}", open);
+                assert_eq!(invalid.0[0].0, SyntaxErrorKind::TooDeep);
+            }
+        }
+        let input = nested("${A:-", "}", 1000);
+        assert_eq!(
+            parse(&input, syntax)[1],
+            Segment::Invalid {
+                kind: SyntaxErrorKind::TooDeep,
+                span: 1..input.len()
+            }
+        );
+
+        // unclosed arithmetic expansions are scanned once
+        let input = "$((".repeat(100_000);
+        let syntax = Syntax {
+            arithmetic: true,
+            ..Syntax::default()
+        };
+        assert_eq!(
+            parse(&input, syntax),
+            vec![Segment::Literal {
+                text: &input,
+                span: 0..input.len()
+            }]
+        );
+    }
+
+    struct Invalid(Vec<(SyntaxErrorKind, Range<usize>)>);
+
+    impl<'a> Visitor<'a> for Invalid {
+        fn visit_invalid(&mut self, kind: &SyntaxErrorKind, span: Range<usize>) {
+            self.0.push((kind.clone(), span));
+        }
+    }
+
+    #[test]
+    fn test_visitor() {
+        #[derive(Default)]
+        struct Collector<'a> {
+            names: Vec<&'a str>,
+            tildes: Vec<(&'a str, usize)>,
+            commands: Vec<&'a str>,
+        }
+
+        impl<'a> Visitor<'a> for Collector<'a> {
+            fn visit_variable(&mut self, name: &'a str, _: Range<usize>) {
+                self.names.push(name);
+            }
+
+            fn visit_braced(
+                &mut self,
+                name: &'a str,
+                modifier: Option<&Modifier<'a>>,
+                _: Range<usize>,
+            ) {
+                self.names.push(name);
+                if let Some(modifier) = modifier {
+                    walk_modifier(self, modifier);
+                }
+            }
+
+            fn visit_tilde(&mut self, user: &'a str, span: Range<usize>) {
+                self.tildes.push((user, span.start));
+            }
+
+            fn visit_command(&mut self, command: &'a str, _: bool, _: Range<usize>) {
+                self.commands.push(command);
+            }
+        }
+
+        let syntax = Syntax {
+            quotes: true,
+            commands: true,
+            arithmetic: true,
+            ..Syntax::default()
+        };
+        let mut collector = Collector::default();
+        walk(
+            &mut collector,
+            &parse(
+                "$A ${B:-~/$C} \"${D//$E/~$F}\" $((G + $H)) $(echo $I) '$J'",
+                syntax,
+            ),
+        );
+        assert_eq!(collector.names, ["A", "B", "C", "D", "E", "F", "H"]);
+        assert_eq!(collector.tildes, [("", 8)]);
+        assert_eq!(collector.commands, ["echo $I"]);
+    }
+}
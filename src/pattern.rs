@@ -0,0 +1,646 @@
+//! Shell pattern matching, as used by `${VAR#pattern}` and similar expansions.
+//!
+//! Patterns follow the POSIX "Pattern Matching Notation": `*` matches any string, `?` matches
+//! any single character, `[...]` matches a single character from a bracket expression, and a
+//! backslash makes the next character match literally. All other characters match themselves.
+
+use std::iter;
+use std::mem;
+
+/// A single element of a compiled pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    /// A character which matches itself.
+    Char(char),
+    /// `?`
+    Any,
+    /// `*`
+    Star,
+    /// `[...]`, possibly negated with `!` or `^`.
+    Class {
+        negated: bool,
+        items: Vec<ClassItem>,
+    },
+}
+
+/// A single element of a bracket expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ClassItem {
+    Char(char),
+    Range(char, char),
+    Named(NamedClass),
+}
+
+/// A `[:name:]` character class inside a bracket expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NamedClass {
+    Alnum,
+    Alpha,
+    Blank,
+    Cntrl,
+    Digit,
+    Graph,
+    Lower,
+    Print,
+    Punct,
+    Space,
+    Upper,
+    Xdigit,
+}
+
+impl NamedClass {
+    fn matches(self, c: char) -> bool {
+        match self {
+            NamedClass::Alnum => c.is_alphanumeric(),
+            NamedClass::Alpha => c.is_alphabetic(),
+            NamedClass::Blank => c == ' ' || c == '\t',
+            NamedClass::Cntrl => c.is_control(),
+            NamedClass::Digit => c.is_ascii_digit(),
+            NamedClass::Graph => !c.is_whitespace() && !c.is_control(),
+            NamedClass::Lower => c.is_lowercase(),
+            NamedClass::Print => !c.is_control(),
+            NamedClass::Punct => c.is_ascii_punctuation(),
+            NamedClass::Space => c.is_whitespace(),
+            NamedClass::Upper => c.is_uppercase(),
+            NamedClass::Xdigit => c.is_ascii_hexdigit(),
+        }
+    }
+}
+
+impl Token {
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            Token::Char(p) => p == c,
+            Token::Any => true,
+            Token::Star => false,
+            Token::Class { negated, ref items } => {
+                let found = items.iter().any(|item| match *item {
+                    ClassItem::Char(p) => p == c,
+                    ClassItem::Range(from, to) => from <= c && c <= to,
+                    ClassItem::Named(class) => class.matches(c),
+                });
+                found != negated
+            }
+        }
+    }
+}
+
+/// A compiled shell pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    tokens: Vec<Token>,
+}
+
+impl Pattern {
+    /// Compiles a pattern.
+    ///
+    /// Every string is a valid pattern: a `[` without a matching `]` and a trailing backslash
+    /// match themselves literally.
+    pub fn new(pattern: &str) -> Pattern {
+        let mut tokens = Vec::new();
+        let mut rest = pattern;
+        while let Some(c) = rest.chars().next() {
+            rest = &rest[c.len_utf8()..];
+            let token = match c {
+                '*' => {
+                    // consecutive stars are equivalent to a single one
+                    if tokens.last() == Some(&Token::Star) {
+                        continue;
+                    }
+                    Token::Star
+                }
+                '?' => Token::Any,
+                '\\' => match rest.chars().next() {
+                    Some(c) => {
+                        rest = &rest[c.len_utf8()..];
+                        Token::Char(c)
+                    }
+                    None => Token::Char('\\'),
+                },
+                '[' => match parse_class(rest) {
+                    Some((token, after_class)) => {
+                        rest = after_class;
+                        token
+                    }
+                    None => Token::Char('['),
+                },
+                c => Token::Char(c),
+            };
+            tokens.push(token);
+        }
+        Pattern { tokens }
+    }
+
+    /// Returns `true` if the whole `text` matches the pattern.
+    pub fn matches(&self, text: &str) -> bool {
+        self.matches_chars(&text.chars().collect::<Vec<_>>())
+    }
+
+    /// Returns `true` if the pattern contains `*`, `?` or bracket expressions, so that it may
+    /// match something else than a single string.
+    pub fn has_wildcards(&self) -> bool {
+        self.tokens.iter().any(|t| !matches!(*t, Token::Char(_)))
+    }
+
+    /// Returns `true` if the single character `c` matches the pattern.
+    pub fn matches_char(&self, c: char) -> bool {
+        self.matches_chars(&[c])
+    }
+
+    /// Returns the end of the shortest or the longest prefix of `text` matching the pattern.
+    pub fn match_prefix(&self, text: &str, longest: bool) -> Option<usize> {
+        let (chars, bounds) = split_chars(text);
+        self.match_at(&chars, 0, longest).map(|end| bounds[end])
+    }
+
+    /// Returns the start of the shortest or the longest suffix of `text` matching the pattern.
+    pub fn match_suffix(&self, text: &str, longest: bool) -> Option<usize> {
+        let (mut chars, bounds) = split_chars(text);
+        // a suffix of the text matches the pattern if its reverse matches the reversed pattern
+        chars.reverse();
+        let reversed = Pattern {
+            tokens: self.tokens.iter().rev().cloned().collect(),
+        };
+        reversed
+            .match_at(&chars, 0, longest)
+            .map(|len| bounds[chars.len() - len])
+    }
+
+    /// Returns an iterator over the byte ranges of the leftmost longest non-empty matches in
+    /// `text`, which do not overlap.
+    pub fn find_iter<'p, 't>(&'p self, text: &'t str) -> Matches<'p, 't> {
+        let kind = if self.has_wildcards() {
+            let (chars, bounds) = split_chars(text);
+            MatchesKind::Wildcards {
+                pattern: self,
+                chars,
+                bounds,
+                start: 0,
+            }
+        } else {
+            let literal: String = self
+                .tokens
+                .iter()
+                .map(|token| match *token {
+                    Token::Char(c) => c,
+                    _ => unreachable!(),
+                })
+                .collect();
+            MatchesKind::Literal {
+                literal,
+                text,
+                start: 0,
+            }
+        };
+        Matches { kind }
+    }
+
+    /// Returns the end of the shortest or the longest match in `chars` which starts at `start`.
+    ///
+    /// The pattern is simulated as a nondeterministic automaton whose states are the positions
+    /// between its tokens, so every character is only looked at once.
+    fn match_at(&self, chars: &[char], start: usize, longest: bool) -> Option<usize> {
+        let tokens = &self.tokens;
+        let mut states = vec![false; tokens.len() + 1];
+        let mut next_states = states.clone();
+        states[0] = true;
+        self.skip_stars(&mut states);
+
+        let mut found = None;
+        for pos in start..chars.len() + 1 {
+            if states[tokens.len()] {
+                found = Some(pos);
+                if !longest {
+                    break;
+                }
+            }
+            if pos == chars.len() || !states.contains(&true) {
+                break;
+            }
+            for state in next_states.iter_mut() {
+                *state = false;
+            }
+            for (p, token) in tokens.iter().enumerate() {
+                if !states[p] {
+                    continue;
+                }
+                match *token {
+                    Token::Star => next_states[p] = true,
+                    ref token if token.matches(chars[pos]) => next_states[p + 1] = true,
+                    _ => {}
+                }
+            }
+            self.skip_stars(&mut next_states);
+            mem::swap(&mut states, &mut next_states);
+        }
+        found
+    }
+
+    /// Returns the byte range of the leftmost longest non-empty match in `chars` which starts at
+    /// `start` or later.
+    ///
+    /// Like `match_at()`, this simulates the automaton of the pattern, but it starts a new match
+    /// at every position, and every state keeps the leftmost start of the matches reaching it, so
+    /// the characters are looked at once however many positions the match may start at.
+    fn find_at(&self, chars: &[char], start: usize) -> Option<(usize, usize)> {
+        let tokens = &self.tokens;
+        let mut states: Vec<Option<usize>> = vec![None; tokens.len() + 1];
+        let mut next_states = states.clone();
+
+        let mut found: Option<(usize, usize)> = None;
+        for pos in start..chars.len() + 1 {
+            // matches starting after a match which was found are not the leftmost ones
+            if found.is_none() {
+                states[0] = Some(states[0].unwrap_or(pos));
+                self.skip_stars_from(&mut states);
+            }
+            match (states[tokens.len()], found) {
+                (Some(match_start), Some((found_start, _))) if match_start > found_start => {}
+                (Some(match_start), _) if match_start < pos => found = Some((match_start, pos)),
+                _ => {}
+            }
+            if pos == chars.len() {
+                break;
+            }
+            if let Some((found_start, _)) = found {
+                for state in states.iter_mut() {
+                    if *state > Some(found_start) {
+                        *state = None;
+                    }
+                }
+                if states.iter().all(Option::is_none) {
+                    break;
+                }
+            }
+
+            for state in next_states.iter_mut() {
+                *state = None;
+            }
+            for (p, token) in tokens.iter().enumerate() {
+                let match_start = match states[p] {
+                    Some(match_start) => match_start,
+                    None => continue,
+                };
+                let next = match *token {
+                    Token::Star => p,
+                    ref token if token.matches(chars[pos]) => p + 1,
+                    _ => continue,
+                };
+                next_states[next] =
+                    Some(next_states[next].map_or(match_start, |s| s.min(match_start)));
+            }
+            self.skip_stars_from(&mut next_states);
+            mem::swap(&mut states, &mut next_states);
+        }
+        found
+    }
+
+    /// Like `skip_stars()`, for states which keep the start of their matches.
+    fn skip_stars_from(&self, states: &mut [Option<usize>]) {
+        for (p, token) in self.tokens.iter().enumerate() {
+            if let (Some(match_start), &Token::Star) = (states[p], token) {
+                states[p + 1] = Some(states[p + 1].map_or(match_start, |s| s.min(match_start)));
+            }
+        }
+    }
+
+    /// Adds the states after stars to `states`, since a star may match an empty string.
+    fn skip_stars(&self, states: &mut [bool]) {
+        for (p, token) in self.tokens.iter().enumerate() {
+            if states[p] && *token == Token::Star {
+                states[p + 1] = true;
+            }
+        }
+    }
+
+    fn matches_chars(&self, text: &[char]) -> bool {
+        let tokens = &self.tokens;
+        let (mut t, mut p) = (0, 0);
+        // the position after the last star and the text position it currently stands for
+        let mut backtrack = None;
+
+        while t < text.len() {
+            match tokens.get(p) {
+                Some(&Token::Star) => {
+                    p += 1;
+                    backtrack = Some((p, t));
+                    continue;
+                }
+                Some(token) if token.matches(text[t]) => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            // let the last star consume one more character and try again
+            match backtrack {
+                Some((star_p, star_t)) => {
+                    p = star_p;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, t));
+                }
+                None => return false,
+            }
+        }
+
+        tokens[p..].iter().all(|t| *t == Token::Star)
+    }
+}
+
+/// Removes the backslashes which escape characters in `pattern`, making it a literal string.
+pub fn unescape(pattern: &str) -> String {
+    let mut result = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.push(chars.next().unwrap_or('\\')),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Escapes the special characters of `text`, so that it can be used as a pattern matching itself.
+pub fn escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        if let '*' | '?' | '[' | ']' | '\\' = c {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+/// An iterator over the matches of a pattern in a string; see `Pattern::find_iter()`.
+pub struct Matches<'p, 't> {
+    kind: MatchesKind<'p, 't>,
+}
+
+enum MatchesKind<'p, 't> {
+    /// A pattern without wildcards, which is searched for as a plain string.
+    Literal {
+        literal: String,
+        text: &'t str,
+        start: usize,
+    },
+    Wildcards {
+        pattern: &'p Pattern,
+        chars: Vec<char>,
+        bounds: Vec<usize>,
+        start: usize,
+    },
+}
+
+impl<'p, 't> Iterator for Matches<'p, 't> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        match self.kind {
+            MatchesKind::Literal {
+                ref literal,
+                text,
+                ref mut start,
+            } => {
+                if literal.is_empty() {
+                    return None;
+                }
+                let match_start = *start + text[*start..].find(literal.as_str())?;
+                *start = match_start + literal.len();
+                Some((match_start, *start))
+            }
+            MatchesKind::Wildcards {
+                pattern,
+                ref chars,
+                ref bounds,
+                ref mut start,
+            } => match pattern.find_at(chars, *start) {
+                Some((match_start, end)) => {
+                    *start = end;
+                    Some((bounds[match_start], bounds[end]))
+                }
+                None => {
+                    *start = chars.len();
+                    None
+                }
+            },
+        }
+    }
+}
+
+/// Returns the characters of `text` along with the byte offsets of their boundaries.
+fn split_chars(text: &str) -> (Vec<char>, Vec<usize>) {
+    let chars = text.chars().collect();
+    let bounds = text
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain(iter::once(text.len()))
+        .collect();
+    (chars, bounds)
+}
+
+/// Parses a bracket expression after its opening bracket.
+///
+/// Returns the token and the rest of the pattern after the closing bracket, or `None` if there is
+/// no closing bracket.
+fn parse_class(pattern: &str) -> Option<(Token, &str)> {
+    let mut rest = pattern;
+    let negated = rest.starts_with('!') || rest.starts_with('^');
+    if negated {
+        rest = &rest[1..];
+    }
+
+    let mut items = Vec::new();
+    let mut first = true;
+    loop {
+        let c = rest.chars().next()?;
+        rest = &rest[c.len_utf8()..];
+
+        // a closing bracket in the first position is a regular character
+        let c = match c {
+            ']' if !first => return Some((Token::Class { negated, items }, rest)),
+            '[' if rest.starts_with(':') => match parse_named_class(&rest[1..]) {
+                Some((class, after_class)) => {
+                    rest = after_class;
+                    items.push(ClassItem::Named(class));
+                    first = false;
+                    continue;
+                }
+                None => '[',
+            },
+            '\\' => {
+                let c = rest.chars().next()?;
+                rest = &rest[c.len_utf8()..];
+                c
+            }
+            c => c,
+        };
+        first = false;
+
+        // `a-z`, but a dash before the closing bracket is a regular character
+        if rest.starts_with('-') && !rest[1..].starts_with(']') {
+            let mut after_dash = rest[1..].chars();
+            let to = match after_dash.next()? {
+                '\\' => after_dash.next()?,
+                to => to,
+            };
+            rest = after_dash.as_str();
+            items.push(ClassItem::Range(c, to));
+        } else {
+            items.push(ClassItem::Char(c));
+        }
+    }
+}
+
+/// Parses a `[:name:]` character class after its `[:`.
+fn parse_named_class(pattern: &str) -> Option<(NamedClass, &str)> {
+    let end_idx = pattern.find(":]")?;
+    let class = match &pattern[..end_idx] {
+        "alnum" => NamedClass::Alnum,
+        "alpha" => NamedClass::Alpha,
+        "blank" => NamedClass::Blank,
+        "cntrl" => NamedClass::Cntrl,
+        "digit" => NamedClass::Digit,
+        "graph" => NamedClass::Graph,
+        "lower" => NamedClass::Lower,
+        "print" => NamedClass::Print,
+        "punct" => NamedClass::Punct,
+        "space" => NamedClass::Space,
+        "upper" => NamedClass::Upper,
+        "xdigit" => NamedClass::Xdigit,
+        _ => return None,
+    };
+    Some((class, &pattern[end_idx + 2..]))
+}
+
+#[cfg(test)]
+mod pattern_tests {
+    use super::{escape, unescape, Pattern};
+
+    #[test]
+    fn test_matches() {
+        let cases = [
+            ("", "", true),
+            ("", "a", false),
+            ("abc", "abc", true),
+            ("abc", "abd", false),
+            ("*", "", true),
+            ("*", "anything", true),
+            ("a*c", "abbbc", true),
+            ("a*c", "abbbd", false),
+            ("*.tar.gz", "archive.tar.gz", true),
+            ("*a*b", "xaxxbxab", true),
+            ("?", "é", true),
+            ("??", "é", false),
+            ("[abc]x", "bx", true),
+            ("[!abc]x", "bx", false),
+            ("[^abc]x", "dx", true),
+            ("[a-f]*", "echo", true),
+            ("[a-f]*", "gecho", false),
+            ("[]]", "]", true),
+            ("[!]]", "]", false),
+            ("[a-]", "-", true),
+            ("[[:digit:]][[:alpha:]]", "1z", true),
+            ("[[:upper:]]", "z", false),
+            ("[", "[", true),
+            ("[ab", "[ab", true),
+            ("\\*", "*", true),
+            ("\\*", "a", false),
+            ("a\\", "a\\", true),
+        ];
+        for &(pattern, text, expected) in cases.iter() {
+            assert_eq!(
+                Pattern::new(pattern).match_prefix(text, true) == Some(text.len()),
+                expected,
+                "{:?} matching {:?}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_prefix_suffix() {
+        let p = Pattern::new("*/");
+        assert_eq!(p.match_prefix("a/b/c", false), Some(2));
+        assert_eq!(p.match_prefix("a/b/c", true), Some(4));
+        assert_eq!(p.match_prefix("abc", true), None);
+
+        let p = Pattern::new(".*");
+        assert_eq!(p.match_suffix("a.tar.gz", false), Some(5));
+        assert_eq!(p.match_suffix("a.tar.gz", true), Some(1));
+        assert_eq!(p.match_suffix("abc", false), None);
+
+        let p = Pattern::new("*");
+        assert_eq!(p.match_prefix("éé", false), Some(0));
+        assert_eq!(p.match_prefix("éé", true), Some(4));
+        assert_eq!(p.match_suffix("éé", false), Some(4));
+        assert_eq!(p.match_suffix("éé", true), Some(0));
+    }
+
+    #[test]
+    fn test_matches_char() {
+        assert!(Pattern::new("?").matches_char('é'));
+        assert!(Pattern::new("*").matches_char('a'));
+        assert!(Pattern::new("[a-f]").matches_char('c'));
+        assert!(!Pattern::new("[a-f]").matches_char('g'));
+        assert!(!Pattern::new("ab").matches_char('a'));
+    }
+
+    #[test]
+    fn test_wildcards() {
+        assert!(!Pattern::new("abc").has_wildcards());
+        assert!(!Pattern::new("a\\*[").has_wildcards());
+        assert!(Pattern::new("a*").has_wildcards());
+        assert!(Pattern::new("a?").has_wildcards());
+        assert!(Pattern::new("[ab]").has_wildcards());
+        assert!(Pattern::new("*.toml").matches("app.toml"));
+        assert!(!Pattern::new("*.toml").matches("app.toml.bak"));
+    }
+
+    #[test]
+    fn test_escape() {
+        let text = "a*b?[c]\\d";
+        assert_eq!(escape(text), "a\\*b\\?\\[c\\]\\\\d");
+        assert_eq!(
+            Pattern::new(&escape(text)).match_prefix(text, true),
+            Some(text.len())
+        );
+        assert_eq!(
+            Pattern::new(&escape(text)).match_prefix("a*b?c", true),
+            None
+        );
+        assert_eq!(unescape(&escape(text)), text);
+        assert_eq!(unescape("\\a\\"), "a\\");
+    }
+
+    #[test]
+    fn test_find() {
+        let find_all = |pattern: &str, text: &str| -> Vec<(usize, usize)> {
+            Pattern::new(pattern).find_iter(text).collect()
+        };
+        assert_eq!(find_all("a*b", "xxabyab"), [(2, 7)]);
+        assert_eq!(find_all("a?b", "xxabyaab"), [(5, 8)]);
+        assert_eq!(find_all("[ab]", "xaxb"), [(1, 2), (3, 4)]);
+        assert_eq!(find_all("é", "aéé"), [(1, 3), (3, 5)]);
+        assert_eq!(find_all("aa", "aaaaa"), [(0, 2), (2, 4)]);
+        assert_eq!(find_all("\\*", "a*b*"), [(1, 2), (3, 4)]);
+
+        // empty matches are never found
+        assert!(find_all("", "abc").is_empty());
+        assert!(find_all("*", "").is_empty());
+
+        // long values are searched in linear time for literal patterns, and without trying every
+        // end of every match for wildcards
+        let long = "ab".repeat(20_000);
+        assert_eq!(find_all("a", &long).len(), 20_000);
+        assert_eq!(find_all("[a]", &long).len(), 20_000);
+        assert_eq!(find_all("a*", &long), [(0, long.len())]);
+        // and in a single pass when no match starts at most positions
+        let long = "a".repeat(20_000);
+        assert!(find_all("*b", &long).is_empty());
+        assert!(find_all("a*b", &long).is_empty());
+        assert_eq!(find_all("*b", &(long.clone() + "b")), [(0, 20_001)]);
+    }
+}
@@ -0,0 +1,233 @@
+//! Listing of the variables which a string references, without expanding it.
+
+use std::ops::Range;
+
+use parse::{self, Modifier, Operator, Segment, Syntax, Visitor, Word};
+
+/// A reference to a variable; see `references()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference<'a> {
+    /// The name of the variable.
+    pub name: &'a str,
+    /// The operator inside the braces, or `None` for `$VAR`, `${VAR}` and names in arithmetic
+    /// expressions.
+    pub operator: Option<Operator>,
+    /// Whether the reference provides a value of its own if the variable is not set, like
+    /// `${VAR:-word}` and `${VAR:=word}` do.
+    pub has_default: bool,
+    /// The byte range of the reference in the input: the whole `$VAR` or `${...}`, or just the
+    /// name in an arithmetic expression.
+    pub span: Range<usize>,
+}
+
+/// The variables which a string references, and whether it has a tilde prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct References<'a> {
+    /// Every reference, including the ones in words after operators, in the order of the input.
+    pub variables: Vec<Reference<'a>>,
+    /// Whether the string starts with a tilde prefix, or has one in the beginning of a word
+    /// after an operator, which `full_with_context()` and the like would expand.
+    pub tilde: bool,
+}
+
+/// Lists the variables which `input` references, without looking any of them up.
+///
+/// The input is parsed with the grammar selected by `syntax`, so `Syntax::default()` lists the
+/// references which `env_with_context()` and `full_with_context()` would expand. Every
+/// reference is listed, including repeated ones, ones in words after operators, which may never
+/// be expanded, and bare names in arithmetic expressions; escaped and malformed references are
+/// not. This makes it possible to check the variables of a template, or document them, before
+/// expanding it.
+///
+/// # Examples
+///
+/// ```
+/// use shellexpand::{Operator, Syntax};
+///
+/// let syntax = Syntax {
+///     arithmetic: true,
+///     ..Syntax::default()
+/// };
+/// let references = shellexpand::references("~/${DATA:-$HOME/data}/$((SHARD + 1))", syntax);
+/// assert!(references.tilde);
+/// assert_eq!(
+///     references
+///         .variables
+///         .iter()
+///         .map(|r| (r.name, r.operator, r.has_default))
+///         .collect::<Vec<_>>(),
+///     [
+///         ("DATA", Some(Operator::Default), true),
+///         ("HOME", None, false),
+///         ("SHARD", None, false),
+///     ]
+/// );
+/// assert_eq!(references.variables[0].span, 2..21);
+/// ```
+pub fn references(input: &str, syntax: Syntax) -> References<'_> {
+    let mut collector = Collector {
+        variables: Vec::new(),
+        tilde: false,
+    };
+    parse::walk(&mut collector, &parse::parse(input, syntax));
+    References {
+        variables: collector.variables,
+        tilde: collector.tilde,
+    }
+}
+
+struct Collector<'a> {
+    variables: Vec<Reference<'a>>,
+    tilde: bool,
+}
+
+impl<'a> Visitor<'a> for Collector<'a> {
+    fn visit_variable(&mut self, name: &'a str, span: Range<usize>) {
+        self.variables.push(Reference {
+            name,
+            operator: None,
+            has_default: false,
+            span,
+        });
+    }
+
+    fn visit_braced(&mut self, name: &'a str, modifier: Option<&Modifier<'a>>, span: Range<usize>) {
+        let operator = modifier.map(|modifier| modifier.operator);
+        self.variables.push(Reference {
+            name,
+            operator,
+            has_default: operator == Some(Operator::Default) || operator == Some(Operator::Assign),
+            span,
+        });
+        if let Some(modifier) = modifier {
+            parse::walk_modifier(self, modifier);
+        }
+    }
+
+    fn visit_tilde(&mut self, _: &'a str, _: Range<usize>) {
+        self.tilde = true;
+    }
+
+    fn visit_arithmetic(&mut self, expression: &Word<'a>, _: Range<usize>) {
+        for segment in &expression.segments {
+            match *segment {
+                Segment::Literal { text, ref span } => self.visit_names(text, span.start),
+                _ => self.visit_segment(segment),
+            }
+        }
+    }
+}
+
+impl<'a> Collector<'a> {
+    /// Lists the names in the literal text of an arithmetic expression, which starts at `offset`
+    /// in the input.
+    fn visit_names(&mut self, text: &'a str, offset: usize) {
+        let mut pos = 0;
+        while let Some(start) = text[pos..].find(parse::is_valid_var_name_char) {
+            let start = pos + start;
+            let end = text[start..]
+                .find(|c| !parse::is_valid_var_name_char(c))
+                .map_or(text.len(), |len| start + len);
+            // numbers, like `10` and `0x1F`, are not names
+            if !text[start..].starts_with(|c: char| c.is_ascii_digit()) {
+                self.variables.push(Reference {
+                    name: &text[start..end],
+                    operator: None,
+                    has_default: false,
+                    span: offset + start..offset + end,
+                });
+            }
+            pos = end;
+        }
+    }
+}
+
+#[cfg(test)]
+mod references_tests {
+    use parse::{Operator, Syntax};
+
+    use super::{references, Reference};
+
+    #[test]
+    fn test_references() {
+        let input = "$A/${B}x${C:=c}${D#$E} $$F ${G:+~/$H} ${ $((I * 0x1F + $J))";
+        let syntax = Syntax {
+            arithmetic: true,
+            ..Syntax::default()
+        };
+        let result = references(input, syntax);
+        assert!(result.tilde);
+        assert_eq!(
+            result.variables,
+            vec![
+                Reference {
+                    name: "A",
+                    operator: None,
+                    has_default: false,
+                    span: 0..2,
+                },
+                Reference {
+                    name: "B",
+                    operator: None,
+                    has_default: false,
+                    span: 3..7,
+                },
+                Reference {
+                    name: "C",
+                    operator: Some(Operator::Assign),
+                    has_default: true,
+                    span: 8..15,
+                },
+                Reference {
+                    name: "D",
+                    operator: Some(Operator::RemoveSmallestPrefix),
+                    has_default: false,
+                    span: 15..22,
+                },
+                Reference {
+                    name: "E",
+                    operator: None,
+                    has_default: false,
+                    span: 19..21,
+                },
+                Reference {
+                    name: "G",
+                    operator: Some(Operator::Alternative),
+                    has_default: false,
+                    span: 27..37,
+                },
+                Reference {
+                    name: "H",
+                    operator: None,
+                    has_default: false,
+                    span: 34..36,
+                },
+                Reference {
+                    name: "I",
+                    operator: None,
+                    has_default: false,
+                    span: 44..45,
+                },
+                Reference {
+                    name: "J",
+                    operator: None,
+                    has_default: false,
+                    span: 55..57,
+                },
+            ]
+        );
+
+        let result = references("~user/$A", Syntax::default());
+        assert!(result.tilde);
+        assert_eq!(result.variables.len(), 1);
+        let result = references("a~/'$A' \\$B", Syntax::default());
+        assert!(!result.tilde);
+        assert_eq!(result.variables.len(), 2);
+        let syntax = Syntax {
+            backslash_escapes: true,
+            quotes: true,
+            ..Syntax::default()
+        };
+        assert!(references("a~/'$A' \\$B", syntax).variables.is_empty());
+    }
+}
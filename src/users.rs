@@ -0,0 +1,95 @@
+//! Lookup of other users' home directories in the system user database.
+
+use std::path::PathBuf;
+
+/// Returns the home directory of the user with the given login name.
+///
+/// On Unix systems the directory is obtained from the system user database with
+/// `getpwnam_r(3)`, so everything configured in NSS (local `/etc/passwd`, LDAP and so on) is
+/// taken into account. `None` is returned if there is no such user or if the user does not
+/// have a home directory. On other platforms this function always returns `None`.
+///
+/// This is the default user home directory context used by `tilde()` and `full()` to expand
+/// `~user` prefixes.
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(shellexpand::user_home_dir("surely-there-is-no-such-user"), None);
+/// ```
+#[cfg(unix)]
+pub fn user_home_dir(name: &str) -> Option<PathBuf> {
+    use std::ffi::{CStr, CString, OsString};
+    use std::mem;
+    use std::os::unix::ffi::OsStringExt;
+    use std::ptr;
+
+    use libc;
+
+    // the buffer is grown on ERANGE, but not indefinitely
+    const MAX_BUFFER_SIZE: usize = 1 << 20;
+
+    let name = CString::new(name).ok()?;
+    let mut buffer_size = match unsafe { libc::sysconf(libc::_SC_GETPW_R_SIZE_MAX) } {
+        n if n > 0 => n as usize,
+        _ => 1024,
+    };
+
+    loop {
+        let mut buffer = vec![0 as libc::c_char; buffer_size];
+        let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+
+        let code = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+
+        if code == libc::ERANGE && buffer_size < MAX_BUFFER_SIZE {
+            buffer_size *= 2;
+            continue;
+        }
+        if code != 0 || result.is_null() || passwd.pw_dir.is_null() {
+            return None;
+        }
+
+        // `pw_dir` points into `buffer`, so it has to be copied out before the buffer is dropped
+        let dir = unsafe { CStr::from_ptr(passwd.pw_dir) }.to_bytes();
+        return if dir.is_empty() {
+            None
+        } else {
+            Some(OsString::from_vec(dir.to_vec()).into())
+        };
+    }
+}
+
+/// Returns the home directory of the user with the given login name.
+///
+/// The system user database is only consulted on Unix systems; on this platform this function
+/// always returns `None`.
+#[cfg(not(unix))]
+pub fn user_home_dir(_name: &str) -> Option<PathBuf> {
+    None
+}
+
+#[cfg(all(test, unix))]
+mod users_tests {
+    use super::user_home_dir;
+
+    #[test]
+    fn test_known_user() {
+        assert!(user_home_dir("root").is_some());
+    }
+
+    #[test]
+    fn test_unknown_user() {
+        assert_eq!(user_home_dir("surely-there-is-no-such-user"), None);
+        assert_eq!(user_home_dir("nul\0byte"), None);
+        assert_eq!(user_home_dir(""), None);
+    }
+}